@@ -1,620 +1,1438 @@
-// You are entering a very scary territory of magic numbers and arbitrary math operations.
-// I don't remember why I did all of this but it works I guess :3
+// Audio processing unit. Like on the real hardware, the channels are stepped from the emulated
+// cycles and their length counters, envelopes and sweep are clocked by a 512Hz frame sequencer
+// driven by the DIV register. The output is resampled to the rate of the audio backend with
+// band-limited steps.
 
-use crate::io::{self, Audio};
+use std::str::FromStr;
 
-use std::sync::{Arc, Mutex};
+use crate::blip::BlipBuffer;
+use crate::consts::CPU_CLOCK_SPEED;
+use crate::io::Audio;
+use crate::save_state::{StateReader, StateWriter};
 
-pub const SAMPLE_RATE: u32 = 65536;
+/// Output rate used when the backend has no device to get it from
+pub const DEFAULT_SAMPLE_RATE: u32 = 48000;
 
-const SAMPLE_AVERAGING: usize = 1; //20;
+// Number of stereo samples given to the backend at once
+const PUSH_SIZE: usize = 64;
 
-const SQUARE_WAVE_PATTERN_DUTY_0: [u8; 32] = [
-    0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf,
-    0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0xf, 0, 0, 0, 0,
-];
+// One bit per duty step, the first step being the least significant bit
+const DUTY_PATTERNS: [u8; 4] = [0b10000000, 0b10000001, 0b11100001, 0b01111110];
 
-const SQUARE_WAVE_PATTERN_DUTY_1: [u8; 32] = [
-    0xd, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe,
-    0xf, 0xe, 0xf, 0xe, 0xd, 2, 0, 1, 0, 1, 0, 1, 2,
-];
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
 
-const SQUARE_WAVE_PATTERN_DUTY_2: [u8; 32] = [
-    0xd, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xf, 0xd, 2, 0, 1, 0, 1,
-    0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 2,
-];
+// Volume shift of the wave channel for each NR32 volume code
+const WAVE_VOLUME_SHIFTS: [u8; 4] = [4, 0, 1, 2];
 
-const SQUARE_WAVE_PATTERN_DUTY_3: [u8; 32] = [
-    0xd, 0xf, 0xe, 0xf, 0xe, 0xf, 0xe, 0xd, 2, 0, 1, 0, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1, 0, 1,
-    0, 1, 0, 1, 0, 2,
-];
+/// Model of the capacitor of the high-pass filter removing the DC offset of the output
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HighPass {
+    None,
+    Dmg,
+    Cgb,
+}
+
+// Cycles between two samples of the oscilloscope (65536Hz)
+const SCOPE_PERIOD: u32 = 64;
+
+/// Samples of each channel kept by the oscilloscope (62.5ms)
+pub const SCOPE_LENGTH: usize = 4096;
+
+const HIGH_PASS_MODELS: [HighPass; 3] = [HighPass::None, HighPass::Dmg, HighPass::Cgb];
+
+impl HighPass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            HighPass::None => "none",
+            HighPass::Dmg => "dmg",
+            HighPass::Cgb => "cgb",
+        }
+    }
+
+    // Part of the capacitor charge kept after one sample (the hardware values are per cycle)
+    fn charge_factor(&self, sample_rate: u32) -> f32 {
+        let cycles_per_sample = CPU_CLOCK_SPEED as f32 / sample_rate as f32;
+        match self {
+            HighPass::None => 0.,
+            HighPass::Dmg => 0.999958f32.powf(cycles_per_sample),
+            HighPass::Cgb => 0.998943f32.powf(cycles_per_sample),
+        }
+    }
 
-const SQUARE_WAVE_PATTERNS: [[u8; 32]; 4] = [
-    SQUARE_WAVE_PATTERN_DUTY_0,
-    SQUARE_WAVE_PATTERN_DUTY_1,
-    SQUARE_WAVE_PATTERN_DUTY_2,
-    SQUARE_WAVE_PATTERN_DUTY_3,
-];
+    // Filters one sample, with the charge factor of the sample rate. The capacitor only charges
+    // while a DAC is on.
+    fn apply(
+        &self,
+        capacitor: &mut f32,
+        input: f32,
+        dacs_enabled: bool,
+        charge_factor: f32,
+    ) -> f32 {
+        if *self == HighPass::None {
+            return input;
+        }
+        if !dacs_enabled {
+            return 0.;
+        }
+
+        let output = input - *capacitor;
+        *capacitor = input - output * charge_factor;
+        output
+    }
+}
+
+impl FromStr for HighPass {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        HIGH_PASS_MODELS
+            .iter()
+            .find(|h| h.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "Unknown high-pass filter \"{}\" (possible values: {})",
+                    s,
+                    HIGH_PASS_MODELS.map(|h| h.name()).join(",")
+                )
+            })
+    }
+}
+
+// Digital (0-15) to analog (1 to -1) conversion
+fn dac(value: u8, dac_enabled: bool) -> f32 {
+    if dac_enabled {
+        1. - value as f32 / 7.5
+    } else {
+        0.
+    }
+}
 
 #[derive(Clone, Debug)]
-pub struct Wave {
-    period_value: u16,
-    pub num_sample: usize,
-    wave_pattern: Box<[u8; 32]>,
-    length_timer: u8,
-    length_timer_enabled: bool,
-
-    env_initial_volume: f32,
-    env_direction: f32,
-    env_sweep_pace: u8,
-
-    period_sweep_pace: u8,
-    period_sweep_direction: u8,
-    period_sweep_slope: u8,
-
-    left_volume: u8,
-    right_volume: u8,
-}
-
-impl Wave {
-    pub fn new(
-        num_sample: usize,
-        period_value: u16,
-        wave_pattern: Box<[u8; 32]>,
-        env_initial_volume: u8,
-        env_direction: u8,
-        env_sweep_pace: u8,
-        length_timer: u8,
-        length_timer_enabled: bool,
-        period_sweep_pace: u8,
-        period_sweep_direction: u8,
-        period_sweep_slope: u8,
-        left_volume: u8,
-        right_volume: u8,
-    ) -> Wave {
-        Wave {
-            period_value,
-            num_sample,
-            wave_pattern,
-            env_initial_volume: env_initial_volume as f32,
-            env_direction: if env_direction == 0 { -1. } else { 1. },
-            env_sweep_pace,
-            length_timer,
-            length_timer_enabled,
-            period_sweep_pace,
-            period_sweep_direction,
-            period_sweep_slope,
-            left_volume,
-            right_volume,
-        }
-    }
-}
-
-impl io::Wave for Wave {
-    fn next(&mut self, left: bool) -> Option<f32> {
-        if left {
-            self.num_sample = self.num_sample.wrapping_add(1);
-        }
-
-        let mut period_value = self.period_value;
-
-        if period_value == 0 {
-            return None;
-        }
-
-        if self.length_timer_enabled
-            && self.length_timer < 64
-            && SAMPLE_RATE * (64 - self.length_timer as u32) / 256 < self.num_sample as u32
-        {
-            return None;
-        }
-
-        if self.period_sweep_slope != 0 && self.period_sweep_pace != 0 {
-            let sweep_i = ((self.num_sample as f32 * (32768 as f32 / SAMPLE_RATE as f32)) as u32
-                / 256)
-                / self.period_sweep_pace as u32;
-
-            if self.period_sweep_direction == 0 {
-                period_value = 2048
-                    - ((2048 - period_value) as f32
-                        * f32::powf(
-                            f32::powf(2., -(self.period_sweep_slope as f32)) + 1.,
-                            sweep_i as f32,
-                        )) as u16;
-            } else {
-                period_value = 2048
-                    - ((2048 - period_value) as f32
-                        * f32::powf(
-                            -f32::powf(2., -(self.period_sweep_slope as f32)) + 1.,
-                            sweep_i as f32,
-                        )) as u16;
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub counter: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    fn new(max: u16) -> Self {
+        Self {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    // NRx1 write
+    fn load(&mut self, length: u16) {
+        self.counter = self.max - length;
+    }
+
+    // NRx4 write. The length is clocked an extra time when it gets enabled during the half of
+    // the frame sequencer period that doesn't clock it.
+    // Returns false if the channel has to be turned off.
+    fn write_enable(&mut self, enable: bool, trigger: bool, frame_step: u8) -> bool {
+        let extra_clock = frame_step & 1 == 1;
+        let was_enabled = self.enabled;
+        let mut keep_on = true;
+        self.enabled = enable;
+
+        if extra_clock && !was_enabled && enable && self.counter != 0 {
+            self.counter -= 1;
+            if self.counter == 0 && !trigger {
+                keep_on = false;
             }
+        }
 
-            if period_value > 2048 {
-                return None;
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if enable && extra_clock {
+                self.counter -= 1;
             }
         }
 
-        let envelope_time = if self.env_sweep_pace != 0 {
-            (self.num_sample as f32 / SAMPLE_RATE as f32) * 64. / self.env_sweep_pace as f32
+        keep_on
+    }
+
+    // Returns true when the counter reaches 0 and the channel has to be turned off
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter == 0
         } else {
-            0.
-        };
+            false
+        }
+    }
+}
 
-        let envelope = self.env_initial_volume + (self.env_direction * envelope_time);
+#[derive(Clone, Debug, Default)]
+pub struct Envelope {
+    pub initial_volume: u8,
+    pub increase: bool,
+    pub period: u8,
+    pub volume: u8,
+    timer: u8,
+    running: bool,
+}
 
-        let envelope_boundaries = if envelope > 16. {
-            16.
-        } else if envelope < 0. {
-            0.
-        } else {
-            envelope
-        };
+impl Envelope {
+    // NRx2 write
+    fn write(&mut self, value: u8, playing: bool) {
+        let increase = value & 0b1000 != 0;
+
+        // "Zombie mode": writing to the envelope of a playing channel changes its volume
+        if playing {
+            if self.period == 0 && self.running {
+                self.volume += 1;
+            } else if !self.increase {
+                self.volume += 2;
+            }
+            if self.increase != increase {
+                self.volume = 16u8.wrapping_sub(self.volume);
+            }
+            self.volume &= 0xf;
+        }
 
-        let mut avg = 0.;
+        self.initial_volume = value >> 4;
+        self.increase = increase;
+        self.period = value & 0b111;
+    }
 
-        for n in 0..SAMPLE_AVERAGING {
-            if self.num_sample as i32 + n as i32 - SAMPLE_AVERAGING as i32 >= 0 {
-                avg += (self.wave_pattern[(((8. * 32768. / (SAMPLE_RATE as f32)
-                    * (self.num_sample + n - (SAMPLE_AVERAGING / 2)) as f32
-                    / period_value as f32)
-                    * 16.)
-                    % 32.) as u8 as usize] as f32
-                    * 2.
-                    - 16.)
-                    / 16.; // Before you ask, no I don't remember why it's so complicated :3
+    // NRx2 read
+    fn register(&self) -> u8 {
+        (self.initial_volume << 4) | ((self.increase as u8) << 3) | self.period
+    }
+
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.timer = self.period;
+        self.volume = self.initial_volume;
+        self.running = true;
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.running {
+                if self.increase && self.volume < 15 {
+                    self.volume += 1;
+                } else if !self.increase && self.volume > 0 {
+                    self.volume -= 1;
+                } else {
+                    self.running = false;
+                }
             }
         }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Sweep {
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow_frequency: u16,
+    // Set when a frequency was computed in negate mode since the last trigger
+    negate_used: bool,
+}
+
+impl Sweep {
+    // NR10 write. Returns false if the channel has to be turned off (clearing the negate
+    // bit after it was used disables the channel)
+    fn write(&mut self, value: u8) -> bool {
+        self.period = (value >> 4) & 0b111;
+        self.negate = value & 0b1000 != 0;
+        self.shift = value & 0b111;
+
+        !self.negate_used || self.negate
+    }
+
+    // NR10 read
+    fn register(&self) -> u8 {
+        0x80 | (self.period << 4) | ((self.negate as u8) << 3) | self.shift
+    }
+
+    // None if the new frequency overflows
+    fn calculate(&mut self) -> Option<u16> {
+        let delta = self.shadow_frequency >> self.shift;
+        let frequency = if self.negate {
+            self.negate_used = true;
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
 
-        if left {
-            avg = (self.left_volume as f32 / 8.) * avg;
+        if frequency > 2047 {
+            None
         } else {
-            avg = (self.right_volume as f32 / 8.) * avg;
+            Some(frequency)
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    // Returns false if the channel has to be turned off
+    fn trigger(&mut self, frequency: u16) -> bool {
+        self.shadow_frequency = frequency;
+        self.reload_timer();
+        self.enabled = self.period != 0 || self.shift != 0;
+        self.negate_used = false;
+
+        self.shift == 0 || self.calculate().is_some()
+    }
+
+    // Returns false if the channel has to be turned off
+    fn clock(&mut self, frequency: &mut u16) -> bool {
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer != 0 {
+            return true;
+        }
+
+        self.reload_timer();
+        if !self.enabled || self.period == 0 {
+            return true;
         }
 
-        Some((avg / SAMPLE_AVERAGING as f32) * envelope_boundaries / 32.)
+        match self.calculate() {
+            None => false,
+            Some(new_frequency) if self.shift != 0 => {
+                self.shadow_frequency = new_frequency;
+                *frequency = new_frequency;
+                // The overflow check is done a second time with the new frequency
+                self.calculate().is_some()
+            }
+            Some(_) => true,
+        }
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct NoiseWave {
-    pub num_sample: usize,
-    length_timer: u8,
-    length_timer_enabled: bool,
-
-    env_initial_volume: f32,
-    env_direction: f32,
-    env_sweep_pace: u8,
-
-    clock_shift: u8,
-    lsfr_width: u8,
-    clock_divider: u8,
-
-    rng: u16,
-    last_i: usize,
-
-    left_volume: u8,
-    right_volume: u8,
-}
-
-impl NoiseWave {
-    pub fn new(
-        num_sample: usize,
-        env_initial_volume: u8,
-        env_direction: u8,
-        env_sweep_pace: u8,
-        length_timer: u8,
-        length_timer_enabled: bool,
-        clock_shift: u8,
-        lsfr_width: u8,
-        clock_divider: u8,
-        left_volume: u8,
-        right_volume: u8,
-    ) -> NoiseWave {
-        NoiseWave {
-            num_sample,
-            env_initial_volume: env_initial_volume as f32,
-            env_direction: if env_direction == 0 { -1. } else { 1. },
-            env_sweep_pace,
-            length_timer,
-            length_timer_enabled,
-            clock_shift,
-            lsfr_width,
-            clock_divider,
-            rng: 0x42,
-            last_i: 0,
-            left_volume,
-            right_volume,
-        }
-    }
-}
-
-impl io::Wave for NoiseWave {
-    fn next(&mut self, left: bool) -> Option<f32> {
-        if left {
-            self.num_sample = self.num_sample.wrapping_add(1);
-        }
-
-        let clock_divider = if self.clock_divider == 0 {
-            0.5
-        } else {
-            self.clock_divider as f32
-        };
+pub struct SquareChannel {
+    pub on: bool,
+    pub duty: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    // Only channel 1 has a frequency sweep
+    pub sweep: Option<Sweep>,
+
+    duty_position: u8,
+    timer: i32,
+}
 
-        if self.length_timer_enabled
-            && self.length_timer < 64
-            && SAMPLE_RATE * (64 - self.length_timer as u32) / 256 < self.num_sample as u32
-        {
-            return None;
+impl SquareChannel {
+    pub fn new(sweep: bool) -> Self {
+        Self {
+            on: false,
+            duty: 0,
+            frequency: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            sweep: if sweep { Some(Sweep::default()) } else { None },
+            duty_position: 0,
+            timer: 0,
         }
+    }
 
-        let envelope_time = if self.env_sweep_pace != 0 {
-            (self.num_sample as f32 / SAMPLE_RATE as f32) * 64. / self.env_sweep_pace as f32
-        } else {
-            0.
-        };
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 4
+    }
 
-        let envelope = self.env_initial_volume + (self.env_direction * envelope_time);
+    /// Frequency of the square wave (8 duty steps)
+    pub fn frequency_hz(&self) -> f32 {
+        CPU_CLOCK_SPEED as f32 / (self.period() * 8) as f32
+    }
 
-        let envelope_boundaries = if envelope > 16. {
-            16.
-        } else if envelope < 0. {
-            0.
-        } else {
-            envelope
-        };
+    // Cycles until the next change of duty step
+    fn next_clock(&self) -> u32 {
+        self.timer.max(1) as u32
+    }
 
-        let ns = ((262144. / ((clock_divider) * (2 << self.clock_shift) as f32)) / 32768.)
-            * self.num_sample as f32;
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_position = (self.duty_position + 1) % 8;
+        }
+    }
 
-        let i = (ns as f32 * (32768 as f32 / SAMPLE_RATE as f32)) as usize;
+    /// Digital output (0-15)
+    pub fn output(&self) -> u8 {
+        if self.on && DUTY_PATTERNS[self.duty as usize] & (1 << self.duty_position) != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
 
-        let up = self.rng & 1;
-        if i != self.last_i {
-            self.last_i = i;
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
 
-            self.rng >>= 1;
-            if self.lsfr_width == 1 {
-                self.rng |= ((self.rng & 1) ^ ((self.rng >> 1) & 1)) << 7;
-            } else {
-                self.rng |= ((self.rng & 1) ^ ((self.rng >> 1) & 1)) << 15;
+    fn trigger(&mut self) {
+        self.on = self.dac_enabled();
+        self.timer = self.period();
+        self.envelope.trigger();
+        if let Some(sweep) = &mut self.sweep {
+            if !sweep.trigger(self.frequency) {
+                self.on = false;
             }
         }
+    }
 
-        let mut res = up as f32 * 2. - 1.;
-        if left {
-            res = (self.left_volume as f32 / 8.) * res;
-        } else {
-            res = (self.right_volume as f32 / 8.) * res;
+    // Reads of NRx0-NRx4, the write-only bits read as 1
+    fn r(&self, reg: u8) -> u8 {
+        match reg {
+            0 => self.sweep.as_ref().map_or(0xff, |sweep| sweep.register()),
+            1 => (self.duty << 6) | 0x3f,
+            2 => self.envelope.register(),
+            3 => 0xff,
+            _ => 0xbf | ((self.length.enabled as u8) << 6),
         }
+    }
 
-        Some(res * envelope_boundaries / 64.)
+    // Writes to NRx0-NRx4
+    fn w(&mut self, reg: u8, value: u8, frame_step: u8) {
+        match reg {
+            0 => {
+                if let Some(sweep) = &mut self.sweep {
+                    if !sweep.write(value) {
+                        self.on = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load((value & 0b111111) as u16);
+            }
+            2 => {
+                self.envelope.write(value, self.on);
+                if !self.dac_enabled() {
+                    self.on = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xff) | (((value & 0b111) as u16) << 8);
+                let trigger = value & 0x80 != 0;
+                if !self
+                    .length
+                    .write_enable(value & 0x40 != 0, trigger, frame_step)
+                {
+                    self.on = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+        }
     }
 }
 
 #[derive(Clone, Debug)]
-pub struct MutableWave {
-    wave_ch1: Arc<Mutex<Option<Wave>>>,
-    wave_ch2: Arc<Mutex<Option<Wave>>>,
-    wave_ch3: Arc<Mutex<Option<Wave>>>,
-    wave_ch4: Arc<Mutex<Option<NoiseWave>>>,
-}
-
-impl MutableWave {
-    pub fn new(
-        wave_ch1: Arc<Mutex<Option<Wave>>>,
-        wave_ch2: Arc<Mutex<Option<Wave>>>,
-        wave_ch3: Arc<Mutex<Option<Wave>>>,
-        wave_ch4: Arc<Mutex<Option<NoiseWave>>>,
-    ) -> Self {
+pub struct WaveChannel {
+    pub on: bool,
+    pub dac_on: bool,
+    pub volume_code: u8,
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub wave_ram: [u8; 16],
+
+    position: u8,
+    // Last nibble read from the wave RAM
+    sample: u8,
+    timer: i32,
+}
+
+impl Default for WaveChannel {
+    fn default() -> Self {
         Self {
-            wave_ch1,
-            wave_ch2,
-            wave_ch3,
-            wave_ch4,
+            on: false,
+            dac_on: false,
+            volume_code: 0,
+            frequency: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [0; 16],
+            position: 0,
+            sample: 0,
+            timer: 0,
         }
     }
 }
 
-impl io::Wave for MutableWave {
-    fn next(&mut self, left: bool) -> Option<f32> {
-        let mut res = 0.;
+impl WaveChannel {
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 2
+    }
 
-        // Imagine using an Arc<Mutex<>> in a sound wave generation function that needs to reliably
-        // run 65536 times a second. Couldn't be me :3
-        if let Ok(mut wave_o) = self.wave_ch1.lock() {
-            if let Some(wave) = wave_o.as_mut() {
-                if let Some(result) = wave.next(left) {
-                    res += result / 4.;
-                } else {
-                    *wave_o = None;
-                }
-            }
+    /// Frequency of the wave (32 samples)
+    pub fn frequency_hz(&self) -> f32 {
+        CPU_CLOCK_SPEED as f32 / (self.period() * 32) as f32
+    }
+
+    // Cycles until the next read of the wave RAM
+    fn next_clock(&self) -> u32 {
+        self.timer.max(1) as u32
+    }
+
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % 32;
+            let byte = self.wave_ram[self.position as usize / 2];
+            self.sample = if self.position & 1 == 0 {
+                byte >> 4
+            } else {
+                byte & 0xf
+            };
         }
+    }
 
-        if let Ok(mut wave_o) = self.wave_ch2.lock() {
-            if let Some(wave) = wave_o.as_mut() {
-                if let Some(result) = wave.next(left) {
-                    res += result / 4.;
-                } else {
-                    *wave_o = None;
+    /// Digital output (0-15)
+    pub fn output(&self) -> u8 {
+        if self.on {
+            self.sample >> WAVE_VOLUME_SHIFTS[self.volume_code as usize]
+        } else {
+            0
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.on = self.dac_on;
+        self.timer = self.period();
+        self.position = 0;
+    }
+
+    // While the channel is playing, the wave RAM can only be accessed at the byte being read by
+    // the channel. On DMG it is only possible right when the channel reads it, so this checks
+    // that the last read happened in the last 2 cycles.
+    fn wave_ram_index(&self, index: usize, cgb_mode: bool) -> Option<usize> {
+        if !self.on {
+            Some(index)
+        } else if cgb_mode || self.period() - self.timer < 2 {
+            Some(self.position as usize / 2)
+        } else {
+            None
+        }
+    }
+
+    fn r_wave_ram(&self, index: usize, cgb_mode: bool) -> u8 {
+        self.wave_ram_index(index, cgb_mode)
+            .map_or(0xff, |i| self.wave_ram[i])
+    }
+
+    fn w_wave_ram(&mut self, index: usize, value: u8, cgb_mode: bool) {
+        if let Some(i) = self.wave_ram_index(index, cgb_mode) {
+            self.wave_ram[i] = value;
+        }
+    }
+
+    // Reads of NR30-NR34, the write-only bits read as 1
+    fn r(&self, reg: u8) -> u8 {
+        match reg {
+            0 => 0x7f | ((self.dac_on as u8) << 7),
+            1 | 3 => 0xff,
+            2 => 0x9f | (self.volume_code << 5),
+            _ => 0xbf | ((self.length.enabled as u8) << 6),
+        }
+    }
+
+    // Writes to NR30-NR34
+    fn w(&mut self, reg: u8, value: u8, frame_step: u8) {
+        match reg {
+            0 => {
+                self.dac_on = value & 0x80 != 0;
+                if !self.dac_on {
+                    self.on = false;
+                }
+            }
+            1 => self.length.load(value as u16),
+            2 => self.volume_code = (value >> 5) & 0b11,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            _ => {
+                self.frequency = (self.frequency & 0xff) | (((value & 0b111) as u16) << 8);
+                let trigger = value & 0x80 != 0;
+                if !self
+                    .length
+                    .write_enable(value & 0x40 != 0, trigger, frame_step)
+                {
+                    self.on = false;
+                }
+                if trigger {
+                    self.trigger();
                 }
             }
         }
+    }
+}
 
-        if let Ok(mut wave_o) = self.wave_ch3.lock() {
-            if let Some(wave) = wave_o.as_mut() {
-                if let Some(result) = wave.next(left) {
-                    res += result / 4.;
-                } else {
-                    *wave_o = None;
-                }
+#[derive(Clone, Debug)]
+pub struct NoiseChannel {
+    pub on: bool,
+    pub clock_shift: u8,
+    // 7 bits LFSR instead of 15 bits
+    pub narrow: bool,
+    pub divisor_code: u8,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+
+    lfsr: u16,
+    timer: i32,
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self {
+            on: false,
+            clock_shift: 0,
+            narrow: false,
+            divisor_code: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::default(),
+            lfsr: 0x7fff,
+            timer: 0,
+        }
+    }
+}
+
+impl NoiseChannel {
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[self.divisor_code as usize] << self.clock_shift) as i32
+    }
+
+    /// Shifts of the LFSR per second
+    pub fn frequency_hz(&self) -> f32 {
+        CPU_CLOCK_SPEED as f32 / self.period() as f32
+    }
+
+    // Cycles until the next shift of the LFSR
+    fn next_clock(&self) -> u32 {
+        self.timer.max(1) as u32
+    }
+
+    fn step(&mut self, cycles: u32) {
+        self.timer -= cycles as i32;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            let xor = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (xor << 14);
+            if self.narrow {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (xor << 6);
             }
         }
+    }
 
-        if let Ok(mut wave_o) = self.wave_ch4.lock() {
-            if let Some(wave) = wave_o.as_mut() {
-                if let Some(result) = wave.next(left) {
-                    res += result / 4.;
-                } else {
-                    *wave_o = None;
+    /// Digital output (0-15)
+    pub fn output(&self) -> u8 {
+        if self.on && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    fn trigger(&mut self) {
+        self.on = self.dac_enabled();
+        self.timer = self.period();
+        self.lfsr = 0x7fff;
+        self.envelope.trigger();
+    }
+
+    // Reads of NR41-NR44 (0xff1f is unused), the write-only bits read as 1
+    fn r(&self, reg: u8) -> u8 {
+        match reg {
+            0 | 1 => 0xff,
+            2 => self.envelope.register(),
+            3 => (self.clock_shift << 4) | ((self.narrow as u8) << 3) | self.divisor_code,
+            _ => 0xbf | ((self.length.enabled as u8) << 6),
+        }
+    }
+
+    // Writes to NR41-NR44 (0xff1f is unused)
+    fn w(&mut self, reg: u8, value: u8, frame_step: u8) {
+        match reg {
+            0 => {}
+            1 => self.length.load((value & 0b111111) as u16),
+            2 => {
+                self.envelope.write(value, self.on);
+                if !self.dac_enabled() {
+                    self.on = false;
+                }
+            }
+            3 => {
+                self.clock_shift = value >> 4;
+                self.narrow = value & 0b1000 != 0;
+                self.divisor_code = value & 0b111;
+            }
+            _ => {
+                let trigger = value & 0x80 != 0;
+                if !self
+                    .length
+                    .write_enable(value & 0x40 != 0, trigger, frame_step)
+                {
+                    self.on = false;
+                }
+                if trigger {
+                    self.trigger();
                 }
             }
         }
+    }
+}
 
-        Some(res)
+/// Mixer settings of the frontend for CH1-CH4. They are applied on top of NR50/NR51 without
+/// changing the registers seen by the game.
+#[derive(Clone, Debug)]
+pub struct ChannelControls {
+    pub muted: [bool; 4],
+    // Only this channel can be heard
+    pub solo: Option<usize>,
+    pub volumes: [f32; 4],
+}
+
+impl Default for ChannelControls {
+    fn default() -> Self {
+        Self {
+            muted: [false; 4],
+            solo: None,
+            volumes: [1.; 4],
+        }
     }
 }
 
-pub struct AudioSquareChannel {
-    wave: Arc<Mutex<Option<Wave>>>,
+// Band-limited stereo output and the capacitors of its high-pass filter
+struct StereoBuffer {
+    blips: [BlipBuffer; 2],
+    // Last levels added to the left and right buffers
+    levels: [f32; 2],
+    // Charge of the left and right capacitors
+    capacitors: [f32; 2],
+    // Model of the high-pass filter and its charge factor at the current sample rate
+    high_pass: HighPass,
+    charge_factor: f32,
+}
 
-    pub length_timer: u8,
-    pub length_timer_enabled: bool,
-    pub on: bool,
-    pub period_value: u16,
-    pub duty: u8,
-    pub initial_volume: u8,
-    pub env_direction: u8,
-    pub sweep: u8,
-    pub period_sweep_pace: u8,
-    pub period_sweep_direction: u8,
-    pub period_sweep_slope: u8,
-    pub left: bool,
-    pub right: bool,
-    pub left_volume: u8,
-    pub right_volume: u8,
-}
-
-impl AudioSquareChannel {
-    pub fn new(wave: Arc<Mutex<Option<Wave>>>) -> Self {
+impl StereoBuffer {
+    fn new(sample_rate: u32) -> Self {
         Self {
-            on: true,
-            period_value: 0,
-            duty: 0,
-            initial_volume: 0,
-            env_direction: 0,
-            sweep: 0,
-            wave,
-            length_timer: 0,
-            length_timer_enabled: false,
-            period_sweep_pace: 0,
-            period_sweep_direction: 0,
-            period_sweep_slope: 0,
-            left: true,
-            right: true,
-            left_volume: 7,
-            right_volume: 7,
-        }
-    }
-
-    pub fn update(&mut self, reset: bool) {
-        if let Ok(mut wave) = self.wave.lock() {
-            let num_sample = if let Some(w) = &*wave {
-                w.num_sample
-            } else {
-                0
-            };
-            if self.on && (reset || wave.is_some()) {
-                *wave = Some(Wave::new(
-                    if reset { 0 } else { num_sample },
-                    2048 - self.period_value,
-                    Box::new(SQUARE_WAVE_PATTERNS[self.duty as usize]),
-                    self.initial_volume,
-                    self.env_direction,
-                    self.sweep,
-                    self.length_timer,
-                    self.length_timer_enabled,
-                    self.period_sweep_pace,
-                    self.period_sweep_direction,
-                    self.period_sweep_slope,
-                    if self.left { self.left_volume + 1 } else { 0 },
-                    if self.right { self.right_volume + 1 } else { 0 },
-                ));
-            } else {
-                *wave = None;
+            blips: [
+                BlipBuffer::new(CPU_CLOCK_SPEED, sample_rate),
+                BlipBuffer::new(CPU_CLOCK_SPEED, sample_rate),
+            ],
+            levels: [0.; 2],
+            capacitors: [0.; 2],
+            high_pass: HighPass::None,
+            charge_factor: HighPass::None.charge_factor(sample_rate),
+        }
+    }
+
+    // Level of both sides from `time` cycles after the start of the current frame
+    fn set_levels(&mut self, time: u32, levels: [f32; 2]) {
+        for (side, level) in levels.into_iter().enumerate() {
+            if level != self.levels[side] {
+                self.blips[side].add_delta(time, level - self.levels[side]);
+                self.levels[side] = level;
             }
         }
     }
 
-    pub fn get_wave_mutex(&self) -> Arc<Mutex<Option<Wave>>> {
-        return self.wave.clone();
+    fn end_frame(&mut self, time: u32) {
+        for blip in &mut self.blips {
+            blip.end_frame(time);
+        }
     }
-}
 
-pub struct AudioCustomChannel {
-    wave: Arc<Mutex<Option<Wave>>>,
+    fn set_sample_rate(&mut self, sample_rate: u32) {
+        for blip in &mut self.blips {
+            blip.set_sample_rate(sample_rate);
+        }
+        self.charge_factor = self.high_pass.charge_factor(sample_rate);
+    }
 
-    pub length_timer: u8,
-    pub length_timer_enabled: bool,
-    pub wave_pattern: Box<[u8; 32]>,
-    pub on: bool,
-    pub period_value: u16,
-    pub initial_volume: u8,
+    fn samples_available(&self) -> usize {
+        self.blips[0].samples_available()
+    }
 
-    pub left: bool,
-    pub right: bool,
-    pub left_volume: u8,
-    pub right_volume: u8,
+    // Interleaved filtered samples
+    fn read_samples(&mut self, count: usize, high_pass: HighPass, dacs_enabled: bool) -> Vec<f32> {
+        if high_pass != self.high_pass {
+            self.high_pass = high_pass;
+            self.charge_factor = high_pass.charge_factor(self.blips[0].sample_rate());
+        }
+        let charge_factor = self.charge_factor;
+        let left = self.blips[0].read_samples(count);
+        let right = self.blips[1].read_samples(count);
+
+        let mut samples = Vec::with_capacity(left.len() * 2);
+        for (l, r) in left.into_iter().zip(right) {
+            samples.push(high_pass.apply(&mut self.capacitors[0], l, dacs_enabled, charge_factor));
+            samples.push(high_pass.apply(&mut self.capacitors[1], r, dacs_enabled, charge_factor));
+        }
+        samples
+    }
 }
 
-impl AudioCustomChannel {
-    pub fn new(wave: Arc<Mutex<Option<Wave>>>) -> Self {
-        Self {
-            wave_pattern: Box::new([0; 32]),
-            on: true,
-            period_value: 0,
-            initial_volume: 0,
-            wave,
-            length_timer: 0,
-            length_timer_enabled: false,
+/// Recent analog outputs of each channel, for the oscilloscope views
+pub struct Scope {
+    samples: Box<[[f32; SCOPE_LENGTH]; 4]>,
+    // Index of the next sample to write
+    position: usize,
+    // Cycles since the last sample
+    clock: u32,
+}
 
-            left: true,
-            right: true,
-            left_volume: 7,
-            right_volume: 7,
+impl Scope {
+    fn new() -> Self {
+        Self {
+            samples: Box::new([[0.; SCOPE_LENGTH]; 4]),
+            position: 0,
+            clock: 0,
         }
     }
 
-    pub fn update(&mut self, reset: bool) {
-        if let Ok(mut wave) = self.wave.lock() {
-            let num_sample = if let Some(w) = &*wave {
-                w.num_sample
-            } else {
-                0
-            };
-            if self.on && (reset || wave.is_some()) {
-                *wave = Some(Wave::new(
-                    if reset { 0 } else { num_sample },
-                    2 * (2048 - (self.period_value * 2)),
-                    self.wave_pattern.clone(),
-                    self.initial_volume,
-                    0,
-                    0,
-                    self.length_timer,
-                    self.length_timer_enabled,
-                    0,
-                    0,
-                    0,
-                    if self.left { self.left_volume + 1 } else { 0 },
-                    if self.right { self.right_volume + 1 } else { 0 },
-                ));
-            } else {
-                *wave = None;
+    // The outputs didn't change during `cycles`
+    fn record(&mut self, cycles: u32, outputs: [f32; 4]) {
+        self.clock += cycles;
+        while self.clock >= SCOPE_PERIOD {
+            self.clock -= SCOPE_PERIOD;
+            for (samples, output) in self.samples.iter_mut().zip(outputs) {
+                samples[self.position] = output;
             }
+            self.position = (self.position + 1) % SCOPE_LENGTH;
         }
     }
 
-    pub fn get_wave_mutex(&self) -> Arc<Mutex<Option<Wave>>> {
-        return self.wave.clone();
+    /// The last `SCOPE_LENGTH` samples of a channel, oldest first
+    pub fn samples(&self, channel: usize) -> Vec<f32> {
+        let samples = &self.samples[channel];
+        [&samples[self.position..], &samples[..self.position]].concat()
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        CPU_CLOCK_SPEED as u32 / SCOPE_PERIOD
     }
 }
 
-pub struct AudioNoiseChannel {
-    wave: Arc<Mutex<Option<NoiseWave>>>,
+/// Copy of the audio generated since the last `Channels::take_capture`
+#[derive(Clone, Debug, Default)]
+pub struct Capture {
+    // Interleaved stereo samples, like the ones given to the backend
+    pub mix: Vec<f32>,
+    // Interleaved stereo samples of each channel alone, with the panning and master volume but
+    // not the frontend controls. The stems add up to the mix when no control is used.
+    pub stems: Option<[Vec<f32>; 4]>,
+}
 
-    pub length_timer: u8,
-    pub length_timer_enabled: bool,
-    pub on: bool,
-    pub initial_volume: u8,
-    pub env_direction: u8,
-    pub sweep: u8,
-    pub clock_shift: u8,
-    pub lsfr_width: u8,
-    pub clock_divider: u8,
+pub struct Channels<A: Audio> {
+    audio: A,
 
-    pub left: bool,
-    pub right: bool,
-    pub left_volume: u8,
-    pub right_volume: u8,
+    pub ch1: SquareChannel,
+    pub ch2: SquareChannel,
+    pub ch3: WaveChannel,
+    pub ch4: NoiseChannel,
+
+    // NR50
+    pub master_volume: u8,
+    // NR51
+    pub panning: u8,
+    // NR52 bit 7. Starts on like the boot ROM leaves it.
+    pub power: bool,
+
+    pub high_pass: HighPass,
+    pub controls: ChannelControls,
+
+    // Next step of the frame sequencer (0-7)
+    frame_step: u8,
+    last_div: u8,
+
+    output: StereoBuffer,
+    // Output of each channel alone, only generated while capturing stems
+    stems: Option<Box<[StereoBuffer; 4]>>,
+    capture: Option<Capture>,
+    scope: Option<Box<Scope>>,
+    // Cycles since the start of the current blip frame
+    time: u32,
 }
 
-impl AudioNoiseChannel {
-    pub fn new(wave: Arc<Mutex<Option<NoiseWave>>>) -> Self {
+impl<A: Audio> Channels<A> {
+    pub fn new(audio: A) -> Self {
+        let sample_rate = audio.sample_rate();
         Self {
-            on: true,
-            initial_volume: 0,
-            env_direction: 0,
-            sweep: 0,
-            wave,
-            length_timer: 0,
-            length_timer_enabled: false,
-            clock_shift: 0,
-            lsfr_width: 0,
-            clock_divider: 0,
+            audio,
+            ch1: SquareChannel::new(true),
+            ch2: SquareChannel::new(false),
+            ch3: WaveChannel::default(),
+            ch4: NoiseChannel::default(),
+            master_volume: 0x77,
+            panning: 0xff,
+            power: true,
+            high_pass: HighPass::Dmg,
+            controls: ChannelControls::default(),
+            frame_step: 0,
+            last_div: 0,
+            output: StereoBuffer::new(sample_rate),
+            stems: None,
+            capture: None,
+            scope: None,
+            time: 0,
+        }
+    }
+
+    fn clock_frame_sequencer(&mut self) {
+        let step = self.frame_step;
 
-            left: true,
-            right: true,
-            left_volume: 7,
-            right_volume: 7,
+        if step & 1 == 0 {
+            if self.ch1.length.clock() {
+                self.ch1.on = false;
+            }
+            if self.ch2.length.clock() {
+                self.ch2.on = false;
+            }
+            if self.ch3.length.clock() {
+                self.ch3.on = false;
+            }
+            if self.ch4.length.clock() {
+                self.ch4.on = false;
+            }
         }
+
+        if step == 2 || step == 6 {
+            let ch1 = &mut self.ch1;
+            if let Some(sweep) = &mut ch1.sweep {
+                if !sweep.clock(&mut ch1.frequency) {
+                    ch1.on = false;
+                }
+            }
+        }
+
+        if step == 7 {
+            self.ch1.envelope.clock();
+            self.ch2.envelope.clock();
+            self.ch4.envelope.clock();
+        }
+
+        self.frame_step = (step + 1) % 8;
     }
 
-    pub fn update(&mut self, reset: bool) {
-        if let Ok(mut wave) = self.wave.lock() {
-            let num_sample = if let Some(w) = &*wave {
-                w.num_sample
-            } else {
-                0
-            };
-            if self.on && (reset || wave.is_some()) {
-                *wave = Some(NoiseWave::new(
-                    if reset { 0 } else { num_sample },
-                    self.initial_volume,
-                    self.env_direction,
-                    self.sweep,
-                    self.length_timer,
-                    self.length_timer_enabled,
-                    self.clock_shift,
-                    self.lsfr_width,
-                    self.clock_divider,
-                    if self.left { self.left_volume + 1 } else { 0 },
-                    if self.right { self.right_volume + 1 } else { 0 },
-                ));
+    /// Seconds of audio waiting to be played by the backend
+    pub fn latency(&self) -> Option<f32> {
+        self.audio.latency()
+    }
+
+    /// Generates `ratio` times the backend rate of samples per emulated second. When the
+    /// emulation is paced by the audio, this makes it `ratio` times slower.
+    pub fn set_rate_ratio(&mut self, ratio: f64) {
+        let sample_rate = (self.audio.sample_rate() as f64 * ratio).round() as u32;
+        self.output.set_sample_rate(sample_rate);
+        if let Some(stems) = &mut self.stems {
+            for stem in stems.iter_mut() {
+                stem.set_sample_rate(sample_rate);
+            }
+        }
+    }
+
+    /// Starts keeping a copy of the generated audio, and of each channel alone with `stems`
+    pub fn start_capture(&mut self, stems: bool) {
+        self.capture = Some(Capture {
+            mix: vec![],
+            stems: if stems {
+                Some(Default::default())
             } else {
-                *wave = None;
+                None
+            },
+        });
+        self.stems = if stems {
+            let sample_rate = self.output.blips[0].sample_rate();
+            Some(Box::new(std::array::from_fn(|_| {
+                StereoBuffer::new(sample_rate)
+            })))
+        } else {
+            None
+        };
+    }
+
+    /// Starts or stops keeping the recent outputs of each channel
+    pub fn enable_scope(&mut self, enabled: bool) {
+        self.scope = if enabled {
+            Some(Box::new(Scope::new()))
+        } else {
+            None
+        };
+    }
+
+    pub fn scope(&self) -> Option<&Scope> {
+        self.scope.as_deref()
+    }
+
+    /// The audio generated since the last call (None if it isn't being captured)
+    pub fn take_capture(&mut self) -> Option<Capture> {
+        self.capture.as_mut().map(|capture| Capture {
+            mix: std::mem::take(&mut capture.mix),
+            stems: capture
+                .stems
+                .as_mut()
+                .map(|stems| stems.each_mut().map(std::mem::take)),
+        })
+    }
+
+    /// False if the channel (0-3) is muted or another one is played solo
+    pub fn channel_audible(&self, channel: usize) -> bool {
+        !self.controls.muted[channel] && self.controls.solo.is_none_or(|solo| solo == channel)
+    }
+
+    pub fn set_channel_muted(&mut self, channel: usize, muted: bool) {
+        self.controls.muted[channel] = muted;
+    }
+
+    /// Returns true if the channel is now muted
+    pub fn toggle_channel_mute(&mut self, channel: usize) -> bool {
+        self.controls.muted[channel] = !self.controls.muted[channel];
+        self.controls.muted[channel]
+    }
+
+    /// Only plays this channel (all of them if None)
+    pub fn solo_channel(&mut self, channel: Option<usize>) {
+        self.controls.solo = channel;
+    }
+
+    /// Volume of the channel in the mix, 1 being the normal volume
+    pub fn set_channel_volume(&mut self, channel: usize, volume: f32) {
+        self.controls.volumes[channel] = volume.max(0.);
+    }
+
+    /// Analog output of each channel (between -1 and 1)
+    pub fn outputs(&self) -> [f32; 4] {
+        [
+            dac(self.ch1.output(), self.ch1.dac_enabled()),
+            dac(self.ch2.output(), self.ch2.dac_enabled()),
+            dac(self.ch3.output(), self.ch3.dac_on),
+            dac(self.ch4.output(), self.ch4.dac_enabled()),
+        ]
+    }
+
+    // Left and right output of a channel with the NR51 panning and NR50 volumes. The NR50
+    // volumes go from 1/8 to 8/8, its VIN bits (audio from the cartridge) are ignored since no
+    // cartridge uses it.
+    fn pan(&self, channel: usize, output: f32) -> [f32; 2] {
+        let mut sides = [0.; 2];
+        if self.panning & (0x10 << channel) != 0 {
+            sides[0] = output / 4. * (((self.master_volume >> 4) & 0b111) + 1) as f32 / 8.;
+        }
+        if self.panning & (1 << channel) != 0 {
+            sides[1] = output / 4. * ((self.master_volume & 0b111) + 1) as f32 / 8.;
+        }
+        sides
+    }
+
+    // Adds the changes of the output since the last call to the blip buffers
+    fn update_levels(&mut self) {
+        let outputs = self.outputs();
+
+        let mut mix = [0.; 2];
+        for (i, output) in outputs.iter().enumerate() {
+            if self.channel_audible(i) {
+                let [left, right] = self.pan(i, output * self.controls.volumes[i]);
+                mix[0] += left;
+                mix[1] += right;
+            }
+        }
+        self.output.set_levels(self.time, mix);
+
+        if self.stems.is_some() {
+            let levels: [[f32; 2]; 4] = std::array::from_fn(|i| self.pan(i, outputs[i]));
+            if let Some(stems) = &mut self.stems {
+                for (stem, levels) in stems.iter_mut().zip(levels) {
+                    stem.set_levels(self.time, levels);
+                }
+            }
+        }
+    }
+
+    fn push_samples(&mut self) {
+        let dacs_enabled = self.ch1.dac_enabled()
+            || self.ch2.dac_enabled()
+            || self.ch3.dac_on
+            || self.ch4.dac_enabled();
+
+        let count = self.output.samples_available();
+        let samples = self
+            .output
+            .read_samples(count, self.high_pass, dacs_enabled);
+
+        if let Some(capture) = &mut self.capture {
+            capture.mix.extend_from_slice(&samples);
+            if let (Some(captured), Some(stems)) = (&mut capture.stems, &mut self.stems) {
+                for (captured, stem) in captured.iter_mut().zip(stems.iter_mut()) {
+                    captured.extend(stem.read_samples(count, self.high_pass, dacs_enabled));
+                }
+            }
+        }
+
+        self.audio.push_samples(&samples);
+    }
+
+    /// Runs the APU for `cycles` cycles. `div` is the current value of the DIV register.
+    pub fn tick(&mut self, cycles: u64, div: u8) {
+        // The frame sequencer is clocked on the falling edge of the bit 4 of DIV (512Hz)
+        if self.power && self.last_div & 0x10 != 0 && div & 0x10 == 0 {
+            self.clock_frame_sequencer();
+        }
+        self.last_div = div;
+
+        // Changes from the register writes and the frame sequencer
+        self.update_levels();
+
+        // Stepping from one change of a channel to the next so the steps are placed exactly
+        let mut cycles = cycles as u32;
+        while cycles > 0 {
+            let step = cycles
+                .min(self.ch1.next_clock())
+                .min(self.ch2.next_clock())
+                .min(self.ch3.next_clock())
+                .min(self.ch4.next_clock());
+
+            if self.scope.is_some() {
+                let outputs = self.outputs();
+                if let Some(scope) = &mut self.scope {
+                    scope.record(step, outputs);
+                }
+            }
+
+            self.ch1.step(step);
+            self.ch2.step(step);
+            self.ch3.step(step);
+            self.ch4.step(step);
+
+            cycles -= step;
+            self.time += step;
+            self.update_levels();
+        }
+
+        self.output.end_frame(self.time);
+        if let Some(stems) = &mut self.stems {
+            for stem in stems.iter_mut() {
+                stem.end_frame(self.time);
+            }
+        }
+        self.time = 0;
+
+        if self.output.samples_available() >= PUSH_SIZE {
+            self.push_samples();
+        }
+    }
+
+    // Turning the APU off clears all the registers except the wave RAM and, on DMG, the length
+    // counters. Turning it back on resets the frame sequencer.
+    fn set_power(&mut self, power: bool, cgb_mode: bool) {
+        if !power && self.power {
+            let lengths = [
+                self.ch1.length.counter,
+                self.ch2.length.counter,
+                self.ch3.length.counter,
+                self.ch4.length.counter,
+            ];
+            let wave_ram = self.ch3.wave_ram;
+
+            self.ch1 = SquareChannel::new(true);
+            self.ch2 = SquareChannel::new(false);
+            self.ch3 = WaveChannel::default();
+            self.ch4 = NoiseChannel::default();
+            self.ch3.wave_ram = wave_ram;
+            self.master_volume = 0;
+            self.panning = 0;
+
+            if !cgb_mode {
+                self.ch1.length.counter = lengths[0];
+                self.ch2.length.counter = lengths[1];
+                self.ch3.length.counter = lengths[2];
+                self.ch4.length.counter = lengths[3];
+            }
+        } else if power && !self.power {
+            self.frame_step = 0;
+        }
+        self.power = power;
+    }
+
+    /// Register writes (address, value) bringing a powered off APU to the current settings,
+    /// without triggering the channels. Used to start logs of the writes in the middle of a game.
+    pub fn register_writes(&self) -> Vec<(u8, u8)> {
+        if !self.power {
+            return vec![(0x26, 0x00)];
+        }
+
+        // The wave RAM is written with the DAC of CH3 off so it isn't redirected
+        let mut writes = vec![(0x26, 0x80), (0x1a, 0x00)];
+        for (i, byte) in self.ch3.wave_ram.iter().enumerate() {
+            writes.push((0x30 + i as u8, *byte));
+        }
+
+        let length_enabled = |length: &LengthCounter| (length.enabled as u8) << 6;
+        for (base, ch) in [(0x10, &self.ch1), (0x15, &self.ch2)] {
+            writes.extend([
+                (
+                    base,
+                    ch.sweep.as_ref().map_or(0, |sweep| sweep.register() & 0x7f),
+                ),
+                (base + 1, ch.duty << 6),
+                (base + 2, ch.envelope.register()),
+                (base + 3, ch.frequency as u8),
+                (
+                    base + 4,
+                    (ch.frequency >> 8) as u8 | length_enabled(&ch.length),
+                ),
+            ]);
+        }
+        writes.extend([
+            (0x1a, (self.ch3.dac_on as u8) << 7),
+            (0x1c, self.ch3.volume_code << 5),
+            (0x1d, self.ch3.frequency as u8),
+            (
+                0x1e,
+                (self.ch3.frequency >> 8) as u8 | length_enabled(&self.ch3.length),
+            ),
+            (0x21, self.ch4.envelope.register()),
+            (0x22, self.ch4.r(3)),
+            (0x23, length_enabled(&self.ch4.length)),
+            (0x24, self.master_volume),
+            (0x25, self.panning),
+        ]);
+        writes
+    }
+
+    /// Reads of the sound registers (0xff10-0xff3f). The write-only and unused bits read as 1.
+    pub fn r(&self, addr: u8, cgb_mode: bool) -> u8 {
+        match addr {
+            0x10..=0x14 => self.ch1.r(addr - 0x10),
+            0x15..=0x19 => self.ch2.r(addr - 0x15),
+            0x1a..=0x1e => self.ch3.r(addr - 0x1a),
+            0x1f..=0x23 => self.ch4.r(addr - 0x1f),
+            0x24 => self.master_volume,
+            0x25 => self.panning,
+            0x26 => {
+                ((self.power as u8) << 7)
+                    | 0x70
+                    | (self.ch1.on as u8)
+                    | ((self.ch2.on as u8) << 1)
+                    | ((self.ch3.on as u8) << 2)
+                    | ((self.ch4.on as u8) << 3)
             }
+            0x30..=0x3f => self.ch3.r_wave_ram(addr as usize - 0x30, cgb_mode),
+            _ => 0xff,
         }
     }
 
-    pub fn get_wave_mutex(&self) -> Arc<Mutex<Option<NoiseWave>>> {
-        return self.wave.clone();
+    /// Writes to the sound registers (0xff10-0xff3f). While the APU is off, only NR52, the wave
+    /// RAM and (on DMG) the lengths can be written.
+    pub fn w(&mut self, addr: u8, value: u8, cgb_mode: bool) {
+        if !self.power && (0x10..=0x25).contains(&addr) {
+            if !cgb_mode {
+                match addr {
+                    0x11 => self.ch1.length.load((value & 0b111111) as u16),
+                    0x16 => self.ch2.length.load((value & 0b111111) as u16),
+                    0x1b => self.ch3.length.load(value as u16),
+                    0x20 => self.ch4.length.load((value & 0b111111) as u16),
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        let frame_step = self.frame_step;
+        match addr {
+            0x10..=0x14 => self.ch1.w(addr - 0x10, value, frame_step),
+            0x15..=0x19 => self.ch2.w(addr - 0x15, value, frame_step),
+            0x1a..=0x1e => self.ch3.w(addr - 0x1a, value, frame_step),
+            0x1f..=0x23 => self.ch4.w(addr - 0x1f, value, frame_step),
+            0x24 => self.master_volume = value,
+            0x25 => self.panning = value,
+            0x26 => self.set_power(value & 0x80 != 0, cgb_mode),
+            0x30..=0x3f => self.ch3.w_wave_ram(addr as usize - 0x30, value, cgb_mode),
+            _ => {}
+        }
     }
 }
 
-pub struct Channels<A: Audio> {
-    audio: A,
+// Save states (see `save_state`). Only the state of the hardware is saved, not the output
+// buffers nor the frontend settings.
+
+impl LengthCounter {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.counter);
+    }
 
-    pub ch1: AudioSquareChannel,
-    pub ch2: AudioSquareChannel,
-    pub ch3: AudioCustomChannel,
-    pub ch4: AudioNoiseChannel,
+    fn read_state(&mut self, r: &mut StateReader) {
+        self.enabled = r.bool();
+        self.counter = r.u16().min(self.max);
+    }
 }
 
-impl<A: Audio> Channels<A> {
-    pub fn new(mut audio: A) -> Self {
-        let wave_ch1 = Arc::new(Mutex::new(None));
-        let wave_ch2 = Arc::new(Mutex::new(None));
-        let wave_ch3 = Arc::new(Mutex::new(None));
-        let wave_ch4 = Arc::new(Mutex::new(None));
-
-        audio.attach_wave(MutableWave::new(
-            wave_ch1.clone(),
-            wave_ch2.clone(),
-            wave_ch3.clone(),
-            wave_ch4.clone(),
-        ));
+impl Envelope {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.initial_volume);
+        w.bool(self.increase);
+        w.u8(self.period);
+        w.u8(self.volume);
+        w.u8(self.timer);
+        w.bool(self.running);
+    }
 
-        Self {
-            audio: audio,
-            ch1: AudioSquareChannel::new(wave_ch1),
-            ch2: AudioSquareChannel::new(wave_ch2),
-            ch3: AudioCustomChannel::new(wave_ch3),
-            ch4: AudioNoiseChannel::new(wave_ch4),
+    fn read_state(&mut self, r: &mut StateReader) {
+        self.initial_volume = r.u8() & 0xf;
+        self.increase = r.bool();
+        self.period = r.u8() & 0b111;
+        self.volume = r.u8() & 0xf;
+        self.timer = r.u8();
+        self.running = r.bool();
+    }
+}
+
+impl Sweep {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.u8(self.period);
+        w.bool(self.negate);
+        w.u8(self.shift);
+        w.u8(self.timer);
+        w.bool(self.enabled);
+        w.u16(self.shadow_frequency);
+        w.bool(self.negate_used);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) {
+        self.period = r.u8() & 0b111;
+        self.negate = r.bool();
+        self.shift = r.u8() & 0b111;
+        self.timer = r.u8();
+        self.enabled = r.bool();
+        self.shadow_frequency = r.u16() & 0x7ff;
+        self.negate_used = r.bool();
+    }
+}
+
+impl SquareChannel {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.on);
+        w.u8(self.duty);
+        w.u16(self.frequency);
+        self.length.write_state(w);
+        self.envelope.write_state(w);
+        if let Some(sweep) = &self.sweep {
+            sweep.write_state(w);
         }
+        w.u8(self.duty_position);
+        w.i32(self.timer);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) {
+        self.on = r.bool();
+        self.duty = r.u8() & 0b11;
+        self.frequency = r.u16() & 0x7ff;
+        self.length.read_state(r);
+        self.envelope.read_state(r);
+        if let Some(sweep) = &mut self.sweep {
+            sweep.read_state(r);
+        }
+        self.duty_position = r.u8() % 8;
+        self.timer = r.i32();
+    }
+}
+
+impl WaveChannel {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.on);
+        w.bool(self.dac_on);
+        w.u8(self.volume_code);
+        w.u16(self.frequency);
+        self.length.write_state(w);
+        w.bytes(&self.wave_ram);
+        w.u8(self.position);
+        w.u8(self.sample);
+        w.i32(self.timer);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) {
+        self.on = r.bool();
+        self.dac_on = r.bool();
+        self.volume_code = r.u8() & 0b11;
+        self.frequency = r.u16() & 0x7ff;
+        self.length.read_state(r);
+        r.bytes(&mut self.wave_ram);
+        self.position = r.u8() % 32;
+        self.sample = r.u8() & 0xf;
+        self.timer = r.i32();
+    }
+}
+
+impl NoiseChannel {
+    fn write_state(&self, w: &mut StateWriter) {
+        w.bool(self.on);
+        w.u8(self.clock_shift);
+        w.bool(self.narrow);
+        w.u8(self.divisor_code);
+        self.length.write_state(w);
+        self.envelope.write_state(w);
+        w.u16(self.lfsr);
+        w.i32(self.timer);
+    }
+
+    fn read_state(&mut self, r: &mut StateReader) {
+        self.on = r.bool();
+        self.clock_shift = r.u8() & 0xf;
+        self.narrow = r.bool();
+        self.divisor_code = r.u8() & 0b111;
+        self.length.read_state(r);
+        self.envelope.read_state(r);
+        self.lfsr = r.u16();
+        self.timer = r.i32();
+    }
+}
+
+impl<A: Audio> Channels<A> {
+    /// Writes the state of the APU (see `save_state`)
+    pub fn write_state(&self, w: &mut StateWriter) {
+        self.ch1.write_state(w);
+        self.ch2.write_state(w);
+        self.ch3.write_state(w);
+        self.ch4.write_state(w);
+        w.u8(self.master_volume);
+        w.u8(self.panning);
+        w.bool(self.power);
+        w.u8(self.frame_step);
+        w.u8(self.last_div);
     }
 
-    pub fn next(&mut self) {
-        self.audio.next();
+    pub fn read_state(&mut self, r: &mut StateReader) {
+        self.ch1.read_state(r);
+        self.ch2.read_state(r);
+        self.ch3.read_state(r);
+        self.ch4.read_state(r);
+        self.master_volume = r.u8();
+        self.panning = r.u8();
+        self.power = r.bool();
+        self.frame_step = r.u8() % 8;
+        self.last_div = r.u8();
     }
 }
@@ -0,0 +1,220 @@
+// Debug view of the APU: an oscilloscope of each channel with its frequency and note, volume and
+// envelope, duty, panning and state, and the master registers.
+
+use crate::audio::{Channels, Envelope, Scope};
+use crate::canvas::{Canvas, GLYPH_HEIGHT};
+use crate::io::Audio;
+
+const MARGIN: usize = 8;
+const SCOPE_WIDTH: usize = 256;
+const SCOPE_HEIGHT: usize = 64;
+const INFO_WIDTH: usize = 128;
+const LINE_HEIGHT: usize = GLYPH_HEIGHT + 2;
+
+// Samples of the scope shown (15.6ms), the older ones are used to find a trigger point
+const SCOPE_WINDOW: usize = 1024;
+
+pub const WIDTH: usize = MARGIN * 3 + SCOPE_WIDTH + INFO_WIDTH;
+pub const HEIGHT: usize = MARGIN * 2 + LINE_HEIGHT + (LINE_HEIGHT + SCOPE_HEIGHT + MARGIN) * 4;
+
+const BACKGROUND: u32 = 0x00202020;
+const CELL_BACKGROUND: u32 = 0x00383838;
+const TEXT: u32 = 0x00e0e0e0;
+const DIM_TEXT: u32 = 0x00808080;
+const CHANNEL_COLORS: [u32; 4] = [0x0030d0ff, 0x0030ff80, 0x00ffb030, 0x00ff5080];
+
+const NOTE_NAMES: [&str; 12] = [
+    "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B",
+];
+
+// Nearest note in scientific pitch notation (A4 = 440Hz)
+fn note_name(frequency: f32) -> String {
+    if !(16. ..20000.).contains(&frequency) {
+        return "-".to_string();
+    }
+    let midi = (69. + 12. * (frequency / 440.).log2()).round() as i32;
+    format!(
+        "{}{}",
+        NOTE_NAMES[midi.rem_euclid(12) as usize],
+        midi.div_euclid(12) - 1
+    )
+}
+
+fn envelope_text(envelope: &Envelope) -> String {
+    if envelope.period == 0 {
+        format!("VOL {} ENV OFF", envelope.volume)
+    } else {
+        format!(
+            "VOL {} ENV {}{}",
+            envelope.volume,
+            if envelope.increase { "+" } else { "-" },
+            envelope.period
+        )
+    }
+}
+
+/// Oscilloscope of a channel, triggered on a rising edge so periodic waves stay in place
+pub fn oscilloscope(scope: &Scope, channel: usize, color: u32) -> Canvas {
+    let mut canvas = Canvas::new(SCOPE_WIDTH, SCOPE_HEIGHT, CELL_BACKGROUND);
+    let samples = scope.samples(channel);
+    let recent = &samples[samples.len() - SCOPE_WINDOW..];
+
+    let min = recent.iter().copied().fold(f32::MAX, f32::min);
+    let max = recent.iter().copied().fold(f32::MIN, f32::max);
+    let middle = (min + max) / 2.;
+    let start = if max > min {
+        (1..=samples.len() - SCOPE_WINDOW)
+            .rev()
+            .find(|i| samples[i - 1] < middle && samples[*i] >= middle)
+            .unwrap_or(samples.len() - SCOPE_WINDOW)
+    } else {
+        samples.len() - SCOPE_WINDOW
+    };
+
+    let half = (SCOPE_HEIGHT / 2) as f32 - 2.;
+    let y = |sample: f32| (SCOPE_HEIGHT / 2) as i32 - (sample * half).round() as i32;
+
+    canvas.fill_rect(0, (SCOPE_HEIGHT / 2) as i32, SCOPE_WIDTH, 1, BACKGROUND);
+
+    // Each column covers several samples, drawn as a line from their minimum to their maximum
+    // joined to the previous column
+    let per_column = SCOPE_WINDOW / SCOPE_WIDTH;
+    let mut last = y(samples[start]);
+    for x in 0..SCOPE_WIDTH {
+        let column = &samples[start + x * per_column..start + (x + 1) * per_column];
+        let top = y(column.iter().copied().fold(f32::MIN, f32::max)).min(last);
+        let bottom = y(column.iter().copied().fold(f32::MAX, f32::min)).max(last);
+        for py in top..=bottom {
+            canvas.set(x as i32, py, color);
+        }
+        last = y(column[per_column - 1]);
+    }
+
+    canvas
+}
+
+// Information lines of a channel shown next to its oscilloscope
+fn channel_info<A: Audio>(channels: &Channels<A>, channel: usize) -> Vec<String> {
+    let mut lines = vec![];
+    match channel {
+        0 | 1 => {
+            let ch = if channel == 0 {
+                &channels.ch1
+            } else {
+                &channels.ch2
+            };
+            let frequency = ch.frequency_hz();
+            lines.push(format!("FREQ {:.1} HZ", frequency));
+            lines.push(format!("NOTE {}", note_name(frequency)));
+            lines.push(envelope_text(&ch.envelope));
+            lines.push(format!("DUTY {}%", [12.5, 25., 50., 75.][ch.duty as usize]));
+            if let Some(sweep) = &ch.sweep {
+                lines.push(format!(
+                    "SWEEP {}{} PERIOD {}",
+                    if sweep.negate { "-" } else { "+" },
+                    sweep.shift,
+                    sweep.period
+                ));
+            }
+        }
+        2 => {
+            let frequency = channels.ch3.frequency_hz();
+            lines.push(format!("FREQ {:.1} HZ", frequency));
+            lines.push(format!("NOTE {}", note_name(frequency)));
+            lines.push(format!(
+                "VOL {}",
+                ["0%", "100%", "50%", "25%"][channels.ch3.volume_code as usize]
+            ));
+            lines.push(format!(
+                "DAC {}",
+                if channels.ch3.dac_on { "ON" } else { "OFF" }
+            ));
+        }
+        _ => {
+            let ch = &channels.ch4;
+            lines.push(format!("RATE {:.0} HZ", ch.frequency_hz()));
+            lines.push(format!("LFSR {} BITS", if ch.narrow { 7 } else { 15 }));
+            lines.push(envelope_text(&ch.envelope));
+        }
+    }
+
+    let left = channels.panning & (0x10 << channel) != 0;
+    let right = channels.panning & (1 << channel) != 0;
+    lines.push(format!(
+        "PAN {} {}",
+        if left { "L" } else { "-" },
+        if right { "R" } else { "-" }
+    ));
+    lines
+}
+
+/// All the channels in a single `WIDTH` x `HEIGHT` image. The oscilloscopes are empty if the
+/// scope of the APU isn't enabled (see `Channels::enable_scope`).
+pub fn render<A: Audio>(channels: &Channels<A>) -> Canvas {
+    let mut canvas = Canvas::new(WIDTH, HEIGHT, BACKGROUND);
+    let m = MARGIN as i32;
+
+    canvas.text(
+        m,
+        m,
+        &format!(
+            "NR50 L{} R{}  NR51 {:02X}  APU {}",
+            (channels.master_volume >> 4) & 0b111,
+            channels.master_volume & 0b111,
+            channels.panning,
+            if channels.power { "ON" } else { "OFF" }
+        ),
+        TEXT,
+    );
+
+    let names = ["CH1 SQUARE", "CH2 SQUARE", "CH3 WAVE", "CH4 NOISE"];
+    let on = [
+        channels.ch1.on,
+        channels.ch2.on,
+        channels.ch3.on,
+        channels.ch4.on,
+    ];
+    for channel in 0..4 {
+        let y = m * 2 + (LINE_HEIGHT + channel * (LINE_HEIGHT + SCOPE_HEIGHT + MARGIN)) as i32;
+        let audible = channels.channel_audible(channel);
+
+        let mut label = format!(
+            "{} {}",
+            names[channel],
+            if on[channel] { "ON" } else { "OFF" }
+        );
+        if channels.controls.solo == Some(channel) {
+            label += " SOLO";
+        } else if !audible {
+            label += " MUTED";
+        }
+        if channels.controls.volumes[channel] != 1. {
+            label += &format!(" {}%", channels.controls.volumes[channel] * 100.);
+        }
+        canvas.text(m, y, &label, if audible { TEXT } else { DIM_TEXT });
+
+        let scope_y = y + LINE_HEIGHT as i32;
+        match channels.scope() {
+            Some(scope) => {
+                let color = if audible {
+                    CHANNEL_COLORS[channel]
+                } else {
+                    DIM_TEXT
+                };
+                canvas.blit(m, scope_y, &oscilloscope(scope, channel, color));
+            }
+            None => canvas.fill_rect(m, scope_y, SCOPE_WIDTH, SCOPE_HEIGHT, CELL_BACKGROUND),
+        }
+
+        for (i, line) in channel_info(channels, channel).iter().enumerate() {
+            canvas.text(
+                m * 2 + SCOPE_WIDTH as i32,
+                scope_y + (i * LINE_HEIGHT) as i32,
+                line,
+                TEXT,
+            );
+        }
+    }
+
+    canvas
+}
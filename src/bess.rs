@@ -0,0 +1,382 @@
+// Best Effort Save State (BESS), the format other emulators (SameBoy, ...) use to exchange states.
+// The memory is dumped at the start of the file, followed by blocks:
+//   name (4 bytes) | length (u32 LE) | data
+// and a footer with the offset of the first block and "BESS". CORE must be the first block, only
+// NAME can come before it. The CORE block has the registers and the offsets of the memory dumps,
+// the MBC block the writes restoring the bank registers, and END ends the blocks.
+//
+// Only the state visible to the game is in the format, so the internal state (timer counters,
+// PPU dot, APU timers) is approximated when importing: the channels that were playing are
+// restarted.
+
+use crate::io::{Audio, Serial};
+use crate::logs::{elog, LogLevel};
+use crate::save_state;
+use crate::state::{reg, GBState};
+
+const FOOTER: [u8; 4] = *b"BESS";
+
+const NAME: [u8; 4] = *b"NAME";
+const INFO: [u8; 4] = *b"INFO";
+const CORE: [u8; 4] = *b"CORE";
+const MBC: [u8; 4] = *b"MBC ";
+const END: [u8; 4] = *b"END ";
+
+const MAJOR_VERSION: u16 = 1;
+const MINOR_VERSION: u16 = 1;
+
+const CORE_SIZE: usize = 0xd0;
+const INFO_SIZE: usize = 0x12;
+
+// DMG without a specific revision
+const MODEL: [u8; 4] = *b"GD  ";
+
+const EXECUTION_RUNNING: u8 = 0;
+const EXECUTION_HALTED: u8 = 1;
+const EXECUTION_STOPPED: u8 = 2;
+
+const WRAM_SIZE: usize = 0x2000;
+const VRAM_SIZE: usize = 0x2000;
+const OAM_SIZE: usize = 0xa0;
+const HRAM_SIZE: usize = 0x7f;
+const EXTERNAL_RAM_SIZES: [usize; 6] = [0, 0x800, 0x2000, 0x8000, 0x20000, 0x10000];
+
+fn block(file: &mut Vec<u8>, name: [u8; 4], data: &[u8]) {
+    file.extend(name);
+    file.extend((data.len() as u32).to_le_bytes());
+    file.extend_from_slice(data);
+}
+
+// Size of the external RAM given by the cartridge header, within the emulated one
+fn external_ram_size(rom: &[u8], external_ram: &[u8]) -> usize {
+    EXTERNAL_RAM_SIZES
+        .get(rom[0x149] as usize)
+        .copied()
+        .unwrap_or(0)
+        .min(external_ram.len())
+}
+
+// Values of the registers as if they were written by the game
+fn io_registers<S: Serial, A: Audio>(state: &GBState<S, A>) -> [u8; 0x80] {
+    let mem = &state.mem;
+    let mut io = *mem.io;
+    io[0x00] = mem.r_io(0x00);
+    io[0x01] = mem.serial.read_data();
+    io[0x02] = mem.serial.read_control();
+    io[0x04] = mem.div;
+    io[0x05] = mem.tima;
+    io[0x06] = mem.tma;
+    io[0x07] = ((mem.timer_enabled as u8) << 2) | mem.timer_speed;
+    // The mode of the PPU, the coincidence flag is computed again by the emulator loading it
+    let display = &mem.display;
+    io[0x41] = 0x80
+        | (mem.io[0x41] & 0x78)
+        | if display.ly >= 0x90 {
+            1
+        } else if display.stat < 80 {
+            2
+        } else if display.stat < 280 {
+            3
+        } else {
+            0
+        };
+    io[0x44] = mem.display.ly;
+    io[0x50] = !mem.boot_rom_on as u8;
+
+    let audio = &mem.audio;
+    for (addr, value) in audio.register_writes() {
+        io[addr as usize] = value;
+    }
+    // The lengths left, which are loaded by the same registers as the duty
+    io[0x11] |= (64 - audio.ch1.length.counter) as u8 & 0x3f;
+    io[0x16] |= (64 - audio.ch2.length.counter) as u8 & 0x3f;
+    io[0x1b] = (256 - audio.ch3.length.counter) as u8;
+    io[0x20] = (64 - audio.ch4.length.counter) as u8 & 0x3f;
+    io[0x26] = audio.r(0x26, mem.cgb_mode);
+    io
+}
+
+/// State in the BESS format
+pub fn export<S: Serial, A: Audio>(state: &GBState<S, A>) -> Vec<u8> {
+    let mem = &state.mem;
+    let display = &mem.display;
+    let mut file = vec![];
+
+    // Memory dumps, with their offsets
+    let wram = file.len();
+    file.extend_from_slice(mem.wram_00.as_ref());
+    file.extend_from_slice(mem.wram_01.as_ref());
+    let vram = file.len();
+    file.extend_from_slice(&display.tiledata()[..0x1800]);
+    file.extend_from_slice(display.tilemaps());
+    let external_ram_size = external_ram_size(mem.rom.as_ref(), mem.external_ram.as_ref());
+    let external_ram = file.len();
+    file.extend_from_slice(&mem.external_ram[..external_ram_size]);
+    let oam = file.len();
+    file.extend_from_slice(display.oam());
+    let hram = file.len();
+    file.extend_from_slice(mem.hram.as_ref());
+
+    let first_block = file.len();
+    block(
+        &mut file,
+        NAME,
+        format!("Astatin Emulator {}", env!("CARGO_PKG_VERSION")).as_bytes(),
+    );
+    let cpu = &state.cpu;
+    let mut core = vec![];
+    core.extend(MAJOR_VERSION.to_le_bytes());
+    core.extend(MINOR_VERSION.to_le_bytes());
+    core.extend(MODEL);
+    core.extend(cpu.pc.to_le_bytes());
+    core.extend(u16::from_be_bytes([cpu.r[reg::A as usize], cpu.r[reg::F as usize]]).to_le_bytes());
+    for r in [reg::BC, reg::DE, reg::HL, reg::SP] {
+        core.extend(cpu.r16(r).to_le_bytes());
+    }
+    core.push(mem.ime as u8);
+    core.push(mem.interrupts_register);
+    core.push(if state.is_stopped {
+        EXECUTION_STOPPED
+    } else if mem.halt {
+        EXECUTION_HALTED
+    } else {
+        EXECUTION_RUNNING
+    });
+    core.push(0);
+    core.extend(io_registers(state));
+    for (size, offset) in [
+        (WRAM_SIZE, wram),
+        (VRAM_SIZE, vram),
+        (external_ram_size, external_ram),
+        (OAM_SIZE, oam),
+        (HRAM_SIZE, hram),
+        // No CGB palettes
+        (0, 0),
+        (0, 0),
+    ] {
+        core.extend((size as u32).to_le_bytes());
+        core.extend((offset as u32).to_le_bytes());
+    }
+    block(&mut file, CORE, &core);
+
+    // Title and global checksum from the cartridge header
+    let mut info = mem.rom[0x134..0x144].to_vec();
+    info.extend_from_slice(&mem.rom[0x14e..0x150]);
+    block(&mut file, INFO, &info);
+
+    // ROM only cartridges have no MBC
+    if mem.rom[0x147] != 0 {
+        let mut writes = vec![];
+        for (addr, value) in [
+            (0x0000u16, if mem.ram_bank_enabled { 0x0a } else { 0x00 }),
+            (0x2000, mem.rom_bank),
+            (0x4000, mem.ram_bank),
+        ] {
+            writes.extend(addr.to_le_bytes());
+            writes.push(value);
+        }
+        block(&mut file, MBC, &writes);
+    }
+
+    block(&mut file, END, &[]);
+
+    file.extend((first_block as u32).to_le_bytes());
+    file.extend(FOOTER);
+    file
+}
+
+/// Whether `data` ends with the BESS footer
+pub fn is_bess(data: &[u8]) -> bool {
+    data.len() >= 8 && data[data.len() - 4..] == FOOTER
+}
+
+// Part of the file pointed to by the CORE block
+fn dump<'a>(data: &'a [u8], core: &[u8], field: usize, name: &str) -> Result<&'a [u8], String> {
+    let size = u32::from_le_bytes(core[field..field + 4].try_into().unwrap()) as usize;
+    let offset = u32::from_le_bytes(core[field + 4..field + 8].try_into().unwrap()) as usize;
+    data.get(offset..offset.saturating_add(size))
+        .ok_or_else(|| format!("The {} of the BESS state is outside of the file", name))
+}
+
+/// Loads a BESS state made by this emulator or another one. Nothing is changed if it can't be
+/// loaded.
+pub fn import<S: Serial, A: Audio>(state: &mut GBState<S, A>, data: &[u8]) -> Result<(), String> {
+    if !is_bess(data) {
+        return Err("Not a BESS state".to_string());
+    }
+    let footer = data.len() - 8;
+    let mut offset = u32::from_le_bytes(data[footer..footer + 4].try_into().unwrap()) as usize;
+
+    let mut core = None;
+    let mut mbc_writes = None;
+    loop {
+        let Some(header) = data
+            .get(offset..offset + 8)
+            .filter(|_| offset + 8 <= footer)
+        else {
+            return Err("The BESS state has no END block".to_string());
+        };
+        let name: [u8; 4] = header[..4].try_into().unwrap();
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let Some(content) = data.get(offset + 8..(offset + 8).saturating_add(length)) else {
+            return Err("The BESS state has a truncated block".to_string());
+        };
+        offset += 8 + length;
+
+        match name {
+            CORE => {
+                if length < CORE_SIZE {
+                    return Err("The CORE block of the BESS state is too short".to_string());
+                }
+                core = Some(content);
+            }
+            MBC => {
+                if !length.is_multiple_of(3) {
+                    return Err("The MBC block of the BESS state has a bad length".to_string());
+                }
+                mbc_writes = Some(content);
+            }
+            // Title and global checksum of the ROM
+            INFO if length == INFO_SIZE && content[0x10..] != state.mem.rom[0x14e..0x150] => {
+                return Err(format!(
+                    "The BESS state was made with another ROM ({})",
+                    String::from_utf8_lossy(&content[..0x10]).trim_end_matches('\0')
+                ));
+            }
+            END => break,
+            // Blocks of other emulators or versions and the ones of the CGB/SGB
+            _ => {}
+        }
+    }
+
+    let Some(core) = core else {
+        return Err("The BESS state has no CORE block".to_string());
+    };
+    let major = u16::from_le_bytes([core[0], core[1]]);
+    if major != MAJOR_VERSION {
+        return Err(format!("Unsupported BESS version {}", major));
+    }
+    match core[4] {
+        b'G' | b'S' => {}
+        b'C' => return Err("CGB states are not supported".to_string()),
+        _ => {
+            return Err(format!(
+                "Unknown model \"{}\" in the BESS state",
+                String::from_utf8_lossy(&core[4..8])
+            ))
+        }
+    }
+    let wram = dump(data, core, 0x98, "RAM")?;
+    let vram = dump(data, core, 0xa0, "VRAM")?;
+    let external_ram = dump(data, core, 0xa8, "MBC RAM")?;
+    let oam = dump(data, core, 0xb0, "OAM")?;
+    let hram = dump(data, core, 0xb8, "HRAM")?;
+    if mbc_writes.is_none() && state.mem.rom[0x147] != 0 {
+        elog(
+            LogLevel::Error,
+            "The BESS state has no MBC block, the banks are reset",
+        );
+    }
+
+    let mem = &mut state.mem;
+    let size = wram.len().min(0x1000);
+    mem.wram_00[..size].copy_from_slice(&wram[..size]);
+    let size = wram.len().clamp(0x1000, WRAM_SIZE) - 0x1000;
+    mem.wram_01[..size].copy_from_slice(&wram[0x1000..0x1000 + size]);
+    mem.display.vram_bank = 0;
+    for (i, byte) in vram.iter().take(VRAM_SIZE).enumerate() {
+        mem.display.w(i as u16, *byte);
+    }
+    for (i, byte) in oam.iter().take(OAM_SIZE).enumerate() {
+        mem.display.w(0x7e00 + i as u16, *byte);
+    }
+    let size = external_ram.len().min(mem.external_ram.len());
+    mem.external_ram[..size].copy_from_slice(&external_ram[..size]);
+    let size = hram.len().min(HRAM_SIZE);
+    mem.hram[..size].copy_from_slice(&hram[..size]);
+
+    // The bank registers, the writes to the RAM are ignored
+    mem.rom_bank = 1;
+    mem.ram_bank = 0;
+    mem.ram_bank_enabled = false;
+    for write in mbc_writes.unwrap_or_default().chunks(3) {
+        let addr = u16::from_le_bytes([write[0], write[1]]);
+        if addr < 0x8000 {
+            mem.w(addr, write[2]);
+        }
+    }
+
+    let io: [u8; 0x80] = core[0x18..0x98].try_into().unwrap();
+    for addr in 0..0x80u8 {
+        let value = io[addr as usize];
+        match addr {
+            // Without resetting the counter
+            0x04 => mem.div = value,
+            // Without starting a transfer, set below
+            0x01 | 0x02 => {}
+            // Without the OAM DMA
+            0x46 => {}
+            // Read only
+            0x44 => mem.display.ly = value % 154,
+            0x50 => mem.boot_rom_on = value & 1 == 0,
+            // Without writing the palettes, they have their own dumps
+            0x69 | 0x6b => {}
+            0x10..=0x3f => continue,
+            _ => {
+                mem.w_io(addr, value);
+                continue;
+            }
+        }
+        mem.io[addr as usize] = value;
+    }
+    save_state::restore_serial(mem, io[0x01], io[0x02]);
+
+    // The APU is reset and set up without triggering the channels, then the ones that were
+    // playing are triggered
+    mem.audio.w(0x26, 0x00, mem.cgb_mode);
+    mem.audio.w(0x26, io[0x26], mem.cgb_mode);
+    mem.audio.w(0x1a, 0x00, mem.cgb_mode);
+    for addr in (0x30..0x40).chain(0x10..0x26) {
+        let value = match addr {
+            0x14 | 0x19 | 0x1e | 0x23 => io[addr as usize] & 0x7f,
+            _ => io[addr as usize],
+        };
+        mem.audio.w(addr, value, mem.cgb_mode);
+        mem.io[addr as usize] = value;
+    }
+    for (channel, nrx4) in [0x14, 0x19, 0x1e, 0x23].into_iter().enumerate() {
+        if io[0x26] & (1 << channel) != 0 {
+            mem.audio.w(nrx4, io[nrx4 as usize] | 0x80, mem.cgb_mode);
+        }
+    }
+    mem.io[0x26] = io[0x26];
+
+    // Start of the PPU mode given by STAT
+    let display = &mut mem.display;
+    display.stat = match io[0x41] & 0b11 {
+        0 => 280,
+        3 => 80,
+        _ => 0,
+    };
+    display.window_internal_line_counter = 0;
+    display.redraw_request = Some(Box::new(*display.framebuffer()));
+
+    let cpu = &mut state.cpu;
+    let r16 = |field: usize| u16::from_le_bytes([core[field], core[field + 1]]);
+    cpu.pc = r16(0x08);
+    let [a, f] = r16(0x0a).to_be_bytes();
+    cpu.r[reg::A as usize] = a;
+    cpu.r[reg::F as usize] = f & 0xf0;
+    cpu.w16(reg::BC, r16(0x0c));
+    cpu.w16(reg::DE, r16(0x0e));
+    cpu.w16(reg::HL, r16(0x10));
+    cpu.w16(reg::SP, r16(0x12));
+    mem.ime = core[0x14] != 0;
+    mem.interrupts_register = core[0x15];
+    // STOP ends the emulation here, a stopped CPU waits for an interrupt like a halted one
+    mem.halt = matches!(core[0x16], EXECUTION_HALTED | EXECUTION_STOPPED);
+    state.is_stopped = false;
+    state.div_cycles = 0;
+    state.tima_cycles = 0;
+    Ok(())
+}
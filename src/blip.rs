@@ -0,0 +1,131 @@
+// Band-limited synthesis in the style of blip_buf. The signal is described by its changes of
+// level, each one being added as a band-limited step at its exact position between two output
+// samples. This resamples from the emulated clock to any output rate without the aliasing of
+// sampling the signal directly.
+
+use std::f64::consts::PI;
+
+// Number of positions between two output samples a step can be placed at
+const PHASES: usize = 64;
+
+// Number of output samples affected by one step
+const KERNEL_WIDTH: usize = 16;
+
+// Cutoff frequency of the low-pass filter, relative to the Nyquist frequency of the output
+const CUTOFF: f64 = 0.9;
+
+// Windowed sinc impulses for each phase, each of them summing to 1
+fn kernel() -> Box<[[f32; KERNEL_WIDTH]; PHASES]> {
+    let mut kernel = Box::new([[0.; KERNEL_WIDTH]; PHASES]);
+    let half_width = KERNEL_WIDTH as f64 / 2.;
+
+    for (phase, taps) in kernel.iter_mut().enumerate() {
+        let mut impulse = [0.; KERNEL_WIDTH];
+        for (k, tap) in impulse.iter_mut().enumerate() {
+            let x = k as f64 - half_width + 1. - phase as f64 / PHASES as f64;
+            let sinc = if x == 0. {
+                1.
+            } else {
+                (PI * x * CUTOFF).sin() / (PI * x * CUTOFF)
+            };
+            // Blackman window
+            let window =
+                0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2. * PI * x / half_width).cos();
+            *tap = sinc * window;
+        }
+
+        let sum: f64 = impulse.iter().sum();
+        for (tap, value) in taps.iter_mut().zip(impulse) {
+            *tap = (value / sum) as f32;
+        }
+    }
+
+    kernel
+}
+
+pub struct BlipBuffer {
+    clock_rate: u64,
+    sample_rate: u64,
+    // Position of the start of the current frame after the last complete sample, in output
+    // samples multiplied by `clock_rate`
+    remainder: u64,
+    // Differences between consecutive output samples. The first `available` ones are complete.
+    deltas: Vec<f32>,
+    available: usize,
+    integrator: f32,
+    kernel: Box<[[f32; KERNEL_WIDTH]; PHASES]>,
+}
+
+impl BlipBuffer {
+    /// Buffer converting from `clock_rate` clocks per second to `sample_rate` samples per second
+    pub fn new(clock_rate: u64, sample_rate: u32) -> Self {
+        Self {
+            clock_rate,
+            sample_rate: sample_rate as u64,
+            remainder: 0,
+            deltas: vec![0.; KERNEL_WIDTH],
+            available: 0,
+            integrator: 0.,
+            kernel: kernel(),
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    /// Changes the output rate from the start of the next frame
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate as u64;
+    }
+
+    /// Adds a change of level of `delta` happening `clock` clocks after the start of the
+    /// current frame
+    pub fn add_delta(&mut self, clock: u32, delta: f32) {
+        let position = self.remainder + clock as u64 * self.sample_rate;
+        let index = self.available + (position / self.clock_rate) as usize;
+        let phase = ((position % self.clock_rate) * PHASES as u64 / self.clock_rate) as usize;
+
+        if self.deltas.len() < index + KERNEL_WIDTH {
+            self.deltas.resize(index + KERNEL_WIDTH, 0.);
+        }
+        for (d, tap) in self.deltas[index..index + KERNEL_WIDTH]
+            .iter_mut()
+            .zip(self.kernel[phase])
+        {
+            *d += delta * tap;
+        }
+    }
+
+    /// Ends the current frame after `clocks` clocks, making the samples before it available
+    pub fn end_frame(&mut self, clocks: u32) {
+        let position = self.remainder + clocks as u64 * self.sample_rate;
+        self.available += (position / self.clock_rate) as usize;
+        self.remainder = position % self.clock_rate;
+
+        if self.deltas.len() < self.available + KERNEL_WIDTH {
+            self.deltas.resize(self.available + KERNEL_WIDTH, 0.);
+        }
+    }
+
+    pub fn samples_available(&self) -> usize {
+        self.available
+    }
+
+    /// Removes up to `count` samples from the buffer
+    pub fn read_samples(&mut self, count: usize) -> Vec<f32> {
+        let count = count.min(self.available);
+        let samples = self
+            .deltas
+            .drain(..count)
+            .map(|delta| {
+                self.integrator += delta;
+                self.integrator
+            })
+            .collect();
+
+        self.available -= count;
+        self.deltas.resize(self.deltas.len() + count, 0.);
+        samples
+    }
+}
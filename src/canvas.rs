@@ -0,0 +1,145 @@
+// Small RGB drawing surface with a 3x5 bitmap font, used by the debug and info views.
+
+pub const GLYPH_WIDTH: usize = 4;
+pub const GLYPH_HEIGHT: usize = 6;
+
+// Each glyph is 5 rows of 3 pixels, the most significant bit being the left pixel
+const GLYPHS: [(char, [u8; 5]); 51] = [
+    ('0', [0b111, 0b101, 0b101, 0b101, 0b111]),
+    ('1', [0b010, 0b110, 0b010, 0b010, 0b111]),
+    ('2', [0b111, 0b001, 0b111, 0b100, 0b111]),
+    ('3', [0b111, 0b001, 0b111, 0b001, 0b111]),
+    ('4', [0b101, 0b101, 0b111, 0b001, 0b001]),
+    ('5', [0b111, 0b100, 0b111, 0b001, 0b111]),
+    ('6', [0b111, 0b100, 0b111, 0b101, 0b111]),
+    ('7', [0b111, 0b001, 0b001, 0b001, 0b001]),
+    ('8', [0b111, 0b101, 0b111, 0b101, 0b111]),
+    ('9', [0b111, 0b101, 0b111, 0b001, 0b111]),
+    ('A', [0b010, 0b101, 0b111, 0b101, 0b101]),
+    ('B', [0b110, 0b101, 0b110, 0b101, 0b110]),
+    ('C', [0b011, 0b100, 0b100, 0b100, 0b011]),
+    ('D', [0b110, 0b101, 0b101, 0b101, 0b110]),
+    ('E', [0b111, 0b100, 0b110, 0b100, 0b111]),
+    ('F', [0b111, 0b100, 0b110, 0b100, 0b100]),
+    ('G', [0b011, 0b100, 0b101, 0b101, 0b011]),
+    ('H', [0b101, 0b101, 0b111, 0b101, 0b101]),
+    ('I', [0b111, 0b010, 0b010, 0b010, 0b111]),
+    ('J', [0b001, 0b001, 0b001, 0b101, 0b010]),
+    ('K', [0b101, 0b101, 0b110, 0b101, 0b101]),
+    ('L', [0b100, 0b100, 0b100, 0b100, 0b111]),
+    ('M', [0b101, 0b111, 0b111, 0b101, 0b101]),
+    ('N', [0b110, 0b101, 0b101, 0b101, 0b101]),
+    ('O', [0b010, 0b101, 0b101, 0b101, 0b010]),
+    ('P', [0b110, 0b101, 0b110, 0b100, 0b100]),
+    ('Q', [0b010, 0b101, 0b101, 0b110, 0b011]),
+    ('R', [0b110, 0b101, 0b110, 0b101, 0b101]),
+    ('S', [0b011, 0b100, 0b010, 0b001, 0b110]),
+    ('T', [0b111, 0b010, 0b010, 0b010, 0b010]),
+    ('U', [0b101, 0b101, 0b101, 0b101, 0b111]),
+    ('V', [0b101, 0b101, 0b101, 0b101, 0b010]),
+    ('W', [0b101, 0b101, 0b111, 0b111, 0b101]),
+    ('X', [0b101, 0b101, 0b010, 0b101, 0b101]),
+    ('Y', [0b101, 0b101, 0b010, 0b010, 0b010]),
+    ('Z', [0b111, 0b001, 0b010, 0b100, 0b111]),
+    (':', [0b000, 0b010, 0b000, 0b010, 0b000]),
+    ('#', [0b101, 0b111, 0b101, 0b111, 0b101]),
+    ('-', [0b000, 0b000, 0b111, 0b000, 0b000]),
+    ('.', [0b000, 0b000, 0b000, 0b000, 0b010]),
+    (',', [0b000, 0b000, 0b000, 0b010, 0b100]),
+    ('/', [0b001, 0b001, 0b010, 0b100, 0b100]),
+    ('(', [0b010, 0b100, 0b100, 0b100, 0b010]),
+    (')', [0b010, 0b001, 0b001, 0b001, 0b010]),
+    ('%', [0b101, 0b001, 0b010, 0b100, 0b101]),
+    ('+', [0b000, 0b010, 0b111, 0b010, 0b000]),
+    ('<', [0b001, 0b010, 0b100, 0b010, 0b001]),
+    ('>', [0b100, 0b010, 0b001, 0b010, 0b100]),
+    ('=', [0b000, 0b111, 0b000, 0b111, 0b000]),
+    ('!', [0b010, 0b010, 0b010, 0b000, 0b010]),
+    ('?', [0b111, 0b001, 0b010, 0b000, 0b010]),
+];
+
+fn glyph(c: char) -> Option<[u8; 5]> {
+    if c == ' ' {
+        return Some([0; 5]);
+    }
+
+    let c = c.to_ascii_uppercase();
+    GLYPHS
+        .iter()
+        .find(|(g, _)| *g == c)
+        .or_else(|| GLYPHS.iter().find(|(g, _)| *g == '?'))
+        .map(|(_, rows)| *rows)
+}
+
+/// 0x00RRGGBB pixel buffer
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+impl Canvas {
+    pub fn new(width: usize, height: usize, color: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![color; width * height],
+        }
+    }
+
+    /// Pixels outside of the canvas are ignored
+    pub fn set(&mut self, x: i32, y: i32, color: u32) {
+        if x >= 0 && y >= 0 && (x as usize) < self.width && (y as usize) < self.height {
+            self.pixels[y as usize * self.width + x as usize] = color;
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: i32, y: i32, w: usize, h: usize, color: u32) {
+        for dy in 0..h as i32 {
+            for dx in 0..w as i32 {
+                self.set(x + dx, y + dy, color);
+            }
+        }
+    }
+
+    pub fn rect(&mut self, x: i32, y: i32, w: usize, h: usize, color: u32) {
+        let (w, h) = (w as i32, h as i32);
+        for dx in 0..w {
+            self.set(x + dx, y, color);
+            self.set(x + dx, y + h - 1, color);
+        }
+        for dy in 0..h {
+            self.set(x, y + dy, color);
+            self.set(x + w - 1, y + dy, color);
+        }
+    }
+
+    /// Draws text with the 3x5 font. Unknown characters are drawn as '?'.
+    pub fn text(&mut self, x: i32, y: i32, text: &str, color: u32) {
+        for (i, c) in text.chars().enumerate() {
+            if let Some(rows) = glyph(c) {
+                let gx = x + (i * GLYPH_WIDTH) as i32;
+                for (dy, row) in rows.iter().enumerate() {
+                    for dx in 0..3 {
+                        if row & (0b100 >> dx) != 0 {
+                            self.set(gx + dx, y + dy as i32, color);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn blit(&mut self, x: i32, y: i32, other: &Canvas) {
+        for oy in 0..other.height {
+            for ox in 0..other.width {
+                self.set(
+                    x + ox as i32,
+                    y + oy as i32,
+                    other.pixels[oy * other.width + ox],
+                );
+            }
+        }
+    }
+}
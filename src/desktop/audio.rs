@@ -4,13 +4,10 @@ use cpal::{self, BufferSize};
 use rodio::stream::{OutputStream, OutputStreamBuilder};
 use rodio::{Sink, Source};
 
-use crate::audio::{MutableWave, SAMPLE_RATE};
-use crate::io::{Audio, Wave};
-use crate::logs::{elog, log, LogLevel};
-use std::mem;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::mpsc::{channel, Sender};
-use std::sync::Arc;
+use crate::audio::DEFAULT_SAMPLE_RATE;
+use crate::desktop::ring::{ring_buffer, Consumer, Producer};
+use crate::io::Audio;
+use crate::logs::{log, LogLevel};
 
 #[cfg(target_family = "wasm")]
 use crate::wasm::utils::SystemTime;
@@ -23,26 +20,74 @@ const CPAL_BUFFERSIZE: u32 = 4096;
 const RODIO_BUFFER_SIZE: usize = 2048;
 const RODIO_BUFFER_SINK_LATE_EXPECTED: f32 = 2.;
 const LATE_SPEEDUP_INTENSITY_INV: f32 = 2048.0;
-const SPEEDUP_SKIP_LIMIT: f32 = 1.008;
+const MAX_LATE_SPEEDUP: f32 = 1.008;
+// Maximum latency of the samples waiting in the ring buffer. The samples generated when it is
+// full are dropped.
+const RING_BUFFER_MS: usize = 200;
 
-const TIME_RING_BUFFER_SIZE: usize = (SAMPLE_RATE as usize / BUFFER_SIZE) * 10;
+fn ring_buffer_capacity(sample_rate: u32) -> usize {
+    sample_rate as usize * 2 * RING_BUFFER_MS / 1000
+}
+
+// Logs the fill level of the ring buffer and its underruns and overruns about once per second
+struct RingReport {
+    samples: usize,
+    last_stats: (usize, usize),
+}
+
+impl RingReport {
+    fn new() -> Self {
+        Self {
+            samples: 0,
+            last_stats: (0, 0),
+        }
+    }
+
+    fn pushed(&mut self, producer: &Producer, sample_rate: u32, count: usize) {
+        self.samples += count;
+        if self.samples < sample_rate as usize * 2 {
+            return;
+        }
+        self.samples = 0;
+
+        let (underruns, overruns) = producer.stats();
+        log(
+            LogLevel::AudioLatency,
+            format!(
+                "audio buffer: {:.1}ms ({}/{} samples), {} samples of underrun, {} samples of overrun",
+                1000. * producer.len() as f32 / (2 * sample_rate) as f32,
+                producer.len(),
+                producer.capacity(),
+                underruns - self.last_stats.0,
+                overruns - self.last_stats.1,
+            ),
+        );
+        self.last_stats = (underruns, overruns);
+    }
+}
+
+// Number of seconds of buffers timed by the SpeedFinder
+const TIME_RING_BUFFER_SECONDS: usize = 10;
 struct SpeedFinder {
-    buf: [SystemTime; TIME_RING_BUFFER_SIZE],
+    buf: Vec<SystemTime>,
     i: usize,
     has_circled: bool,
 }
 
 impl SpeedFinder {
-    fn new() -> Self {
+    fn new(sample_rate: u32) -> Self {
         Self {
-            buf: [SystemTime::now(); TIME_RING_BUFFER_SIZE],
+            buf: vec![
+                SystemTime::now();
+                (sample_rate as usize / BUFFER_SIZE) * TIME_RING_BUFFER_SECONDS
+            ],
             i: 0,
             has_circled: false,
         }
     }
 
     fn tick(&mut self) -> Option<f32> {
-        if self.i >= TIME_RING_BUFFER_SIZE {
+        if self.i >= self.buf.len() {
             self.i = 0;
             self.has_circled = true;
         }
@@ -62,7 +107,7 @@ impl SpeedFinder {
             }
         } else {
             return Some(
-                now.duration_since(previous).unwrap().as_secs_f32() / TIME_RING_BUFFER_SIZE as f32,
+                now.duration_since(previous).unwrap().as_secs_f32() / self.buf.len() as f32,
             );
         }
     }
@@ -71,18 +116,22 @@ impl SpeedFinder {
 pub struct HeadlessAudio {}
 
 impl Audio for HeadlessAudio {
-    fn attach_wave(&mut self, _wave: MutableWave) {}
-    fn next(&mut self) {}
+    fn sample_rate(&self) -> u32 {
+        DEFAULT_SAMPLE_RATE
+    }
+
+    fn push_samples(&mut self, _samples: &[f32]) {}
+
+    fn latency(&self) -> Option<f32> {
+        None
+    }
 }
 
 pub struct CpalAudio {
     _stream: Stream,
-    wave_sender: Sender<f32>,
-
-    left: bool,
-    wave: Option<MutableWave>,
-
-    samples_to_play: Arc<AtomicUsize>,
+    producer: Producer,
+    sample_rate: u32,
+    report: RingReport,
 }
 
 impl CpalAudio {
@@ -91,124 +140,94 @@ impl CpalAudio {
         let device = host
             .default_output_device()
             .expect("no output device available");
-        let mut supported_configs_range = device
-            .supported_output_configs()
+        // Using the rate of the device so it doesn't have to resample
+        let supported_config = device
+            .default_output_config()
             .expect("error while querying configs");
-        let supported_config = supported_configs_range
-            .next()
-            .expect("No supported configs")
-            .with_sample_rate(cpal::SampleRate(SAMPLE_RATE));
+        let sample_rate = supported_config.sample_rate().0;
 
         let mut config = supported_config.config();
 
         config.buffer_size = BufferSize::Fixed(CPAL_BUFFERSIZE);
         config.channels = 2;
 
-        let (sender, receiver) = channel::<f32>();
-
-        let samples_to_play = Arc::new(AtomicUsize::new(0));
-        let samples_to_play_clone = samples_to_play.clone();
-
-        let stream = device.build_output_stream(
-            &config,
-            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
-                let mut sample_received = data.len();
-                for i in 0..data.len() {
-                    if let Ok(value) = receiver.try_recv() {
-                        data[i] = value;
-                    } else {
-                        sample_received = i;
-                        break;
-                    }
-                }
-                samples_to_play_clone.fetch_sub(sample_received, Ordering::SeqCst);
-                let samples_to_play = samples_to_play_clone.load(Ordering::SeqCst);
-                let latency_s = samples_to_play as f64 / (SAMPLE_RATE as f64);
-                let latency_in_buffers = samples_to_play as f64 / (data.len() as f64);
-                if latency_in_buffers > 3. && latency_s > 0.1 {
-                    let mut skipping_count = samples_to_play - 3*data.len();
-                    log(LogLevel::Infos, format!("Audio Latency higher than 100ms, skipping {} samples", skipping_count));
-                    for i in 0..skipping_count {
-                        if let Err(_) = receiver.try_recv() {
-                            elog(LogLevel::Error, format!("Samples were fewer than expected when skipping samples (expected {}, got {}", samples_to_play, i));
-                            skipping_count = i;
-                            break;
-                        }
-                    }
-
-                    samples_to_play_clone.fetch_sub(skipping_count, Ordering::SeqCst);
-                }
-            },
-            |err| {
-                log(LogLevel::Error, format!("Cpal Stream error: {:?}", err))
-            },
-            None
-        ).unwrap();
+        let (producer, consumer) = ring_buffer(ring_buffer_capacity(sample_rate));
+
+        let stream = device
+            .build_output_stream(
+                &config,
+                move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+                    consumer.pop(data);
+                },
+                |err| log(LogLevel::Error, format!("Cpal Stream error: {:?}", err)),
+                None,
+            )
+            .unwrap();
 
         stream.play().unwrap();
 
         Self {
             _stream: stream,
-            wave_sender: sender,
-
-            wave: None,
-            left: false,
-            samples_to_play,
+            producer,
+            sample_rate,
+            report: RingReport::new(),
         }
     }
 }
 
 impl Audio for CpalAudio {
-    fn attach_wave(&mut self, wave: MutableWave) {
-        self.wave = Some(wave);
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
     }
 
-    fn next(&mut self) {
-        if let Some(wave) = &mut self.wave {
-            if let Some(v) = wave.next(self.left) {
-                let _ = self.wave_sender.send(v);
-                self.samples_to_play.fetch_add(1, Ordering::SeqCst);
-            }
-            self.left = !self.left;
-        }
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.producer.push(samples);
+        self.report
+            .pushed(&self.producer, self.sample_rate, samples.len());
+    }
+
+    fn latency(&self) -> Option<f32> {
+        Some(self.producer.len() as f32 / (2 * self.sample_rate) as f32)
     }
 }
 
 pub struct RodioAudio {
     sink: Sink,
     _stream: OutputStream,
+    producer: Producer,
+    report: RingReport,
 
-    speed_finder: SpeedFinder,
-    wave: Option<RodioWave<MutableWave>>,
-    buffer: Box<[f32; BUFFER_SIZE]>,
+    // None when the emulator is paced by the audio, the sink then always plays at normal speed
+    speed_finder: Option<SpeedFinder>,
+    sample_rate: u32,
+    // Samples pushed since the last tick of the SpeedFinder
     buffer_i: usize,
 }
 
-struct RodioWave<W: Wave + Send + 'static>(W, usize);
-
-impl<W: Wave + Send + 'static> Iterator for RodioWave<W> {
-    type Item = f32;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        self.1 += 1;
-        let left = self.1 % 2 == 0;
-        let result = self.0.next(left);
-
-        result
-    }
+// Infinite source playing the samples of the ring buffer
+struct RingSource {
+    consumer: Consumer,
+    sample_rate: u32,
+    // Stereo frame being given, popped whole so the channels stay in place after an underrun
+    frame: [f32; 2],
+    // Index of the next sample of `frame`
+    frame_i: usize,
 }
 
-struct RodioBuffer<I: Iterator<Item = f32>>(I);
-
-impl<I: Iterator<Item = f32>> Iterator for RodioBuffer<I> {
+impl Iterator for RingSource {
     type Item = f32;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.0.next()
+        if self.frame_i == self.frame.len() {
+            self.consumer.pop(&mut self.frame);
+            self.frame_i = 0;
+        }
+        self.frame_i += 1;
+        Some(self.frame[self.frame_i - 1])
     }
 }
 
-impl<I: Iterator<Item = f32>> Source for RodioBuffer<I> {
+impl Source for RingSource {
     fn current_span_len(&self) -> Option<usize> {
         None
     }
@@ -218,7 +237,7 @@ impl<I: Iterator<Item = f32>> Source for RodioBuffer<I> {
     }
 
     fn sample_rate(&self) -> u32 {
-        SAMPLE_RATE
+        self.sample_rate
     }
 
     fn total_duration(&self) -> Option<Duration> {
@@ -227,7 +246,9 @@ impl<I: Iterator<Item = f32>> Source for RodioBuffer<I> {
 }
 
 impl RodioAudio {
-    pub fn new() -> Self {
+    /// With `audio_sync`, the speed of the sink isn't adjusted to the speed of the emulator
+    /// since it is the emulator that follows the audio (see `Gameboy::set_audio_sync`)
+    pub fn new(audio_sync: bool) -> Self {
         let stream = OutputStreamBuilder::from_default_device()
             .unwrap()
             .with_buffer_size(BufferSize::Fixed(RODIO_BUFFER_SIZE as u32))
@@ -235,12 +256,26 @@ impl RodioAudio {
             .unwrap();
 
         let sink = Sink::connect_new(stream.mixer());
+        let sample_rate = stream.config().sample_rate();
+
+        let (producer, consumer) = ring_buffer(ring_buffer_capacity(sample_rate));
+        sink.append(RingSource {
+            consumer,
+            sample_rate,
+            frame: [0.; 2],
+            frame_i: 2,
+        });
 
         RodioAudio {
-            speed_finder: SpeedFinder::new(),
-            sink: sink,
-            wave: None,
-            buffer: Box::new([0.0; BUFFER_SIZE]),
+            speed_finder: if audio_sync {
+                None
+            } else {
+                Some(SpeedFinder::new(sample_rate))
+            },
+            sample_rate,
+            sink,
+            producer,
+            report: RingReport::new(),
             buffer_i: 0,
             _stream: stream,
         }
@@ -248,63 +283,39 @@ impl RodioAudio {
 }
 
 impl Audio for RodioAudio {
-    fn attach_wave(&mut self, wave: MutableWave) {
-        let wave = RodioWave(wave, 0);
-
-        self.wave = Some(wave);
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
     }
 
-    fn next(&mut self) {
-        if let Some(wave) = &mut self.wave {
-            if let Some(v) = wave.next() {
-                self.buffer[self.buffer_i] = v;
-                self.buffer_i += 1;
-
-                if self.buffer_i == BUFFER_SIZE {
-                    self.buffer_i = 0;
-                    let mut buffer = Box::new([0.0; BUFFER_SIZE]);
-                    mem::swap(&mut self.buffer, &mut buffer);
-                    if let Some(speed) = self.speed_finder.tick() {
-                        let mut late_speedup: f32;
-                        let rodio_buffers_sink_late =
-                            self.sink.len() as f32 / (RODIO_BUFFER_SIZE / BUFFER_SIZE) as f32;
-                        late_speedup =
-                            ((rodio_buffers_sink_late - RODIO_BUFFER_SINK_LATE_EXPECTED).powi(3)
-                                / LATE_SPEEDUP_INTENSITY_INV)
-                                + 1.;
-
-                        if late_speedup > SPEEDUP_SKIP_LIMIT {
-                            while late_speedup > 1.0 {
-                                let rodio_buffers_sink_late = self.sink.len() as f32
-                                    / (RODIO_BUFFER_SIZE / BUFFER_SIZE) as f32;
-                                late_speedup = ((rodio_buffers_sink_late
-                                    - RODIO_BUFFER_SINK_LATE_EXPECTED)
-                                    .powi(3)
-                                    / LATE_SPEEDUP_INTENSITY_INV)
-                                    + 1.;
-
-                                self.sink.skip_one();
-                            }
-                            late_speedup = 1.;
-                        }
-                        let average_speed =
-                            (1. / speed) / (2 * SAMPLE_RATE / BUFFER_SIZE as u32) as f32;
-                        let rodio_buffers_sink_late =
-                            self.sink.len() as f32 / (RODIO_BUFFER_SIZE / BUFFER_SIZE) as f32;
-                        log(
-                            LogLevel::AudioLatency,
-                            format!(
-                                "audio sink latency: {}ms",
-                                (1000. * rodio_buffers_sink_late
-                                    / ((2 * SAMPLE_RATE) as f32 / RODIO_BUFFER_SIZE as f32))
-                            ),
-                        );
-
-                        self.sink.set_speed(late_speedup * average_speed);
-                    }
-                    self.sink.append(RodioBuffer(buffer.into_iter()));
-                }
+    fn push_samples(&mut self, samples: &[f32]) {
+        self.producer.push(samples);
+        self.report
+            .pushed(&self.producer, self.sample_rate, samples.len());
+
+        let Some(speed_finder) = &mut self.speed_finder else {
+            return;
+        };
+
+        self.buffer_i += samples.len();
+        while self.buffer_i >= BUFFER_SIZE {
+            self.buffer_i -= BUFFER_SIZE;
+            if let Some(speed) = speed_finder.tick() {
+                let rodio_buffers_sink_late = self.producer.len() as f32 / RODIO_BUFFER_SIZE as f32;
+                let late_speedup = (((rodio_buffers_sink_late - RODIO_BUFFER_SINK_LATE_EXPECTED)
+                    .powi(3)
+                    / LATE_SPEEDUP_INTENSITY_INV)
+                    + 1.)
+                    .min(MAX_LATE_SPEEDUP);
+
+                let average_speed =
+                    (1. / speed) / (2 * self.sample_rate / BUFFER_SIZE as u32) as f32;
+
+                self.sink.set_speed(late_speedup * average_speed);
             }
         }
     }
+
+    fn latency(&self) -> Option<f32> {
+        Some(self.producer.len() as f32 / (2 * self.sample_rate) as f32)
+    }
 }
@@ -0,0 +1,139 @@
+// Transports for the infrared port of the CGB. The games measure the length of the light pulses
+// by polling RP, so both emulators have to be kept at the same cycle count while reading is
+// enabled.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::net::TcpListener;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::desktop::lockstep::Lockstep;
+use crate::io::Infrared;
+
+const PROTOCOL: u8 = 2;
+
+// Delay before the light of the other emulator is seen, how far ahead of it an emulator can run.
+// Around 60µs, shorter than the pulses of the games.
+const LATENCY_CYCLES: u128 = 256;
+
+// Interval between the times sent to the other side while reading is disabled (about 4ms). The
+// other side only waits for them while it is reading.
+const IDLE_SYNC_CYCLES: u128 = 16384;
+
+// LED turned on (byte 1) or off (byte 0)
+const LED: u8 = 0x10;
+
+// Two ports facing each other in the same process, the Game Boys must be run at the same cycle
+// count (see `Gameboy::run_linked_until_next_sleep`)
+pub struct LinkedInfrared {
+    leds: Rc<RefCell<[bool; 2]>>,
+    side: usize,
+}
+
+impl LinkedInfrared {
+    pub fn pair() -> (Self, Self) {
+        let leds = Rc::new(RefCell::new([false; 2]));
+        (
+            Self {
+                leds: leds.clone(),
+                side: 0,
+            },
+            Self { leds, side: 1 },
+        )
+    }
+}
+
+impl Infrared for LinkedInfrared {
+    fn write_led(&mut self, on: bool) {
+        self.leds.borrow_mut()[self.side] = on;
+    }
+
+    fn read_light(&self) -> bool {
+        self.leds.borrow()[1 - self.side]
+    }
+
+    fn update_infrared(&mut self, _cycles: u128, _reading: bool) {}
+}
+
+// Port facing another emulator through a socket, kept in lockstep (see `Lockstep`) while reading
+// is enabled in RP. The changes of the LED are sent with their cycle and seen by the other side
+// `LATENCY_CYCLES` later. The light doesn't matter while reading is disabled, so the emulator
+// runs freely and the changes received late are applied at once.
+pub struct SocketInfrared {
+    connection: Lockstep,
+    led: bool,
+    // State of the LED known by the other side
+    sent_led: bool,
+    light: bool,
+    // Changes of the light of the other side to come: cycle (ours) and state
+    changes: VecDeque<(u128, bool)>,
+}
+
+impl SocketInfrared {
+    /// Waits for the other emulator to connect on `listener`
+    pub fn listen(listener: &TcpListener, timeout: Duration) -> Self {
+        Self::new(Lockstep::listen(
+            listener,
+            PROTOCOL,
+            LATENCY_CYCLES,
+            timeout,
+        ))
+    }
+
+    /// Connects to the other emulator, retrying until `timeout`
+    pub fn connect(addr: &str, timeout: Duration) -> Self {
+        Self::new(Lockstep::connect(addr, PROTOCOL, LATENCY_CYCLES, timeout))
+    }
+
+    fn new(connection: Lockstep) -> Self {
+        Self {
+            connection,
+            led: false,
+            sent_led: false,
+            light: false,
+            changes: VecDeque::new(),
+        }
+    }
+}
+
+impl Infrared for SocketInfrared {
+    // Sent at the end of the instruction
+    fn write_led(&mut self, on: bool) {
+        self.led = on;
+    }
+
+    fn read_light(&self) -> bool {
+        self.light
+    }
+
+    fn update_infrared(&mut self, cycles: u128, reading: bool) {
+        let frames = if reading {
+            self.connection.update(cycles)
+        } else {
+            self.connection.poll(cycles, IDLE_SYNC_CYCLES)
+        };
+        for frame in frames {
+            if frame.kind == LED {
+                self.changes
+                    .push_back((frame.cycle + LATENCY_CYCLES, frame.byte != 0));
+            }
+        }
+        if !self.connection.is_connected() {
+            self.changes.clear();
+            self.light = false;
+        }
+        if self.led != self.sent_led {
+            self.sent_led = self.led;
+            self.connection.send(LED, cycles, self.led as u8);
+        }
+
+        while let Some(&(cycle, on)) = self.changes.front() {
+            if cycle > cycles {
+                break;
+            }
+            self.light = on;
+            self.changes.pop_front();
+        }
+    }
+}
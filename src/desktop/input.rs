@@ -67,15 +67,21 @@ impl Input for InputCombiner {
 
 pub struct Gamepad {
     gilrs: Gilrs,
+    // Position of the gamepad among the connected ones, to have one per player
+    index: usize,
     gamepad_id: Option<GamepadId>,
     last_save_state: bool,
 }
 
 impl Gamepad {
     pub fn new() -> Self {
+        Self::with_index(0)
+    }
+
+    pub fn with_index(index: usize) -> Self {
         let gilrs = Gilrs::new().unwrap();
 
-        let gamepad_id = if let Some((gamepad_id, _gamepad)) = gilrs.gamepads().next() {
+        let gamepad_id = if let Some((gamepad_id, _gamepad)) = gilrs.gamepads().nth(index) {
             log(
                 LogLevel::Infos,
                 format!("Gamepad connected: {:?}", gamepad_id),
@@ -88,6 +94,7 @@ impl Gamepad {
 
         Self {
             gilrs,
+            index,
             gamepad_id,
             last_save_state: false,
         }
@@ -113,7 +120,7 @@ impl Input for Gamepad {
                 self.gamepad_id = None;
             }
         } else {
-            if let Some((gamepad_id, _gamepad)) = self.gilrs.gamepads().next() {
+            if let Some((gamepad_id, _gamepad)) = self.gilrs.gamepads().nth(self.index) {
                 log(
                     LogLevel::Infos,
                     format!("Gamepad connected: {:?}", gamepad_id),
@@ -192,16 +199,52 @@ impl Input for Gamepad {
     }
 }
 
+// Keys of A, B, Select, Start, then Right, Left, Up, Down (the order of the bits of the joypad
+// register)
+pub type KeyMapping = [KeyCode; 8];
+
+pub const PLAYER1_KEYS: KeyMapping = [
+    KeyCode::KeyA,
+    KeyCode::KeyB,
+    KeyCode::Backspace,
+    KeyCode::Enter,
+    KeyCode::ArrowRight,
+    KeyCode::ArrowLeft,
+    KeyCode::ArrowUp,
+    KeyCode::ArrowDown,
+];
+
+// Second Game Boy of the linked mode, on the other side of the keyboard
+pub const PLAYER2_KEYS: KeyMapping = [
+    KeyCode::KeyO,
+    KeyCode::KeyU,
+    KeyCode::KeyN,
+    KeyCode::KeyM,
+    KeyCode::KeyL,
+    KeyCode::KeyJ,
+    KeyCode::KeyI,
+    KeyCode::KeyK,
+];
+
+// Plays the game backwards while held (see `Gameboy::set_rewinding`)
+pub const REWIND_KEY: KeyCode = KeyCode::KeyR;
+
 pub struct Keyboard {
     keys: Keys,
+    mapping: KeyMapping,
     action_reg: u8,
     direction_reg: u8,
 }
 
 impl Keyboard {
     pub fn new(keys: Keys) -> Self {
+        Self::with_mapping(keys, PLAYER1_KEYS)
+    }
+
+    pub fn with_mapping(keys: Keys, mapping: KeyMapping) -> Self {
         Self {
             keys,
+            mapping,
             action_reg: 0,
             direction_reg: 0,
         }
@@ -211,45 +254,16 @@ impl Keyboard {
 impl Input for Keyboard {
     fn update_events(&mut self, _cycles: u128) -> Option<u128> {
         if let Ok(keys) = self.keys.lock() {
-            let mut res = 0xf;
-
-            if (*keys).contains(&KeyCode::KeyA) {
-                res &= 0b1110;
-            }
-
-            if (*keys).contains(&KeyCode::KeyB) {
-                res &= 0b1101;
-            }
-
-            if (*keys).contains(&KeyCode::Backspace) {
-                res &= 0b1011;
-            }
-
-            if (*keys).contains(&KeyCode::Enter) {
-                res &= 0b0111;
-            }
-
-            self.action_reg = res;
-
-            let mut res = 0xf;
-
-            if (*keys).contains(&KeyCode::ArrowRight) {
-                res &= 0b1110;
-            }
-
-            if (*keys).contains(&KeyCode::ArrowLeft) {
-                res &= 0b1101;
-            }
-
-            if (*keys).contains(&KeyCode::ArrowUp) {
-                res &= 0b1011;
-            }
-
-            if (*keys).contains(&KeyCode::ArrowDown) {
-                res &= 0b0111;
-            }
+            let reg = |mapping: &[KeyCode]| {
+                mapping
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, key)| (*keys).contains(key))
+                    .fold(0xf, |res, (bit, _)| res & !(1 << bit))
+            };
 
-            self.direction_reg = res;
+            self.action_reg = reg(&self.mapping[..4]);
+            self.direction_reg = reg(&self.mapping[4..]);
         }
 
         None
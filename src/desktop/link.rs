@@ -0,0 +1,265 @@
+// Link cable between two emulators kept in lockstep (see `Lockstep`), so the transfers happen on
+// the same cycles whatever the network and host timings:
+//  - a transfer with the internal clock is announced when it starts and completes on both sides
+//    on the cycle it ends, a byte transfer later, so the other side always knows it in time
+//  - the side with the external clock answers with its byte if it was waiting for a transfer, or
+//    0xff otherwise
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::net::TcpListener;
+use std::rc::Rc;
+use std::time::Duration;
+
+use crate::consts::CPU_CLOCK_SPEED;
+use crate::desktop::lockstep::{Frame, Lockstep};
+use crate::io::Serial;
+
+const PROTOCOL: u8 = 1;
+
+// Transfer of one byte at 8192Hz, also how far ahead of the other side an emulator can run
+const TRANSFER_CYCLES: u128 = CPU_CLOCK_SPEED as u128 / 1024;
+
+// Start of a transfer with the internal clock, the byte is the one sent
+const TRANSFER: u8 = 0x10;
+// Byte answered at the end of the transfer of the other side
+const REPLY: u8 = 0x11;
+
+pub struct LinkSerial {
+    transfer_requested: bool,
+    external_clock: bool,
+    current_data: u8,
+
+    connection: Lockstep,
+
+    // End of the transfer we started with the internal clock
+    transfer_end: Option<u128>,
+    reply: Option<u8>,
+    // Transfers started by the other side: end (in our cycles) and byte sent
+    peer_transfers: VecDeque<(u128, u8)>,
+}
+
+impl LinkSerial {
+    /// Waits for the other emulator to connect on `listener`
+    pub fn listen(listener: &TcpListener, timeout: Duration) -> Self {
+        Self::new(Lockstep::listen(
+            listener,
+            PROTOCOL,
+            TRANSFER_CYCLES,
+            timeout,
+        ))
+    }
+
+    /// Connects to the other emulator, retrying until `timeout`
+    pub fn connect(addr: &str, timeout: Duration) -> Self {
+        Self::new(Lockstep::connect(addr, PROTOCOL, TRANSFER_CYCLES, timeout))
+    }
+
+    fn new(connection: Lockstep) -> Self {
+        Self {
+            transfer_requested: false,
+            external_clock: false,
+            current_data: 0,
+
+            connection,
+
+            transfer_end: None,
+            reply: None,
+            peer_transfers: VecDeque::new(),
+        }
+    }
+
+    fn handle(&mut self, frame: Frame) {
+        match frame.kind {
+            TRANSFER => self
+                .peer_transfers
+                .push_back((frame.cycle + TRANSFER_CYCLES, frame.byte)),
+            REPLY => self.reply = Some(frame.byte),
+            _ => {}
+        }
+    }
+}
+
+impl Serial for LinkSerial {
+    fn read_data(&self) -> u8 {
+        self.current_data
+    }
+
+    fn read_control(&self) -> u8 {
+        (if self.external_clock { 0 } else { 0x01 })
+            | (if self.transfer_requested { 0x80 } else { 0 })
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.current_data = data;
+    }
+
+    fn write_control(&mut self, control: u8) {
+        self.external_clock = (control & 0b01) == 0;
+        self.transfer_requested = (control & 0x80) != 0;
+    }
+
+    fn update_serial(&mut self, cycles: u128) -> bool {
+        for frame in self.connection.update(cycles) {
+            self.handle(frame);
+        }
+        if !self.connection.is_connected() {
+            self.peer_transfers.clear();
+        }
+
+        if self.transfer_requested && !self.external_clock && self.transfer_end.is_none() {
+            self.transfer_end = Some(cycles + TRANSFER_CYCLES);
+            self.reply = None;
+            self.connection.send(TRANSFER, cycles, self.current_data);
+        }
+
+        let mut interrupt = false;
+        if let Some(&(end, byte)) = self.peer_transfers.front() {
+            if cycles >= end {
+                self.peer_transfers.pop_front();
+                let reply = if self.transfer_requested && self.external_clock {
+                    self.transfer_requested = false;
+                    interrupt = true;
+                    std::mem::replace(&mut self.current_data, byte)
+                } else {
+                    0xff
+                };
+                self.connection.send(REPLY, cycles, reply);
+            }
+        }
+
+        if let Some(end) = self.transfer_end {
+            if cycles >= end {
+                while self.reply.is_none() && self.connection.is_connected() {
+                    if let Some(frame) = self.connection.wait(cycles) {
+                        self.handle(frame);
+                    }
+                }
+                // Nothing is received when the cable is unplugged
+                self.current_data = self.reply.take().unwrap_or(0xff);
+                self.transfer_requested = false;
+                self.transfer_end = None;
+                interrupt = true;
+            }
+        }
+
+        interrupt
+    }
+
+    fn close_serial(&mut self) {
+        self.connection.close();
+    }
+}
+
+#[derive(Default)]
+struct Port {
+    transfer_requested: bool,
+    external_clock: bool,
+    data: u8,
+    // End of the transfer started with the internal clock
+    transfer_end: Option<u128>,
+    // Cycle of the interrupt of a completed transfer
+    interrupt: Option<u128>,
+}
+
+// Cable between two Game Boys of the same process, which must be run at the same cycle count
+// (see `Gameboy::run_linked_until_next_sleep`). A transfer is completed by the first side that
+// reaches its end, with the other side at most one instruction behind, and both get their
+// interrupt on the cycle it ends.
+#[derive(Default)]
+struct Cable {
+    ports: [Port; 2],
+}
+
+impl Cable {
+    fn complete_transfers(&mut self, cycles: u128) {
+        for master in 0..2 {
+            let Some(end) = self.ports[master].transfer_end else {
+                continue;
+            };
+            if cycles < end {
+                continue;
+            }
+
+            let [a, b] = &mut self.ports;
+            let (master_port, other) = if master == 0 { (a, b) } else { (b, a) };
+            if other.transfer_requested && other.external_clock {
+                std::mem::swap(&mut master_port.data, &mut other.data);
+                other.transfer_requested = false;
+                other.interrupt = Some(end);
+            } else {
+                master_port.data = 0xff;
+            }
+            master_port.transfer_requested = false;
+            master_port.transfer_end = None;
+            master_port.interrupt = Some(end);
+        }
+    }
+}
+
+pub struct LinkedSerial {
+    cable: Rc<RefCell<Cable>>,
+    side: usize,
+}
+
+impl LinkedSerial {
+    /// Two serial ports connected to each other
+    pub fn pair() -> (Self, Self) {
+        let cable = Rc::new(RefCell::new(Cable::default()));
+        (
+            Self {
+                cable: cable.clone(),
+                side: 0,
+            },
+            Self { cable, side: 1 },
+        )
+    }
+}
+
+impl Serial for LinkedSerial {
+    fn read_data(&self) -> u8 {
+        self.cable.borrow().ports[self.side].data
+    }
+
+    fn read_control(&self) -> u8 {
+        let cable = self.cable.borrow();
+        let port = &cable.ports[self.side];
+        (if port.external_clock { 0 } else { 0x01 })
+            | (if port.transfer_requested { 0x80 } else { 0 })
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.cable.borrow_mut().ports[self.side].data = data;
+    }
+
+    fn write_control(&mut self, control: u8) {
+        let mut cable = self.cable.borrow_mut();
+        let port = &mut cable.ports[self.side];
+        port.external_clock = (control & 0b01) == 0;
+        port.transfer_requested = (control & 0x80) != 0;
+        if !port.transfer_requested || port.external_clock {
+            port.transfer_end = None;
+        }
+    }
+
+    fn update_serial(&mut self, cycles: u128) -> bool {
+        let mut cable = self.cable.borrow_mut();
+        let port = &mut cable.ports[self.side];
+        if port.transfer_requested && !port.external_clock && port.transfer_end.is_none() {
+            port.transfer_end = Some(cycles + TRANSFER_CYCLES);
+        }
+
+        cable.complete_transfers(cycles);
+
+        let port = &mut cable.ports[self.side];
+        match port.interrupt {
+            Some(cycle) if cycles >= cycle => {
+                port.interrupt = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn close_serial(&mut self) {}
+}
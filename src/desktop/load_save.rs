@@ -1,5 +1,7 @@
+use crate::bess;
 use crate::io::{Audio, LoadSave, Serial};
 use crate::logs::{elog, log, LogLevel};
+use crate::save_state;
 use crate::state::GBState;
 use std::fs::File;
 use std::io::{Read, Write};
@@ -9,6 +11,7 @@ pub struct FSLoadSave {
     rom_file: String,
     save_file: String,
     state_file: Option<String>,
+    bess: bool,
 }
 
 impl FSLoadSave {
@@ -17,6 +20,7 @@ impl FSLoadSave {
             rom_file: rom_file.into(),
             save_file: save_file.into(),
             state_file: None,
+            bess: false,
         }
     }
 
@@ -24,6 +28,12 @@ impl FSLoadSave {
         self.state_file = Some(state_file.into());
         self
     }
+
+    /// Saves the states in the BESS format instead of the one of the emulator
+    pub fn bess(mut self, bess: bool) -> Self {
+        self.bess = bess;
+        self
+    }
 }
 
 impl LoadSave for FSLoadSave {
@@ -132,25 +142,17 @@ impl LoadSave for FSLoadSave {
 
     fn save_state<S: Serial, A: Audio>(&self, state: &GBState<S, A>) -> Result<(), std::io::Error> {
         if let Some(state_file) = &self.state_file {
-            let mut state_file = File::create(state_file)?;
-            for addr in 0x8000..0xa000 {
-                state_file.write_all(&[state.mem.r(addr)])?;
+            let mut f = File::create(state_file)?;
+            if self.bess {
+                f.write_all(&bess::export(state))?;
+            } else {
+                f.write_all(&save_state::save(state))?;
             }
 
-            state_file.write_all(state.mem.wram_00.as_ref())?;
-            state_file.write_all(state.mem.wram_01.as_ref())?;
-
-            for addr in 0xff00..0xff80 {
-                state_file.write_all(&[state.mem.r(addr)])?;
-            }
-
-            state_file.write_all(state.mem.hram.as_ref())?;
-            state_file.write_all(&[state.mem.interrupts_register])?;
-
-            state_file.write_all(&state.cpu.r)?;
-            state_file.write_all(&state.cpu.pc.to_le_bytes())?;
-            state_file.write_all(&state.cpu.sp.to_le_bytes())?;
-            state_file.write_all(&[state.mem.boot_rom_on.into(), state.mem.ime.into()])?;
+            log(
+                LogLevel::Infos,
+                format!("State saved to \"{}\"!", state_file),
+            );
         } else {
             elog(
                 LogLevel::Error,
@@ -165,41 +167,15 @@ impl LoadSave for FSLoadSave {
         state: &mut GBState<S, A>,
     ) -> Result<(), std::io::Error> {
         if let Some(state_file) = &self.state_file {
-            let mut state_file = File::open(state_file)?;
-
-            let mut vram = Box::new([0; 0x2000]);
-            state_file.read_exact(vram.as_mut())?;
-            for i in 0x0000..0x2000 {
-                state.mem.w(0x8000 + i, vram[i as usize]);
-            }
-
-            state_file.read_exact(state.mem.wram_00.as_mut())?;
-            state_file.read_exact(state.mem.wram_01.as_mut())?;
-
-            let mut io = [0; 0x80];
-            state_file.read_exact(io.as_mut())?;
-            for i in 0x00..0x80 {
-                state.mem.w(0xff00 + i, io[i as usize]);
-            }
-
-            state_file.read_exact(state.mem.hram.as_mut())?;
-
-            let mut reg8 = [0; 1];
-            state_file.read_exact(reg8.as_mut())?;
-            state.mem.interrupts_register = reg8[0];
-
-            state_file.read_exact(&mut state.cpu.r)?;
-
-            let mut reg16 = [0; 2];
-            state_file.read_exact(&mut reg16)?;
-            state.cpu.pc = u16::from_le_bytes(reg16);
-            state_file.read_exact(&mut reg16)?;
-            state.cpu.sp = u16::from_le_bytes(reg16);
-
-            state_file.read_exact(reg8.as_mut())?;
-            state.mem.boot_rom_on = reg8[0] != 0;
-            state_file.read_exact(reg8.as_mut())?;
-            state.mem.ime = reg8[0] != 0;
+            let mut data = vec![];
+            File::open(state_file)?.read_to_end(&mut data)?;
+            save_state::load(state, &data)
+                .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+
+            log(
+                LogLevel::Infos,
+                format!("State loaded from \"{}\"!", state_file),
+            );
         }
         Ok(())
     }
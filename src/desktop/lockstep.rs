@@ -0,0 +1,240 @@
+// Connection between two emulators kept in lockstep, used by the link cable and the infrared port.
+// Each side sends timestamped frames and never runs more than `lookahead` cycles ahead of the time
+// it knows the other side has reached. A frame sent at a cycle is then always received before the
+// other side reaches `lookahead` cycles later, and acting on it then gives the same result
+// whatever the network and host timings.
+//
+// Frames are 10 bytes: kind, cycle of the sender (u64 LE), byte. The kinds below 0x10 are the
+// ones of the connection.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::logs::{elog, log, LogLevel};
+
+const FRAME_SIZE: usize = 10;
+
+// Start of the connection, the byte is the protocol
+const HELLO: u8 = 0x00;
+// Time of the sender, so the other side can keep running
+const SYNC: u8 = 0x01;
+const BYE: u8 = 0x02;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Frame {
+    pub kind: u8,
+    // Converted to the cycles of the receiver
+    pub cycle: u128,
+    pub byte: u8,
+}
+
+pub struct Lockstep {
+    stream: Option<TcpStream>,
+    frames: Receiver<[u8; FRAME_SIZE]>,
+    protocol: u8,
+    lookahead: u128,
+    timeout: Duration,
+
+    // Cycles of both sides when the connection started, to convert the times of the other side
+    origin: Option<(u128, u64)>,
+    // Last time reached by the other side, in our cycles
+    peer_cycles: u128,
+    last_sent: u128,
+}
+
+impl Lockstep {
+    /// Waits for the other emulator to connect on `listener`
+    pub fn listen(
+        listener: &TcpListener,
+        protocol: u8,
+        lookahead: u128,
+        timeout: Duration,
+    ) -> Self {
+        log(
+            LogLevel::Infos,
+            format!(
+                "Waiting for the other emulator on {:?}",
+                listener.local_addr()
+            ),
+        );
+        let stream = match listener.accept() {
+            Ok((stream, addr)) => {
+                log(LogLevel::Infos, format!("Connected with {:?}", addr));
+                Some(stream)
+            }
+            Err(err) => {
+                elog(LogLevel::Error, format!("Connection failed ({})", err));
+                None
+            }
+        };
+        Self::new(stream, protocol, lookahead, timeout)
+    }
+
+    /// Connects to the other emulator, retrying until `timeout`
+    pub fn connect(addr: &str, protocol: u8, lookahead: u128, timeout: Duration) -> Self {
+        let start = Instant::now();
+        let stream = loop {
+            match TcpStream::connect(addr) {
+                Ok(stream) => {
+                    log(LogLevel::Infos, format!("Connected to {:?}", addr));
+                    break Some(stream);
+                }
+                Err(err) if start.elapsed() >= timeout => {
+                    elog(
+                        LogLevel::Error,
+                        format!("Connection to {:?} failed ({})", addr, err),
+                    );
+                    break None;
+                }
+                Err(_) => thread::sleep(Duration::from_millis(100)),
+            }
+        };
+        Self::new(stream, protocol, lookahead, timeout)
+    }
+
+    fn new(stream: Option<TcpStream>, protocol: u8, lookahead: u128, timeout: Duration) -> Self {
+        let (tx, frames) = mpsc::channel();
+        if let Some(stream) = &stream {
+            let _ = stream.set_nodelay(true);
+            let mut input = stream.try_clone().unwrap();
+            // The channel is closed when the connection is
+            thread::spawn(move || {
+                let mut bytes = [0; FRAME_SIZE];
+                while input.read_exact(&mut bytes).is_ok() {
+                    if tx.send(bytes).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        Self {
+            stream,
+            frames,
+            protocol,
+            lookahead,
+            timeout,
+
+            origin: None,
+            peer_cycles: 0,
+            last_sent: 0,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// Sends a frame at `cycles`, after `update` was called for it
+    pub fn send(&mut self, kind: u8, cycles: u128, byte: u8) {
+        let mut bytes = [0; FRAME_SIZE];
+        bytes[0] = kind;
+        bytes[1..9].copy_from_slice(&(cycles as u64).to_le_bytes());
+        bytes[9] = byte;
+        if let Some(stream) = &mut self.stream {
+            if stream.write_all(&bytes).is_err() {
+                self.disconnect("Connection closed");
+            }
+        }
+        self.last_sent = cycles;
+    }
+
+    fn disconnect(&mut self, reason: &str) {
+        if let Some(stream) = self.stream.take() {
+            log(LogLevel::Infos, reason);
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    // Frame for the user of the connection, if it is one
+    fn handle(&mut self, bytes: [u8; FRAME_SIZE]) -> Option<Frame> {
+        let (kind, byte) = (bytes[0], bytes[9]);
+        let cycle = u64::from_le_bytes(bytes[1..9].try_into().unwrap());
+        let Some((origin, peer_origin)) = self.origin else {
+            if kind == HELLO && byte == self.protocol {
+                self.origin = Some((self.last_sent, cycle));
+                self.peer_cycles = self.last_sent;
+            } else {
+                self.disconnect("Protocol mismatch with the other emulator");
+            }
+            return None;
+        };
+
+        let cycle = cycle.saturating_sub(peer_origin) as u128 + origin;
+        self.peer_cycles = self.peer_cycles.max(cycle);
+        match kind {
+            HELLO | SYNC => None,
+            BYE => {
+                self.disconnect("Connection closed by the other emulator");
+                None
+            }
+            _ => Some(Frame { kind, cycle, byte }),
+        }
+    }
+
+    /// Waits for the next frame after sending our time so the other side can't wait for us.
+    /// Returns None if it wasn't for the user of the connection or if the connection was lost.
+    pub fn wait(&mut self, cycles: u128) -> Option<Frame> {
+        if !self.is_connected() {
+            return None;
+        }
+        if self.origin.is_some() && self.last_sent != cycles {
+            self.send(SYNC, cycles, 0);
+        }
+        match self.frames.recv_timeout(self.timeout) {
+            Ok(bytes) => self.handle(bytes),
+            Err(RecvTimeoutError::Timeout) => {
+                self.disconnect("The other emulator timed out");
+                None
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                self.disconnect("Connection closed");
+                None
+            }
+        }
+    }
+
+    /// Frames received up to `cycles`, waiting for the other side if we are `lookahead` cycles
+    /// ahead of it. Must be called at every instruction.
+    pub fn update(&mut self, cycles: u128) -> Vec<Frame> {
+        let mut frames = self.poll(cycles, self.lookahead / 2);
+        while self.is_connected() && cycles >= self.peer_cycles + self.lookahead {
+            frames.extend(self.wait(cycles));
+        }
+        frames
+    }
+
+    /// Frames received so far, without waiting for the other side (apart from the start of the
+    /// connection). Our time is sent every `sync_interval` cycles, so the other side can still
+    /// use `update` but waits for us longer with a larger interval.
+    pub fn poll(&mut self, cycles: u128, sync_interval: u128) -> Vec<Frame> {
+        let mut frames = vec![];
+        if !self.is_connected() {
+            return frames;
+        }
+
+        if self.origin.is_none() {
+            self.send(HELLO, cycles, self.protocol);
+            while self.is_connected() && self.origin.is_none() {
+                frames.extend(self.wait(cycles));
+            }
+        } else if cycles >= self.last_sent + sync_interval {
+            self.send(SYNC, cycles, 0);
+        }
+
+        while let Ok(bytes) = self.frames.try_recv() {
+            frames.extend(self.handle(bytes));
+        }
+        frames
+    }
+
+    pub fn close(&mut self) {
+        if self.is_connected() {
+            self.send(BYE, self.last_sent, 0);
+            self.disconnect("Connection closed");
+        }
+    }
+}
@@ -1,5 +1,13 @@
 pub mod audio;
+pub mod infrared;
 pub mod input;
+pub mod link;
 pub mod load_save;
+pub mod lockstep;
+pub mod printer;
+pub mod record;
+pub mod ring;
 pub mod serial;
+pub mod slots;
+pub mod wav;
 pub mod window;
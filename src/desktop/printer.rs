@@ -0,0 +1,359 @@
+// Game Boy Printer connected to the serial port. The game sends packets of the form
+//   0x88 0x33 | command | compression | length (LE) | data | checksum (LE) | 0x00 0x00
+// and the printer answers 0x81 to the first of the two last bytes and its status to the second.
+// The image data is kept until a print command, which adds it to the current page with the
+// palette and margins of the command. A page ends with a margin after the image and is saved
+// as a PNG file.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::consts::CPU_CLOCK_SPEED;
+use crate::io::Serial;
+use crate::logs::{elog, log, LogLevel};
+use crate::png;
+
+// Transfer of one byte at 8192Hz
+const BYTE_TRANSFER_CYCLES: u128 = CPU_CLOCK_SPEED as u128 / 1024;
+
+// Time during which the printer reports itself as busy after a print command
+const PRINT_CYCLES: u128 = CPU_CLOCK_SPEED as u128;
+
+const COMMAND_INIT: u8 = 0x01;
+const COMMAND_PRINT: u8 = 0x02;
+const COMMAND_DATA: u8 = 0x04;
+const COMMAND_BREAK: u8 = 0x08;
+
+mod status {
+    pub const CHECKSUM_ERROR: u8 = 1 << 0;
+    pub const PRINTING: u8 = 1 << 1;
+    pub const IMAGE_DATA_FULL: u8 = 1 << 2;
+    pub const UNPROCESSED_DATA: u8 = 1 << 3;
+}
+
+// Reply to the first byte after the checksum
+const ALIVE: u8 = 0x81;
+
+// 20 tiles of 8 pixels
+const WIDTH: usize = 160;
+
+// Height of the paper fed for each unit of margin
+const MARGIN_LINE_HEIGHT: usize = 8;
+
+// Shades of the paper for the 4 colors after the palette
+const PAPER_SHADES: [u32; 4] = [0x00ffffff, 0x00aaaaaa, 0x00555555, 0x00000000];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PacketState {
+    Magic1,
+    Magic2,
+    Command,
+    Compression,
+    LengthLow,
+    LengthHigh,
+    Data,
+    ChecksumLow,
+    ChecksumHigh,
+    Alive,
+    Status,
+}
+
+pub struct PrinterSerial {
+    transfer_requested: bool,
+    external_clock: bool,
+    current_data: u8,
+    // Cycle at which the current transfer started
+    transfer_start: Option<u128>,
+
+    state: PacketState,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    // Sum of the bytes from the command to the end of the data
+    checksum: u16,
+    received_checksum: u16,
+
+    status: u8,
+    busy_until: u128,
+    cycles: u128,
+
+    // 2bpp tiles received since the last print, 20 tiles per line
+    image: Vec<u8>,
+    // Color indexes of the current page, WIDTH pixels per line
+    page: Vec<u8>,
+
+    directory: PathBuf,
+    next_page: usize,
+}
+
+impl PrinterSerial {
+    /// Printer saving its pages as "print-<n>.png" in `directory`
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        let directory = directory.into();
+        if let Err(err) = fs::create_dir_all(&directory) {
+            elog(
+                LogLevel::Error,
+                format!("Failed to create the printer directory ({})", err),
+            );
+        }
+
+        Self {
+            transfer_requested: false,
+            external_clock: false,
+            current_data: 0,
+            transfer_start: None,
+
+            state: PacketState::Magic1,
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: vec![],
+            checksum: 0,
+            received_checksum: 0,
+
+            status: 0,
+            busy_until: 0,
+            cycles: 0,
+
+            image: vec![],
+            page: vec![],
+
+            directory,
+            next_page: 1,
+        }
+    }
+
+    // Handles a byte sent by the game and returns the byte sent back at the same time
+    fn exchange(&mut self, byte: u8) -> u8 {
+        let mut reply = 0;
+        self.state = match self.state {
+            PacketState::Magic1 if byte == 0x88 => PacketState::Magic2,
+            PacketState::Magic1 => PacketState::Magic1,
+            PacketState::Magic2 if byte == 0x33 => PacketState::Command,
+            PacketState::Magic2 => PacketState::Magic1,
+            PacketState::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                PacketState::Compression
+            }
+            PacketState::Compression => {
+                self.compressed = byte & 1 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthLow
+            }
+            PacketState::LengthLow => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                PacketState::LengthHigh
+            }
+            PacketState::LengthHigh => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    PacketState::ChecksumLow
+                } else {
+                    PacketState::Data
+                }
+            }
+            PacketState::ChecksumLow => {
+                self.received_checksum = byte as u16;
+                PacketState::ChecksumHigh
+            }
+            PacketState::ChecksumHigh => {
+                self.received_checksum |= (byte as u16) << 8;
+                if self.received_checksum == self.checksum {
+                    self.status &= !status::CHECKSUM_ERROR;
+                    self.run_command();
+                } else {
+                    self.status |= status::CHECKSUM_ERROR;
+                }
+                PacketState::Alive
+            }
+            PacketState::Alive => {
+                reply = ALIVE;
+                PacketState::Status
+            }
+            PacketState::Status => {
+                reply = self.status;
+                PacketState::Magic1
+            }
+        };
+        reply
+    }
+
+    fn run_command(&mut self) {
+        match self.command {
+            COMMAND_INIT => {
+                self.image.clear();
+                self.status = 0;
+            }
+            COMMAND_DATA if !self.data.is_empty() => {
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    self.data.clone()
+                };
+                self.image.extend(data);
+                self.status |= status::UNPROCESSED_DATA;
+            }
+            COMMAND_PRINT if self.data.len() == 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                self.print(sheets, margins >> 4, margins & 0xf, palette);
+                self.status = status::PRINTING | status::IMAGE_DATA_FULL;
+                self.busy_until = self.cycles + PRINT_CYCLES;
+            }
+            COMMAND_BREAK => {
+                self.image.clear();
+                self.status = 0;
+            }
+            // Data end, status request
+            _ => {}
+        }
+    }
+
+    // Adds the received image to the page, `sheets` times (0 only feeds the paper)
+    fn print(&mut self, sheets: u8, margin_before: u8, margin_after: u8, palette: u8) {
+        self.feed(margin_before);
+
+        let lines = self.image.len() / (WIDTH / 8 * 16) * 8;
+        for _ in 0..sheets {
+            for y in 0..lines {
+                for x in 0..WIDTH {
+                    let tile = (y / 8) * (WIDTH / 8) + x / 8;
+                    let low = self.image[tile * 16 + (y % 8) * 2];
+                    let high = self.image[tile * 16 + (y % 8) * 2 + 1];
+                    let bit = 7 - (x % 8);
+                    let color = ((low >> bit) & 1) | (((high >> bit) & 1) << 1);
+                    self.page.push((palette >> (color * 2)) & 0b11);
+                }
+            }
+        }
+        self.image.clear();
+
+        self.feed(margin_after);
+        if margin_after > 0 {
+            self.save_page();
+        }
+    }
+
+    fn feed(&mut self, margin: u8) {
+        if !self.page.is_empty() || margin > 0 {
+            let blank = margin as usize * MARGIN_LINE_HEIGHT * WIDTH;
+            self.page.extend(std::iter::repeat_n(0, blank));
+        }
+    }
+
+    fn save_page(&mut self) {
+        if self.page.iter().all(|color| *color == 0) {
+            self.page.clear();
+            return;
+        }
+
+        let path = loop {
+            let path = self.directory.join(format!("print-{}.png", self.next_page));
+            self.next_page += 1;
+            if !path.exists() {
+                break path;
+            }
+        };
+
+        let pixels: Vec<u32> = self
+            .page
+            .iter()
+            .map(|color| PAPER_SHADES[*color as usize])
+            .collect();
+        let height = pixels.len() / WIDTH;
+        match fs::write(&path, png::encode_rgb(&pixels, WIDTH, height)) {
+            Ok(()) => log(LogLevel::Infos, format!("Printed to {:?}", path)),
+            Err(err) => elog(
+                LogLevel::Error,
+                format!("Failed to save the print to {:?} ({})", path, err),
+            ),
+        }
+        self.page.clear();
+    }
+}
+
+// Run-length decoding: a control byte with the bit 7 set repeats the next byte (control & 0x7f)
+// + 2 times, otherwise it is followed by (control + 1) bytes to copy
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut i = 0;
+    while i < data.len() {
+        let control = data[i];
+        i += 1;
+        if control & 0x80 != 0 {
+            if let Some(byte) = data.get(i) {
+                out.extend(std::iter::repeat_n(*byte, (control & 0x7f) as usize + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + control as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+impl Serial for PrinterSerial {
+    fn read_data(&self) -> u8 {
+        self.current_data
+    }
+
+    fn read_control(&self) -> u8 {
+        (if self.external_clock { 0 } else { 0x01 })
+            | (if self.transfer_requested { 0x80 } else { 0 })
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.current_data = data;
+    }
+
+    fn write_control(&mut self, control: u8) {
+        self.external_clock = (control & 0b01) == 0;
+        self.transfer_requested = (control & 0x80) != 0;
+    }
+
+    // The printer has no clock, only the transfers started by the game with its internal clock
+    // go through
+    fn update_serial(&mut self, cycles: u128) -> bool {
+        self.cycles = cycles;
+        if self.status & status::PRINTING != 0 && cycles >= self.busy_until {
+            self.status &= !status::PRINTING;
+        }
+
+        if !self.transfer_requested || self.external_clock {
+            self.transfer_start = None;
+            return false;
+        }
+
+        let start = *self.transfer_start.get_or_insert(cycles);
+        if cycles < start + BYTE_TRANSFER_CYCLES {
+            return false;
+        }
+
+        self.current_data = self.exchange(self.current_data);
+        self.transfer_requested = false;
+        self.transfer_start = None;
+        true
+    }
+
+    // Saves the page being printed
+    fn close_serial(&mut self) {
+        if !self.page.is_empty() {
+            self.save_page();
+        }
+    }
+}
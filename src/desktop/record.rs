@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::mpsc::{channel, Receiver, Sender};
+
+use crate::consts::CPU_CLOCK_SPEED;
+use crate::desktop::wav::WavWriter;
+use crate::filters::Filter;
+use crate::io::Audio;
+
+const FRAME_CYCLES: u64 = 70224;
+
+/// Audio backend wrapper sending a copy of every generated sample to a `Recorder`
+pub struct RecordingAudio<A: Audio> {
+    inner: A,
+    tap: Sender<Vec<f32>>,
+}
+
+impl<A: Audio> RecordingAudio<A> {
+    pub fn new(inner: A) -> (Self, Receiver<Vec<f32>>) {
+        let (tap, samples) = channel();
+        (Self { inner, tap }, samples)
+    }
+}
+
+impl<A: Audio> Audio for RecordingAudio<A> {
+    fn sample_rate(&self) -> u32 {
+        self.inner.sample_rate()
+    }
+
+    fn push_samples(&mut self, samples: &[f32]) {
+        let _ = self.tap.send(samples.to_vec());
+        self.inner.push_samples(samples);
+    }
+
+    fn latency(&self) -> Option<f32> {
+        self.inner.latency()
+    }
+}
+
+enum VideoOutput {
+    Y4m(BufWriter<File>),
+    Rgb(BufWriter<File>),
+    Gif(GifWriter),
+}
+
+/// Writes every emulated frame to a video file (the format depends on the extension:
+/// .y4m, .rgb or .gif) and the audio next to it in a .wav file.
+pub struct Recorder {
+    video: VideoOutput,
+    wav: WavWriter,
+    samples: Receiver<Vec<f32>>,
+    filter: Filter,
+    width: usize,
+    height: usize,
+}
+
+impl Recorder {
+    pub fn new(
+        path: &str,
+        filter: Filter,
+        samples: Receiver<Vec<f32>>,
+        sample_rate: u32,
+    ) -> io::Result<Self> {
+        let width = 160 * filter.scale();
+        let height = 144 * filter.scale();
+
+        let path = Path::new(path);
+        let video = match path.extension().and_then(|e| e.to_str()) {
+            Some("y4m") => {
+                let mut file = BufWriter::new(File::create(path)?);
+                file.write_all(
+                    format!(
+                        "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444\n",
+                        width, height, CPU_CLOCK_SPEED, FRAME_CYCLES
+                    )
+                    .as_bytes(),
+                )?;
+                VideoOutput::Y4m(file)
+            }
+            Some("rgb") => VideoOutput::Rgb(BufWriter::new(File::create(path)?)),
+            Some("gif") => VideoOutput::Gif(GifWriter::create(path, width, height)?),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Unknown recording format (possible extensions: .y4m, .rgb, .gif)",
+                ))
+            }
+        };
+
+        let wav = WavWriter::create(path.with_extension("wav").to_str().unwrap(), 2, sample_rate)?;
+
+        Ok(Self {
+            video,
+            wav,
+            samples,
+            filter,
+            width,
+            height,
+        })
+    }
+
+    /// Writes a frame and all the audio generated since the previous one
+    pub fn frame(&mut self, fb: &[u32; 160 * 144]) -> io::Result<()> {
+        for samples in self.samples.try_iter() {
+            self.wav.write_samples(&samples)?;
+        }
+        self.wav.update_header()?;
+
+        let fb = self.filter.apply(fb.as_ref(), 160, 144);
+        match &mut self.video {
+            VideoOutput::Y4m(file) => {
+                file.write_all(b"FRAME\n")?;
+                let mut planes = vec![0; self.width * self.height * 3];
+                for (i, px) in fb.iter().enumerate() {
+                    let r = ((px >> 16) & 0xff) as i32;
+                    let g = ((px >> 8) & 0xff) as i32;
+                    let b = (px & 0xff) as i32;
+                    let n = self.width * self.height;
+
+                    // BT.601 studio range
+                    planes[i] = (16 + ((66 * r + 129 * g + 25 * b + 128) >> 8)) as u8;
+                    planes[n + i] = (128 + ((-38 * r - 74 * g + 112 * b + 128) >> 8)) as u8;
+                    planes[2 * n + i] = (128 + ((112 * r - 94 * g - 18 * b + 128) >> 8)) as u8;
+                }
+                file.write_all(&planes)
+            }
+            VideoOutput::Rgb(file) => {
+                let rgb: Vec<u8> = fb
+                    .iter()
+                    .flat_map(|px| px.to_be_bytes()[1..].to_vec())
+                    .collect();
+                file.write_all(&rgb)
+            }
+            VideoOutput::Gif(gif) => gif.frame(&fb),
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        match self.video {
+            VideoOutput::Y4m(mut file) | VideoOutput::Rgb(mut file) => file.flush()?,
+            VideoOutput::Gif(gif) => gif.finish()?,
+        }
+        self.wav.finish()
+    }
+}
+
+struct GifWriter {
+    file: BufWriter<File>,
+    width: usize,
+    height: usize,
+    // Time in centiseconds since the start, used to alternate between 1 and 2cs delays so the
+    // average speed is right
+    time_cs: f64,
+}
+
+impl GifWriter {
+    fn create(path: &Path, width: usize, height: usize) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        file.write_all(b"GIF89a")?;
+        file.write_all(&(width as u16).to_le_bytes())?;
+        file.write_all(&(height as u16).to_le_bytes())?;
+        file.write_all(&[0, 0, 0])?; // No global color table
+
+        // Loop forever
+        file.write_all(&[0x21, 0xff, 0x0b])?;
+        file.write_all(b"NETSCAPE2.0")?;
+        file.write_all(&[0x03, 0x01, 0x00, 0x00, 0x00])?;
+
+        Ok(Self {
+            file,
+            width,
+            height,
+            time_cs: 0.,
+        })
+    }
+
+    fn frame(&mut self, fb: &[u32]) -> io::Result<()> {
+        let mut palette: Vec<u32> = vec![];
+        let mut palette_index: HashMap<u32, u8> = HashMap::new();
+        let mut indices = Vec::with_capacity(fb.len());
+
+        for px in fb {
+            if let Some(i) = palette_index.get(px) {
+                indices.push(*i);
+            } else if palette.len() < 256 {
+                palette_index.insert(*px, palette.len() as u8);
+                indices.push(palette.len() as u8);
+                palette.push(*px);
+            } else {
+                break;
+            }
+        }
+
+        // More than 256 colors (can only happen in CGB mode): using a 6x6x6 color cube
+        if indices.len() != fb.len() {
+            palette = (0..216)
+                .map(|i| (((i / 36) * 51) << 16) | ((((i / 6) % 6) * 51) << 8) | ((i % 6) * 51))
+                .collect();
+            indices = fb
+                .iter()
+                .map(|px| {
+                    let q = |shift: u32| ((px >> shift) & 0xff) * 6 / 256;
+                    (q(16) * 36 + q(8) * 6 + q(0)) as u8
+                })
+                .collect();
+        }
+
+        let table_bits = (usize::BITS - (palette.len() - 1).max(1).leading_zeros()) as u8;
+
+        let start = self.time_cs;
+        self.time_cs += FRAME_CYCLES as f64 * 100. / CPU_CLOCK_SPEED as f64;
+        let delay = (self.time_cs.round() - start.round()) as u16;
+
+        // Graphic control extension
+        self.file.write_all(&[0x21, 0xf9, 0x04, 0x00])?;
+        self.file.write_all(&delay.to_le_bytes())?;
+        self.file.write_all(&[0x00, 0x00])?;
+
+        // Image descriptor with a local color table
+        self.file.write_all(&[0x2c, 0, 0, 0, 0])?;
+        self.file.write_all(&(self.width as u16).to_le_bytes())?;
+        self.file.write_all(&(self.height as u16).to_le_bytes())?;
+        self.file.write_all(&[0x80 | (table_bits - 1)])?;
+        for i in 0..(1 << table_bits) {
+            let color = palette.get(i).copied().unwrap_or(0);
+            self.file.write_all(&color.to_be_bytes()[1..])?;
+        }
+
+        let min_code_size = table_bits.max(2);
+        self.file.write_all(&[min_code_size])?;
+        for block in lzw_encode(&indices, min_code_size).chunks(255) {
+            self.file.write_all(&[block.len() as u8])?;
+            self.file.write_all(block)?;
+        }
+        self.file.write_all(&[0])
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.file.write_all(&[0x3b])?;
+        self.file.flush()
+    }
+}
+
+fn lzw_encode(indices: &[u8], min_code_size: u8) -> Vec<u8> {
+    let clear = 1u16 << min_code_size;
+    let end = clear + 1;
+
+    let mut out = vec![];
+    let mut bits = 0u32;
+    let mut n_bits = 0;
+    let mut write = |code: u16, size: u8| {
+        bits |= (code as u32) << n_bits;
+        n_bits += size;
+        while n_bits >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            n_bits -= 8;
+        }
+    };
+
+    let mut dict: HashMap<(u16, u8), u16> = HashMap::new();
+    let mut next_code = end + 1;
+    let mut code_size = min_code_size + 1;
+
+    write(clear, code_size);
+
+    let mut prefix = indices[0] as u16;
+    for k in &indices[1..] {
+        if let Some(code) = dict.get(&(prefix, *k)) {
+            prefix = *code;
+            continue;
+        }
+
+        write(prefix, code_size);
+        if next_code < 4096 {
+            dict.insert((prefix, *k), next_code);
+            next_code += 1;
+            // The decoder is one code behind, so the size only grows once it needs it
+            if next_code > (1 << code_size) && code_size < 12 {
+                code_size += 1;
+            }
+        } else {
+            write(clear, code_size);
+            dict.clear();
+            next_code = end + 1;
+            code_size = min_code_size + 1;
+        }
+        prefix = *k as u16;
+    }
+    write(prefix, code_size);
+    write(end, code_size);
+
+    if n_bits > 0 {
+        out.push(bits as u8);
+    }
+    out
+}
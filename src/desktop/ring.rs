@@ -0,0 +1,125 @@
+// Lock-free single producer single consumer ring buffer of interleaved stereo samples, giving the
+// samples generated by the emulator thread to the audio callback without blocking either of them.
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+struct RingBuffer {
+    // f32 samples stored as their bits
+    samples: Box<[AtomicU32]>,
+    // Total number of samples written and read (wrapping). The ring is empty when they are equal.
+    written: AtomicUsize,
+    read: AtomicUsize,
+    // Samples the consumer asked for while the ring was empty
+    underruns: AtomicUsize,
+    // Samples dropped by the producer while the ring was full
+    overruns: AtomicUsize,
+}
+
+impl RingBuffer {
+    fn len(&self) -> usize {
+        self.written
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read.load(Ordering::Acquire))
+    }
+}
+
+/// Emulator side of the ring buffer
+pub struct Producer(Arc<RingBuffer>);
+
+/// Audio callback side of the ring buffer
+pub struct Consumer(Arc<RingBuffer>);
+
+/// Creates a ring buffer holding up to `capacity` samples (rounded up to whole stereo frames)
+pub fn ring_buffer(capacity: usize) -> (Producer, Consumer) {
+    let capacity = (capacity + 1) & !1;
+    let ring = Arc::new(RingBuffer {
+        samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        written: AtomicUsize::new(0),
+        read: AtomicUsize::new(0),
+        underruns: AtomicUsize::new(0),
+        overruns: AtomicUsize::new(0),
+    });
+    (Producer(ring.clone()), Consumer(ring))
+}
+
+impl Producer {
+    /// Adds as many whole stereo frames as there is room for and returns the number of samples
+    /// added. The other ones are counted as overruns.
+    pub fn push(&self, samples: &[f32]) -> usize {
+        let ring = &self.0;
+        let capacity = ring.samples.len();
+        let written = ring.written.load(Ordering::Relaxed);
+        let free = capacity - written.wrapping_sub(ring.read.load(Ordering::Acquire));
+        let count = samples.len().min(free) & !1;
+
+        for (i, sample) in samples[..count].iter().enumerate() {
+            ring.samples[written.wrapping_add(i) % capacity]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        ring.written
+            .store(written.wrapping_add(count), Ordering::Release);
+
+        if count < samples.len() {
+            ring.overruns
+                .fetch_add(samples.len() - count, Ordering::Relaxed);
+        }
+        count
+    }
+
+    /// Number of samples waiting to be played
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.0.samples.len()
+    }
+
+    /// Total number of samples (underruns, overruns) since the creation of the ring buffer
+    pub fn stats(&self) -> (usize, usize) {
+        (
+            self.0.underruns.load(Ordering::Relaxed),
+            self.0.overruns.load(Ordering::Relaxed),
+        )
+    }
+}
+
+impl Consumer {
+    /// Fills `out` with the oldest samples, and silence if there isn't enough of them (counted
+    /// as underruns). Returns the number of samples taken from the ring.
+    pub fn pop(&self, out: &mut [f32]) -> usize {
+        let ring = &self.0;
+        let capacity = ring.samples.len();
+        let read = ring.read.load(Ordering::Relaxed);
+        let available = ring.written.load(Ordering::Acquire).wrapping_sub(read);
+        let count = out.len().min(available);
+
+        for (i, sample) in out[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(
+                ring.samples[read.wrapping_add(i) % capacity].load(Ordering::Relaxed),
+            );
+        }
+        ring.read.store(read.wrapping_add(count), Ordering::Release);
+
+        if count < out.len() {
+            out[count..].fill(0.);
+            ring.underruns
+                .fetch_add(out.len() - count, Ordering::Relaxed);
+        }
+        count
+    }
+
+    /// Number of samples waiting to be played
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
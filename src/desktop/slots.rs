@@ -0,0 +1,89 @@
+// Numbered save-state slots, kept next to the ROM as "<rom>.slot<n>.state". Each state has the
+// time it was saved and a thumbnail of the screen (see `save_state::save_with_info`).
+
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::canvas::{Canvas, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::save_state::{self, StateInfo};
+
+pub const SLOT_COUNT: u8 = 10;
+
+const INDICATOR_BACKGROUND: u32 = 0x1d1d28;
+const INDICATOR_TEXT: u32 = 0xe0e0e0;
+
+pub fn slot_path(rom: &str, slot: u8) -> String {
+    format!("{}.slot{}.state", rom, slot)
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |time| time.as_secs())
+}
+
+/// "YYYY-MM-DD HH:MM" in UTC
+pub fn format_timestamp(timestamp: u64) -> String {
+    let (days, seconds) = (timestamp / 86400, timestamp % 86400);
+
+    // Civil date from the days since 1970-01-01, with years starting in March
+    let days = days as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        (seconds / 60) % 60
+    )
+}
+
+/// Information of the state saved in a slot, None if the slot is empty
+pub fn slot_info(rom: &str, slot: u8) -> Option<Result<StateInfo, String>> {
+    match fs::read(slot_path(rom, slot)) {
+        Ok(data) => Some(save_state::read_info(&data)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => Some(Err(err.to_string())),
+    }
+}
+
+/// Short description of a slot for the indicator: the time it was saved or "EMPTY"
+pub fn slot_summary(rom: &str, slot: u8) -> String {
+    match slot_info(rom, slot) {
+        None => "EMPTY".to_string(),
+        Some(Ok(StateInfo {
+            timestamp: Some(timestamp),
+            ..
+        })) => format_timestamp(timestamp),
+        Some(_) => "SAVED".to_string(),
+    }
+}
+
+/// Draws a line of text at the top of the screen
+pub fn draw_indicator(fb: &mut [u32; 160 * 144], text: &str) {
+    let mut canvas = Canvas {
+        width: 160,
+        height: 144,
+        pixels: fb.to_vec(),
+    };
+    let width = (text.chars().count() * GLYPH_WIDTH + 3).min(160);
+    canvas.fill_rect(0, 0, width, GLYPH_HEIGHT + 3, INDICATOR_BACKGROUND);
+    canvas.text(2, 2, text, INDICATOR_TEXT);
+    fb.copy_from_slice(&canvas.pixels);
+}
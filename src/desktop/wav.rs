@@ -0,0 +1,119 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::audio::Capture;
+
+const HEADER_SIZE: u32 = 44;
+
+/// 16 bits PCM WAV file writer. The sizes in the header are kept up to date by `update_header`
+/// so the file stays readable if the emulator is killed before `finish`.
+pub struct WavWriter {
+    file: BufWriter<File>,
+    channels: u16,
+    data_size: u32,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+
+        let block_align = channels * 2;
+        file.write_all(b"RIFF")?;
+        file.write_all(&0u32.to_le_bytes())?;
+        file.write_all(b"WAVEfmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?; // PCM
+        file.write_all(&channels.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        file.write_all(&block_align.to_le_bytes())?;
+        file.write_all(&16u16.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            file,
+            channels,
+            data_size: 0,
+        })
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Writes samples between -1 and 1 (interleaved if there are multiple channels)
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for sample in samples {
+            let value = (sample.clamp(-1., 1.) * i16::MAX as f32) as i16;
+            self.file.write_all(&value.to_le_bytes())?;
+        }
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn update_header(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file
+            .write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(40))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.update_header()?;
+        self.file.flush()
+    }
+}
+
+/// Writes the audio captured from the APU into a stereo WAV file, and optionally each channel
+/// alone into `<name>-ch1.wav` to `<name>-ch4.wav` next to it
+pub struct WavExport {
+    mix: WavWriter,
+    stems: Option<Vec<WavWriter>>,
+}
+
+impl WavExport {
+    pub fn create(path: &str, stems: bool, sample_rate: u32) -> io::Result<Self> {
+        let mix = WavWriter::create(path, 2, sample_rate)?;
+
+        let stems = if stems {
+            let path = Path::new(path);
+            let name = path.file_stem().and_then(|s| s.to_str()).unwrap_or("audio");
+            let writers = (1..=4)
+                .map(|i| {
+                    let stem = path.with_file_name(format!("{}-ch{}.wav", name, i));
+                    WavWriter::create(stem.to_str().unwrap(), 2, sample_rate)
+                })
+                .collect::<io::Result<_>>()?;
+            Some(writers)
+        } else {
+            None
+        };
+
+        Ok(Self { mix, stems })
+    }
+
+    pub fn write(&mut self, capture: &Capture) -> io::Result<()> {
+        self.mix.write_samples(&capture.mix)?;
+        self.mix.update_header()?;
+
+        if let (Some(writers), Some(stems)) = (&mut self.stems, &capture.stems) {
+            for (writer, samples) in writers.iter_mut().zip(stems) {
+                writer.write_samples(samples)?;
+                writer.update_header()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        for writer in self.stems.into_iter().flatten() {
+            writer.finish()?;
+        }
+        self.mix.finish()
+    }
+}
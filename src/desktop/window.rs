@@ -3,13 +3,17 @@ use std::sync::mpsc::{channel, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 
+use crate::canvas::Canvas;
+use crate::display::Layer;
+use crate::filters::Filter;
 use crate::io::{Window, WindowSignal};
-use crate::logs::{elog, LogLevel};
+use crate::logs::{elog, log, LogLevel};
+use crate::{audio_viewer, vram_viewer};
 
 use pixels::{Error, Pixels, SurfaceTexture};
 use winit::dpi::LogicalSize;
 use winit::event::{Event, WindowEvent};
-use winit::event_loop::EventLoopBuilder;
+use winit::event_loop::{EventLoop, EventLoopBuilder};
 use winit::keyboard::{KeyCode, PhysicalKey};
 
 #[cfg(all(unix))]
@@ -37,17 +41,101 @@ impl Window for Headless {
 
 #[cfg(not(target_family = "wasm"))]
 pub struct DesktopWindow {
-    fb_send: Sender<Box<[u32; 160 * 144]>>,
+    // Screens side by side
+    fb_send: Sender<Box<[u32]>>,
     signal_recv: Receiver<WindowSignal>,
     pub keys: Keys,
+    // Images to show in the VRAM viewer window (see `vram_viewer::render`), if it was opened
+    pub viewer: Option<Sender<Canvas>>,
+    // Images to show in the audio viewer window (see `audio_viewer::render`), if it was opened
+    pub audio_viewer: Option<Sender<Canvas>>,
+}
+
+// Debug window showing the last image received
+#[cfg(not(target_family = "wasm"))]
+struct ViewerWindow {
+    window: Arc<winit::window::Window>,
+    pixels: Pixels<'static>,
+    images: Receiver<Canvas>,
+    image: Canvas,
+}
+
+#[cfg(not(target_family = "wasm"))]
+impl ViewerWindow {
+    fn new(
+        event_loop: &EventLoop<()>,
+        title: String,
+        width: usize,
+        height: usize,
+        images: Receiver<Canvas>,
+    ) -> Self {
+        let size = LogicalSize::new(width as f64, height as f64);
+        let window = Arc::new(
+            WindowBuilder::new()
+                .with_title(title)
+                .with_inner_size(size)
+                .with_min_inner_size(size)
+                .build(event_loop)
+                .unwrap(),
+        );
+        let window_size = window.inner_size();
+        let surface_texture =
+            SurfaceTexture::new(window_size.width, window_size.height, window.clone());
+        let pixels = Pixels::new(width as u32, height as u32, surface_texture).unwrap();
+
+        Self {
+            window,
+            pixels,
+            images,
+            image: Canvas::new(width, height, 0),
+        }
+    }
+
+    // Returns true if the window has to be closed
+    fn handle(&mut self, event: &WindowEvent) -> bool {
+        match event {
+            WindowEvent::RedrawRequested => {
+                while let Ok(image) = self.images.try_recv() {
+                    self.image = image;
+                }
+                draw(self.pixels.frame_mut(), &self.image.pixels);
+                if let Err(err) = self.pixels.render() {
+                    elog(
+                        LogLevel::Error,
+                        format!("Error during viewer render: {}", err),
+                    );
+                }
+            }
+            WindowEvent::Resized(size) => {
+                if let Err(err) = self.pixels.resize_surface(size.width, size.height) {
+                    elog(
+                        LogLevel::Error,
+                        format!("Error during viewer resize: {}", err),
+                    );
+                }
+            }
+            WindowEvent::CloseRequested => return true,
+            _ => {}
+        }
+        false
+    }
 }
 
 #[cfg(not(target_family = "wasm"))]
 impl DesktopWindow {
-    pub fn new(title: impl Into<String>) -> Result<Self, Error> {
+    pub fn new(
+        title: impl Into<String>,
+        filter: Filter,
+        vram_viewer: bool,
+        audio_viewer: bool,
+        screens: u32,
+    ) -> Result<Self, Error> {
         let title: String = title.into();
+        let width = WIDTH * screens;
         let (fb_send, fb_recv) = channel();
         let (signal_send, signal_recv) = channel();
+        let (viewer_send, viewer_recv) = channel::<Canvas>();
+        let (audio_viewer_send, audio_viewer_recv) = channel::<Canvas>();
 
         let keys = Arc::new(Mutex::new(HashSet::new()));
 
@@ -60,43 +148,80 @@ impl DesktopWindow {
                 .unwrap();
             let mut input = WinitInputHelper::new();
             let window = Arc::new({
-                let size = LogicalSize::new((WIDTH * 4) as f64, (HEIGHT * 4) as f64);
+                let size = LogicalSize::new((width * 4) as f64, (HEIGHT * 4) as f64);
                 WindowBuilder::new()
-                    .with_title(title)
+                    .with_title(title.clone())
                     .with_inner_size(size)
                     .with_min_inner_size(size)
                     .build(&event_loop)
                     .unwrap()
             });
 
+            let mut viewers = vec![];
+            if vram_viewer {
+                viewers.push(ViewerWindow::new(
+                    &event_loop,
+                    format!("{} - VRAM viewer", title),
+                    vram_viewer::WIDTH,
+                    vram_viewer::HEIGHT,
+                    viewer_recv,
+                ));
+            }
+            if audio_viewer {
+                viewers.push(ViewerWindow::new(
+                    &event_loop,
+                    format!("{} - Audio viewer", title),
+                    audio_viewer::WIDTH,
+                    audio_viewer::HEIGHT,
+                    audio_viewer_recv,
+                ));
+            }
+
+            let mut filter = filter;
+            let mut solo_sprite: Option<usize> = None;
+            let mut scale = filter.scale() as u32;
             let mut pixels = {
                 let window_size = window.inner_size();
                 let surface_texture =
                     SurfaceTexture::new(window_size.width, window_size.height, window.clone());
-                Pixels::new(WIDTH, HEIGHT, surface_texture).unwrap()
+                Pixels::new(width * scale, HEIGHT * scale, surface_texture).unwrap()
             };
-            let mut fb = Box::new([0; 160 * 144]);
+            let mut fb: Box<[u32]> = vec![0; (width * HEIGHT) as usize].into_boxed_slice();
             event_loop
                 .run(|event, elwt| {
                     elwt.set_control_flow(winit::event_loop::ControlFlow::wait_duration(
                         Duration::from_micros(1000000 / 60),
                     ));
                     if let Event::WindowEvent {
+                        window_id,
                         event: WindowEvent::RedrawRequested,
-                        ..
                     } = event
                     {
-                        loop {
-                            if let Ok(new_fb) = fb_recv.try_recv() {
-                                fb = new_fb;
-                            } else {
-                                break;
+                        if window_id == window.id() {
+                            loop {
+                                if let Ok(new_fb) = fb_recv.try_recv() {
+                                    fb = new_fb;
+                                } else {
+                                    break;
+                                }
+                            }
+                            if filter.scale() as u32 != scale {
+                                scale = filter.scale() as u32;
+                                if let Err(err) =
+                                    pixels.resize_buffer(width * scale, HEIGHT * scale)
+                                {
+                                    elog(LogLevel::Error, format!("Error during resize: {}", err));
+                                    return;
+                                }
+                            }
+                            draw(
+                                pixels.frame_mut(),
+                                &filter.apply(fb.as_ref(), width as usize, HEIGHT as usize),
+                            );
+                            if let Err(err) = pixels.render() {
+                                elog(LogLevel::Error, format!("Error during render: {}", err));
+                                return;
                             }
-                        }
-                        draw(pixels.frame_mut(), &fb);
-                        if let Err(err) = pixels.render() {
-                            elog(LogLevel::Error, format!("Error during render: {}", err));
-                            return;
                         }
                     }
 
@@ -119,6 +244,107 @@ impl DesktopWindow {
                                 }
                             }
                         }
+
+                        if keyboard_event.state.is_pressed() && !keyboard_event.repeat {
+                            let signal = match keyboard_event.physical_key {
+                                PhysicalKey::Code(KeyCode::F2) => {
+                                    filter = filter.next();
+                                    log(LogLevel::Infos, format!("Filter: {}", filter));
+                                    Some(WindowSignal::Filter(filter))
+                                }
+                                PhysicalKey::Code(KeyCode::F5) => {
+                                    Some(WindowSignal::ToggleLayer(Layer::Background))
+                                }
+                                PhysicalKey::Code(KeyCode::F6) => {
+                                    Some(WindowSignal::ToggleLayer(Layer::Window))
+                                }
+                                PhysicalKey::Code(KeyCode::F7) => {
+                                    Some(WindowSignal::ToggleLayer(Layer::Objects))
+                                }
+                                PhysicalKey::Code(KeyCode::F8) => {
+                                    // Cycles between all the sprites and each one alone,
+                                    // backward with shift
+                                    let shift = keys.lock().is_ok_and(|keys| {
+                                        keys.contains(&KeyCode::ShiftLeft)
+                                            || keys.contains(&KeyCode::ShiftRight)
+                                    });
+                                    solo_sprite = match (solo_sprite, shift) {
+                                        (None, false) => Some(0),
+                                        (None, true) => Some(39),
+                                        (Some(39), false) | (Some(0), true) => None,
+                                        (Some(i), false) => Some(i + 1),
+                                        (Some(i), true) => Some(i - 1),
+                                    };
+                                    Some(WindowSignal::SoloSprite(solo_sprite))
+                                }
+                                PhysicalKey::Code(KeyCode::F9) => Some(WindowSignal::ToggleVgmLog),
+                                PhysicalKey::Code(KeyCode::F10) => {
+                                    Some(WindowSignal::ToggleHiddenLayerColor)
+                                }
+                                PhysicalKey::Code(KeyCode::F12) => Some(WindowSignal::Screenshot),
+                                PhysicalKey::Code(KeyCode::PageUp) => Some(WindowSignal::NextTrack),
+                                PhysicalKey::Code(KeyCode::PageDown) => {
+                                    Some(WindowSignal::PreviousTrack)
+                                }
+                                PhysicalKey::Code(KeyCode::F3) => Some(WindowSignal::SaveSlot),
+                                PhysicalKey::Code(KeyCode::F4) => Some(WindowSignal::LoadSlot),
+                                PhysicalKey::Code(code) if digit(code).is_some() => {
+                                    // 0-9 selects the save-state slot, Ctrl+1-4 mutes CH1-CH4,
+                                    // Ctrl+Shift+1-4 plays them solo and Alt+1-4 changes their
+                                    // volume
+                                    let digit = digit(code).unwrap();
+                                    let pressed = |left, right| {
+                                        keys.lock().is_ok_and(|keys| {
+                                            keys.contains(&left) || keys.contains(&right)
+                                        })
+                                    };
+                                    let ctrl = pressed(KeyCode::ControlLeft, KeyCode::ControlRight);
+                                    let shift = pressed(KeyCode::ShiftLeft, KeyCode::ShiftRight);
+                                    let alt = pressed(KeyCode::AltLeft, KeyCode::AltRight);
+                                    let channel = (digit as usize).wrapping_sub(1);
+                                    match (ctrl, shift, alt) {
+                                        (false, false, false) => {
+                                            Some(WindowSignal::SelectSlot(digit))
+                                        }
+                                        (true, false, false) if channel < 4 => {
+                                            Some(WindowSignal::ToggleChannelMute(channel))
+                                        }
+                                        (true, true, false) if channel < 4 => {
+                                            Some(WindowSignal::ToggleChannelSolo(channel))
+                                        }
+                                        (false, false, true) if channel < 4 => {
+                                            Some(WindowSignal::CycleChannelVolume(channel))
+                                        }
+                                        _ => None,
+                                    }
+                                }
+                                _ => None,
+                            };
+
+                            if let Some(signal) = signal {
+                                if let Err(err) = signal_send.send(signal) {
+                                    elog(
+                                        LogLevel::Error,
+                                        format!("window signal send failed with error {}", err),
+                                    );
+                                }
+                            }
+                        }
+                    }
+
+                    // The viewer windows only get the keyboard handling above, so they can be
+                    // closed without stopping the emulator
+                    if let Event::WindowEvent {
+                        window_id,
+                        event: ref viewer_event,
+                    } = event
+                    {
+                        if let Some(i) = viewers.iter().position(|v| v.window.id() == window_id) {
+                            if viewers[i].handle(viewer_event) {
+                                viewers.remove(i);
+                            }
+                            return;
+                        }
                     }
 
                     if input.update(&event) {
@@ -141,6 +367,9 @@ impl DesktopWindow {
                         }
 
                         window.request_redraw();
+                        for viewer in &viewers {
+                            viewer.window.request_redraw();
+                        }
                     }
                 })
                 .unwrap();
@@ -150,6 +379,12 @@ impl DesktopWindow {
             fb_send,
             signal_recv,
             keys,
+            viewer: if vram_viewer { Some(viewer_send) } else { None },
+            audio_viewer: if audio_viewer {
+                Some(audio_viewer_send)
+            } else {
+                None
+            },
         })
     }
 }
@@ -170,10 +405,50 @@ impl Window for DesktopWindow {
             None
         }
     }
+
+    // The window must have been created with 2 screens
+    fn update_linked(
+        &mut self,
+        fb: Box<[u32; 160 * 144]>,
+        fb2: Box<[u32; 160 * 144]>,
+    ) -> Option<WindowSignal> {
+        let mut screens = Vec::with_capacity(160 * 144 * 2);
+        for (line, line2) in fb.chunks_exact(160).zip(fb2.chunks_exact(160)) {
+            screens.extend_from_slice(line);
+            screens.extend_from_slice(line2);
+        }
+        if let Err(err) = self.fb_send.send(screens.into_boxed_slice()) {
+            elog(
+                LogLevel::Error,
+                format!("Framebuffer channel send failed with error: {}", err),
+            );
+        }
+
+        self.signal_recv.try_recv().ok()
+    }
 }
 
-pub fn draw(frame: &mut [u8], fb: &[u32; 160 * 144]) {
+pub fn draw(frame: &mut [u8], fb: &[u32]) {
     for (i, pixel) in frame.chunks_exact_mut(4).enumerate() {
         pixel.copy_from_slice(&((fb[i] << 8) | 0xff).to_be_bytes())
     }
 }
+
+// Value of the digit keys of the main keyboard
+fn digit(code: KeyCode) -> Option<u8> {
+    [
+        KeyCode::Digit0,
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ]
+    .iter()
+    .position(|key| *key == code)
+    .map(|digit| digit as u8)
+}
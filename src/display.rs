@@ -1,6 +1,7 @@
 // Very readable, much clean wow.
 
 use crate::consts::DISPLAY_UPDATE_SLEEP_TIME_MICROS;
+use crate::save_state::{StateReader, StateWriter};
 #[cfg(target_family = "wasm")]
 use crate::wasm::utils::SystemTime;
 use std::mem;
@@ -9,9 +10,22 @@ use std::time::SystemTime;
 
 const COLORS: [u32; 4] = [0x00e0f8d0, 0x0088c070, 0x346856, 0x00081820];
 
+pub const DMG_PALETTES: [(&str, [u32; 4]); 3] = [
+    ("green", COLORS),
+    ("grey", [0x00ffffff, 0x00aaaaaa, 0x00555555, 0x00000000]),
+    ("pocket", [0x00c4cfa1, 0x008b956d, 0x004d533c, 0x001f1f1f]),
+];
+
+pub fn dmg_palette(name: &str) -> Option<[u32; 4]> {
+    DMG_PALETTES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, colors)| *colors)
+}
+
 const LINE_DOTS: u64 = 456;
 
-mod lcdc_flags {
+pub mod lcdc_flags {
     pub const _BG_PRIORITY: u8 = 0b1;
     pub const OBJ_ENABLE: u8 = 0b10;
     pub const OBJ_SIZE: u8 = 0b100;
@@ -22,6 +36,40 @@ mod lcdc_flags {
     pub const LCD_ENABLE: u8 = 0b10000000;
 }
 
+// Color of the hidden layers when `Layers::hidden_color` is used
+pub const HIDDEN_LAYER_DEBUG_COLOR: u32 = 0x00ff00ff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Background,
+    Window,
+    Objects,
+}
+
+/// Debug switches to hide layers or sprites without changing LCDC
+#[derive(Debug, Clone)]
+pub struct Layers {
+    pub bg: bool,
+    pub window: bool,
+    pub obj: bool,
+    // Indexed by OAM entry
+    pub sprites: [bool; 40],
+    // Hidden layers are transparent if None, drawn with this color otherwise
+    pub hidden_color: Option<u32>,
+}
+
+impl Default for Layers {
+    fn default() -> Self {
+        Self {
+            bg: true,
+            window: true,
+            obj: true,
+            sprites: [true; 40],
+            hidden_color: None,
+        }
+    }
+}
+
 pub enum DisplayInterrupt {
     Vblank,
     Stat,
@@ -52,6 +100,14 @@ pub struct Display {
 
     pub cgb_mode: bool,
 
+    pub layers: Layers,
+
+    // Colors used for the 4 shades in DMG mode
+    pub dmg_colors: [u32; 4],
+
+    // Number of frames completed since the start (incremented when entering VBlank)
+    pub frame_count: u64,
+
     pub window_x: u8,
     pub window_y: u8,
     pub window_internal_line_counter: u8,
@@ -92,13 +148,70 @@ impl Display {
             stat: 0,
             lyc: 0,
             cgb_mode: false,
+            layers: Layers::default(),
+            dmg_colors: COLORS,
+            frame_count: 0,
             lcd_interrupt_mode: 0xff,
             redraw_request: None,
         }
     }
 
     pub fn cls(&mut self) {
-        self.framebuffer = Box::new([COLORS[0]; 160 * 144]);
+        self.framebuffer = Box::new([self.dmg_colors[0]; 160 * 144]);
+    }
+
+    pub fn framebuffer(&self) -> &[u32; 160 * 144] {
+        &self.framebuffer
+    }
+
+    // Read-only access to the video memory for the debug viewers
+    pub fn tiledata(&self) -> &[u8; 0x3000] {
+        &self.tiledata
+    }
+
+    pub fn tilemaps(&self) -> &[u8; 0x800] {
+        &self.tilemaps
+    }
+
+    pub fn bg_map_attr(&self) -> &[u8; 0x400] {
+        &self.bg_map_attr
+    }
+
+    pub fn oam(&self) -> &[u8; 0xa0] {
+        &self.oam
+    }
+
+    pub fn layer_visible(&self, layer: Layer) -> bool {
+        match layer {
+            Layer::Background => self.layers.bg,
+            Layer::Window => self.layers.window,
+            Layer::Objects => self.layers.obj,
+        }
+    }
+
+    pub fn set_layer_visible(&mut self, layer: Layer, visible: bool) {
+        match layer {
+            Layer::Background => self.layers.bg = visible,
+            Layer::Window => self.layers.window = visible,
+            Layer::Objects => self.layers.obj = visible,
+        }
+    }
+
+    pub fn toggle_layer(&mut self, layer: Layer) -> bool {
+        let visible = !self.layer_visible(layer);
+        self.set_layer_visible(layer, visible);
+        visible
+    }
+
+    pub fn set_sprite_visible(&mut self, index: usize, visible: bool) {
+        self.layers.sprites[index] = visible;
+    }
+
+    /// Only shows the sprite of this OAM index, or all of them if None
+    pub fn solo_sprite(&mut self, index: Option<usize>) {
+        for (i, visible) in self.layers.sprites.iter_mut().enumerate() {
+            *visible = index.is_none() || index == Some(i);
+        }
     }
 
     pub fn color_palette(&self, color_byte: u8, palette: u8, cgb_mode: bool) -> u32 {
@@ -113,7 +226,7 @@ impl Display {
 
             (red << 16) | (green << 8) | blue
         } else {
-            COLORS[((palette >> (color_byte << 1)) & 0b11) as usize]
+            self.dmg_colors[((palette >> (color_byte << 1)) & 0b11) as usize]
         }
     }
 
@@ -198,6 +311,20 @@ impl Display {
             0
         };
 
+        if !self.layers.bg {
+            if self.ly < 144 {
+                let color = self.layers.hidden_color.unwrap_or(self.color_palette(
+                    0,
+                    if self.cgb_mode { 0 } else { self.bg_palette },
+                    self.cgb_mode,
+                ));
+                let line = self.ly as usize * 160;
+                self.framebuffer[line..line + 160].fill(color);
+                self.bg_buffer[line..line + 160].fill(0);
+            }
+            return;
+        }
+
         let y_tile = (self.ly + self.viewport_y) as usize;
 
         for x in 0..32 {
@@ -228,6 +355,18 @@ impl Display {
             return;
         }
 
+        if !self.layers.window {
+            if let (Some(color), true) = (self.layers.hidden_color, self.ly < 144) {
+                let line = self.ly as usize * 160;
+                let start = (self.window_x as usize).max(7) - 7;
+                if start < 160 {
+                    self.framebuffer[line + start..line + 160].fill(color);
+                }
+            }
+            self.window_internal_line_counter += 1;
+            return;
+        }
+
         let tilemap_y_px = (self.ly - self.window_y) as usize;
         let y_tile = tilemap_y_px / 8;
         let tile_y_px = tilemap_y_px % 8;
@@ -260,6 +399,10 @@ impl Display {
             let x = self.oam[o * 4 + 1];
             let mut tile = self.oam[o * 4 + 2];
             let opts = self.oam[o * 4 + 3];
+            let hidden = !self.layers.obj || !self.layers.sprites[o];
+            if hidden && self.layers.hidden_color.is_none() {
+                continue;
+            }
             let bg_priority_flag = opts & 0b10000000 != 0;
             let x_flip = opts & 0b100000 != 0;
             let y_flip = opts & 0b1000000 != 0;
@@ -306,15 +449,19 @@ impl Display {
                         && !((bg_priority_flag/* && self.lcdc & lcdc_flags::BG_PRIORITY != 0 */)
                             && self.bg_buffer[pxy as usize * 160 + pxx as usize] != 0)
                     {
-                        self.framebuffer[pxy as usize * 160 + pxx as usize] = self.color_palette(
-                            data,
-                            if self.cgb_mode {
-                                cgb_palette + 8
-                            } else {
-                                self.obj_palettes[palette as usize]
-                            },
-                            self.cgb_mode,
-                        );
+                        self.framebuffer[pxy as usize * 160 + pxx as usize] =
+                            match (hidden, self.layers.hidden_color) {
+                                (true, Some(color)) => color,
+                                _ => self.color_palette(
+                                    data,
+                                    if self.cgb_mode {
+                                        cgb_palette + 8
+                                    } else {
+                                        self.obj_palettes[palette as usize]
+                                    },
+                                    self.cgb_mode,
+                                ),
+                            };
                     }
                 }
             }
@@ -331,6 +478,7 @@ impl Display {
             self.ly = (self.ly + 1) % 154;
             self.stat %= LINE_DOTS;
             if self.ly == 0x90 {
+                self.frame_count += 1;
                 ret_interrupt = DisplayInterrupt::Vblank;
                 if self.lcd_interrupt_mode == 1 {
                     ret_interrupt = DisplayInterrupt::Both;
@@ -370,3 +518,64 @@ impl Display {
         result
     }
 }
+
+impl Display {
+    /// Writes the state of the PPU, VRAM and OAM (see `save_state`)
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.bytes(self.tiledata.as_ref());
+        w.bytes(self.bg_map_attr.as_ref());
+        w.bytes(self.tilemaps.as_ref());
+        w.bytes(self.oam.as_ref());
+        w.bytes(self.cram.as_ref());
+        w.u8(self.bg_palette);
+        w.bytes(&self.obj_palettes);
+        w.u8(self.viewport_y);
+        w.u8(self.viewport_x);
+        w.u8(self.lcdc);
+        w.u8(self.ly);
+        w.u8(self.lyc);
+        w.u8(self.lcd_interrupt_mode);
+        w.u8(self.vram_bank);
+        w.bool(self.cgb_mode);
+        w.u8(self.window_x);
+        w.u8(self.window_y);
+        w.u8(self.window_internal_line_counter);
+        w.u64(self.stat);
+        w.u64(self.frame_count);
+        // The lines already drawn in the current frame
+        for pixel in self.framebuffer.iter() {
+            w.u32(*pixel);
+        }
+        w.bytes(self.bg_buffer.as_ref());
+    }
+
+    pub fn read_state(&mut self, r: &mut StateReader) {
+        r.bytes(self.tiledata.as_mut());
+        r.bytes(self.bg_map_attr.as_mut());
+        r.bytes(self.tilemaps.as_mut());
+        r.bytes(self.oam.as_mut());
+        r.bytes(self.cram.as_mut());
+        self.bg_palette = r.u8();
+        r.bytes(&mut self.obj_palettes);
+        self.viewport_y = r.u8();
+        self.viewport_x = r.u8();
+        self.lcdc = r.u8();
+        self.ly = r.u8() % 154;
+        self.lyc = r.u8();
+        self.lcd_interrupt_mode = r.u8();
+        self.vram_bank = r.u8() & 1;
+        self.cgb_mode = r.bool();
+        self.window_x = r.u8();
+        self.window_y = r.u8();
+        self.window_internal_line_counter = r.u8();
+        self.stat = r.u64();
+        self.frame_count = r.u64();
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = r.u32();
+        }
+        r.bytes(self.bg_buffer.as_mut());
+
+        // Shows the loaded screen without waiting for the next frame
+        self.redraw_request = Some(self.framebuffer.clone());
+    }
+}
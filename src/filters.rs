@@ -0,0 +1,322 @@
+// Software upscaling filters. Everything works on 0x00RRGGBB u32 buffers so it runs without
+// a GPU and can be reused by the window, screenshots, recordings and the wasm frontend.
+
+use std::fmt;
+use std::str::FromStr;
+
+const FILTERS: [Filter; 8] = [
+    Filter::None,
+    Filter::Scale2x,
+    Filter::Scale3x,
+    Filter::Hq2x,
+    Filter::XbrLite,
+    Filter::Scanlines,
+    Filter::DotMatrix,
+    Filter::DmgGrid,
+];
+
+// Thresholds used by hqx to decide if two colors are "different"
+const HQX_Y_THRESHOLD: i32 = 48;
+const HQX_U_THRESHOLD: i32 = 7;
+const HQX_V_THRESHOLD: i32 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    None,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    XbrLite,
+    Scanlines,
+    DotMatrix,
+    DmgGrid,
+}
+
+impl Filter {
+    pub fn scale(&self) -> usize {
+        match self {
+            Filter::None => 1,
+            Filter::Scale2x | Filter::Hq2x | Filter::XbrLite | Filter::Scanlines => 2,
+            Filter::Scale3x | Filter::DotMatrix | Filter::DmgGrid => 3,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Filter::None => "none",
+            Filter::Scale2x => "scale2x",
+            Filter::Scale3x => "scale3x",
+            Filter::Hq2x => "hq2x",
+            Filter::XbrLite => "xbr-lite",
+            Filter::Scanlines => "scanlines",
+            Filter::DotMatrix => "dot-matrix",
+            Filter::DmgGrid => "dmg-grid",
+        }
+    }
+
+    /// The filter after this one, used to cycle through them with a hotkey
+    pub fn next(&self) -> Filter {
+        let i = FILTERS.iter().position(|f| f == self).unwrap_or(0);
+        FILTERS[(i + 1) % FILTERS.len()]
+    }
+
+    /// Returns a new buffer of `width * scale()` by `height * scale()` pixels
+    pub fn apply(&self, src: &[u32], width: usize, height: usize) -> Vec<u32> {
+        let img = Image { src, width, height };
+        let scale = self.scale();
+        let mut dst = vec![0; width * scale * height * scale];
+
+        for y in 0..height {
+            for x in 0..width {
+                let block = match self {
+                    Filter::None => [img.px(x, y, 0, 0); 9],
+                    Filter::Scale2x => img.scale2x(x, y),
+                    Filter::Scale3x => img.scale3x(x, y),
+                    Filter::Hq2x => img.hq2x(x, y),
+                    Filter::XbrLite => img.xbr_lite(x, y),
+                    Filter::Scanlines => scanlines(img.px(x, y, 0, 0)),
+                    Filter::DotMatrix => dot_matrix(img.px(x, y, 0, 0)),
+                    Filter::DmgGrid => dmg_grid(img.px(x, y, 0, 0)),
+                };
+
+                for by in 0..scale {
+                    let line = (y * scale + by) * width * scale;
+                    for bx in 0..scale {
+                        dst[line + x * scale + bx] = block[by * scale + bx];
+                    }
+                }
+            }
+        }
+
+        dst
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FILTERS
+            .iter()
+            .find(|f| f.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                format!(
+                    "Unknown filter \"{}\" (possible values: {})",
+                    s,
+                    FILTERS.map(|f| f.name()).join(",")
+                )
+            })
+    }
+}
+
+struct Image<'a> {
+    src: &'a [u32],
+    width: usize,
+    height: usize,
+}
+
+impl Image<'_> {
+    // Pixel at (x + dx, y + dy), clamped to the edges of the image
+    fn px(&self, x: usize, y: usize, dx: isize, dy: isize) -> u32 {
+        let x = (x as isize + dx).clamp(0, self.width as isize - 1) as usize;
+        let y = (y as isize + dy).clamp(0, self.height as isize - 1) as usize;
+        self.src[y * self.width + x]
+    }
+
+    // The blocks are always 3x3 so they can be returned without allocating. 2x filters only
+    // fill the first 4 values (row-major 2x2).
+
+    fn scale2x(&self, x: usize, y: usize) -> [u32; 9] {
+        let b = self.px(x, y, 0, -1);
+        let d = self.px(x, y, -1, 0);
+        let e = self.px(x, y, 0, 0);
+        let f = self.px(x, y, 1, 0);
+        let h = self.px(x, y, 0, 1);
+
+        let mut out = [e; 9];
+        if b != h && d != f {
+            out[0] = if d == b { d } else { e };
+            out[1] = if b == f { f } else { e };
+            out[2] = if d == h { d } else { e };
+            out[3] = if h == f { f } else { e };
+        }
+        out
+    }
+
+    fn scale3x(&self, x: usize, y: usize) -> [u32; 9] {
+        let a = self.px(x, y, -1, -1);
+        let b = self.px(x, y, 0, -1);
+        let c = self.px(x, y, 1, -1);
+        let d = self.px(x, y, -1, 0);
+        let e = self.px(x, y, 0, 0);
+        let f = self.px(x, y, 1, 0);
+        let g = self.px(x, y, -1, 1);
+        let h = self.px(x, y, 0, 1);
+        let i = self.px(x, y, 1, 1);
+
+        let mut out = [e; 9];
+        if b != h && d != f {
+            out[0] = if d == b { d } else { e };
+            out[1] = if (d == b && e != c) || (b == f && e != a) {
+                b
+            } else {
+                e
+            };
+            out[2] = if b == f { f } else { e };
+            out[3] = if (d == b && e != g) || (d == h && e != a) {
+                d
+            } else {
+                e
+            };
+            out[5] = if (b == f && e != i) || (h == f && e != c) {
+                f
+            } else {
+                e
+            };
+            out[6] = if d == h { d } else { e };
+            out[7] = if (d == h && e != i) || (h == f && e != g) {
+                h
+            } else {
+                e
+            };
+            out[8] = if h == f { f } else { e };
+        }
+        out
+    }
+
+    // A rule based version of hq2x: it uses the same YUV "different" test but instead of the
+    // 256 entries pattern table each quadrant only looks at its two sides and its corner.
+    fn hq2x(&self, x: usize, y: usize) -> [u32; 9] {
+        let e = self.px(x, y, 0, 0);
+        let mut out = [e; 9];
+
+        for (i, (dx, dy)) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].into_iter().enumerate() {
+            let side_x = self.px(x, y, dx, 0);
+            let side_y = self.px(x, y, 0, dy);
+            let corner = self.px(x, y, dx, dy);
+
+            out[i] = if hqx_different(e, side_x)
+                && hqx_different(e, side_y)
+                && !hqx_different(side_x, side_y)
+            {
+                mix(&[(e, 2), (side_x, 1), (side_y, 1)])
+            } else if hqx_different(e, corner) && !hqx_different(side_x, side_y) {
+                mix(&[(e, 3), (corner, 1)])
+            } else {
+                e
+            };
+        }
+        out
+    }
+
+    // Level 1 xBR at 2x. The neighbourhood is mirrored for each corner so the same edge
+    // detection rule can be used for the 4 output pixels.
+    fn xbr_lite(&self, x: usize, y: usize) -> [u32; 9] {
+        let e = self.px(x, y, 0, 0);
+        let mut out = [e; 9];
+
+        for (i, (sx, sy)) in [(-1, -1), (1, -1), (-1, 1), (1, 1)].into_iter().enumerate() {
+            let q = |i: isize, j: isize| self.px(x, y, i * sx, j * sy);
+
+            let (b, c, d, f) = (q(0, -1), q(1, -1), q(-1, 0), q(1, 0));
+            let (g, h, i_) = (q(-1, 1), q(0, 1), q(1, 1));
+            let (f4, i4, h5, i5) = (q(2, 0), q(2, 1), q(0, 2), q(1, 2));
+
+            let edge_across = yuv_distance(e, c)
+                + yuv_distance(e, g)
+                + yuv_distance(i_, f4)
+                + yuv_distance(i_, h5)
+                + 4 * yuv_distance(h, f);
+            let edge_along = yuv_distance(h, d)
+                + yuv_distance(h, i5)
+                + yuv_distance(f, i4)
+                + yuv_distance(f, b)
+                + 4 * yuv_distance(e, i_);
+
+            if edge_across < edge_along && e != f && e != h {
+                let new = if yuv_distance(e, f) <= yuv_distance(e, h) {
+                    f
+                } else {
+                    h
+                };
+                out[i] = mix(&[(e, 1), (new, 1)]);
+            }
+        }
+        out
+    }
+}
+
+fn scanlines(c: u32) -> [u32; 9] {
+    let dark = shade(c, 5, 8);
+    [c, c, dark, dark, 0, 0, 0, 0, 0]
+}
+
+fn dot_matrix(c: u32) -> [u32; 9] {
+    let edge = shade(c, 3, 4);
+    let corner = shade(c, 1, 2);
+    [c, c, edge, c, c, edge, edge, edge, corner]
+}
+
+// The DMG screen has light gaps between its (darker) pixels
+fn dmg_grid(c: u32) -> [u32; 9] {
+    let gap = mix(&[(c, 1), (0xffffff, 1)]);
+    let corner = mix(&[(c, 1), (0xffffff, 3)]);
+    [c, c, gap, c, c, gap, gap, gap, corner]
+}
+
+fn channels(c: u32) -> [i32; 3] {
+    [
+        ((c >> 16) & 0xff) as i32,
+        ((c >> 8) & 0xff) as i32,
+        (c & 0xff) as i32,
+    ]
+}
+
+fn shade(c: u32, num: i32, den: i32) -> u32 {
+    let [r, g, b] = channels(c);
+    (((r * num / den) as u32) << 16) | (((g * num / den) as u32) << 8) | (b * num / den) as u32
+}
+
+fn mix(colors: &[(u32, i32)]) -> u32 {
+    let mut sum = [0; 3];
+    let mut total = 0;
+    for (c, w) in colors {
+        for (s, v) in sum.iter_mut().zip(channels(*c)) {
+            *s += v * w;
+        }
+        total += w;
+    }
+    ((sum[0] / total) as u32) << 16 | ((sum[1] / total) as u32) << 8 | (sum[2] / total) as u32
+}
+
+fn yuv(c: u32) -> [i32; 3] {
+    let [r, g, b] = channels(c);
+    [
+        (299 * r + 587 * g + 114 * b) / 1000,
+        (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+        (500 * r - 419 * g - 81 * b) / 1000 + 128,
+    ]
+}
+
+fn hqx_different(a: u32, b: u32) -> bool {
+    let [ay, au, av] = yuv(a);
+    let [by, bu, bv] = yuv(b);
+    (ay - by).abs() > HQX_Y_THRESHOLD
+        || (au - bu).abs() > HQX_U_THRESHOLD
+        || (av - bv).abs() > HQX_V_THRESHOLD
+}
+
+fn yuv_distance(a: u32, b: u32) -> i32 {
+    let [ay, au, av] = yuv(a);
+    let [by, bu, bv] = yuv(b);
+    HQX_Y_THRESHOLD * (ay - by).abs()
+        + HQX_U_THRESHOLD * (au - bu).abs()
+        + HQX_V_THRESHOLD * (av - bv).abs()
+}
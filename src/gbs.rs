@@ -0,0 +1,230 @@
+// Player for GBS files (Game Boy Sound): the sound driver and music data ripped from a game,
+// with a header giving the routines to call. The data is loaded into the ROM behind a small
+// driver, and runs on the emulated CPU and APU: INIT is called with the track number, then PLAY
+// at every VBlank or timer interrupt.
+
+use crate::canvas::{Canvas, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::consts::CPU_CLOCK_SPEED;
+use crate::io::{Audio, Serial};
+use crate::state::GBState;
+
+pub const HEADER_SIZE: usize = 0x70;
+
+// Lowest load address allowed by the format, the ROM before it is used by the driver
+const MIN_LOAD_ADDRESS: u16 = 0x400;
+
+// Idle loop of the driver, INIT returns to it: EI, then HALT until the next interrupt forever
+const DRIVER_ADDRESS: u16 = 0x100;
+const DRIVER: [u8; 4] = [0xfb, 0x76, 0x18, 0xfd];
+
+const VBLANK_VECTOR: u16 = 0x40;
+const TIMER_VECTOR: u16 = 0x50;
+
+// Size of the emulated ROM, the banks after it can't be loaded
+const ROM_SIZE: usize = 0x200000;
+
+const FRAME_CYCLES: u64 = 70224;
+
+const BACKGROUND: u32 = 0x1d1d28;
+const TEXT: u32 = 0xe0e0e0;
+const DIM_TEXT: u32 = 0x8080a0;
+
+#[derive(Debug, Clone)]
+pub struct GbsHeader {
+    pub version: u8,
+    pub track_count: u8,
+    // 1-based like in the file
+    pub first_track: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+}
+
+// Zero-padded string of the header
+fn header_string(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as char)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+impl GbsHeader {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        if data.len() < HEADER_SIZE || &data[..3] != b"GBS" {
+            return Err("Not a GBS file".to_string());
+        }
+
+        let word = |i: usize| u16::from_le_bytes([data[i], data[i + 1]]);
+        let header = Self {
+            version: data[0x03],
+            track_count: data[0x04],
+            first_track: data[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0a),
+            stack_pointer: word(0x0c),
+            timer_modulo: data[0x0e],
+            timer_control: data[0x0f],
+            title: header_string(&data[0x10..0x30]),
+            author: header_string(&data[0x30..0x50]),
+            copyright: header_string(&data[0x50..0x70]),
+        };
+
+        if header.version != 1 {
+            return Err(format!("Unsupported GBS version {}", header.version));
+        }
+        if header.track_count == 0 {
+            return Err("The GBS file has no track".to_string());
+        }
+        if header.load_address < MIN_LOAD_ADDRESS || header.load_address >= 0x8000 {
+            return Err(format!(
+                "Invalid GBS load address 0x{:04x}",
+                header.load_address
+            ));
+        }
+        Ok(header)
+    }
+
+    // Timer interrupts if bit 2 of TAC is set, VBlank interrupts otherwise
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0b100 != 0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Gbs {
+    pub header: GbsHeader,
+    data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        Ok(Self {
+            header: GbsHeader::parse(data)?,
+            data: data[HEADER_SIZE..].to_vec(),
+        })
+    }
+
+    /// Writes the data at the load address of a ROM image (the banks after the first one being
+    /// switched like on an MBC1), with the driver and vectors in front of it
+    pub fn load_rom(&self, rom: &mut [u8]) {
+        let load_address = self.header.load_address as usize;
+        let end = (load_address + self.data.len())
+            .min(rom.len())
+            .min(ROM_SIZE);
+
+        rom.fill(0xff);
+        rom[load_address..end].copy_from_slice(&self.data[..end - load_address]);
+
+        // The RST instructions jump to the same offsets from the load address
+        for rst in (0..0x40).step_by(8) {
+            let target = (load_address + rst).to_le_bytes();
+            rom[rst..rst + 3].copy_from_slice(&[0xc3, target[0], target[1]]);
+        }
+
+        // CALL PLAY then RETI
+        let play = self.header.play_address.to_le_bytes();
+        for vector in [VBLANK_VECTOR, TIMER_VECTOR] {
+            let vector = vector as usize;
+            rom[vector..vector + 4].copy_from_slice(&[0xcd, play[0], play[1], 0xd9]);
+        }
+
+        let driver = DRIVER_ADDRESS as usize;
+        rom[driver..driver + DRIVER.len()].copy_from_slice(&DRIVER);
+    }
+
+    /// Resets the console and calls INIT for a track (starting from 1). The ROM must have been
+    /// loaded with `load_rom`.
+    pub fn start_track<S: Serial, A: Audio>(&self, state: &mut GBState<S, A>, track: u8) {
+        let header = &self.header;
+        let mem = &mut state.mem;
+
+        mem.boot_rom_on = false;
+        mem.rom_bank = 1;
+        mem.ram_bank = 0;
+        mem.ram_bank_enabled = true;
+        mem.wram_00.fill(0);
+        mem.wram_01.fill(0);
+        mem.external_ram.fill(0);
+        mem.hram.fill(0);
+
+        mem.ime = false;
+        mem.halt = false;
+        mem.io[0x0f] = 0;
+        mem.interrupts_register = if header.uses_timer() { 0b100 } else { 0b1 };
+
+        // Power cycling the APU and setting the registers left by the boot ROM
+        let cgb_mode = mem.cgb_mode;
+        mem.audio.w(0x26, 0x00, cgb_mode);
+        mem.audio.w(0x26, 0x80, cgb_mode);
+        mem.audio.w(0x24, 0x77, cgb_mode);
+        mem.audio.w(0x25, 0xf3, cgb_mode);
+
+        mem.w(0xff06, header.timer_modulo);
+        mem.w(0xff07, header.timer_control & 0b111);
+        mem.tima = header.timer_modulo;
+        // Only the display for the VBlank interrupts, nothing is drawn
+        mem.display.lcdc = 0x80;
+
+        state.is_stopped = false;
+        state.cpu.r = [0; 8];
+        state.cpu.r[6] = track.saturating_sub(1);
+        state.cpu.sp = header.stack_pointer;
+        state.push(DRIVER_ADDRESS);
+        state.cpu.pc = header.init_address;
+    }
+
+    /// Screen with the information of the file and the track being played
+    pub fn info_screen(&self, track: u8, frames: u64) -> Box<[u32; 160 * 144]> {
+        let mut canvas = Canvas::new(160, 144, BACKGROUND);
+        let line = |i: i32| 8 + i * (GLYPH_HEIGHT as i32 + 2);
+        let max_chars = 160 / GLYPH_WIDTH - 2;
+
+        canvas.text(4, line(0), "GBS PLAYER", DIM_TEXT);
+        for (i, text) in [
+            &self.header.title,
+            &self.header.author,
+            &self.header.copyright,
+        ]
+        .iter()
+        .enumerate()
+        {
+            let text: String = text.chars().take(max_chars).collect();
+            canvas.text(4, line(i as i32 + 2), &text, TEXT);
+        }
+
+        let seconds = frames * FRAME_CYCLES / CPU_CLOCK_SPEED;
+        canvas.text(
+            4,
+            line(6),
+            &format!("TRACK {}/{}", track, self.header.track_count),
+            TEXT,
+        );
+        canvas.text(
+            4,
+            line(7),
+            &format!("{}:{:02}", seconds / 60, seconds % 60),
+            TEXT,
+        );
+
+        canvas.text(
+            4,
+            144 - 8 - GLYPH_HEIGHT as i32,
+            "PGUP/PGDN: TRACK",
+            DIM_TEXT,
+        );
+
+        let mut fb = Box::new([0; 160 * 144]);
+        fb.copy_from_slice(&canvas.pixels);
+        fb
+    }
+}
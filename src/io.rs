@@ -5,10 +5,30 @@ use std::thread;
 #[cfg(not(target_family = "wasm"))]
 use std::time::{Duration, SystemTime};
 
-use crate::audio::MutableWave;
+use crate::audio::Channels;
 use crate::consts;
+use crate::display::{Display, Layer};
+use crate::filters::Filter;
+use crate::gbs::Gbs;
 use crate::logs::{elog, log, LogLevel};
+use crate::png;
+use crate::rewind::Rewind;
+use crate::save_state;
 use crate::state::GBState;
+use crate::vgm::VgmLog;
+
+// Audio latency kept when the emulation is paced by the audio
+#[cfg(not(target_family = "wasm"))]
+const AUDIO_SYNC_TARGET_LATENCY: f32 = 0.06;
+// Maximum change of the resampling ratio used to get back to the target latency
+#[cfg(not(target_family = "wasm"))]
+const AUDIO_SYNC_MAX_RATE_ADJUSTMENT: f32 = 0.005;
+// Weight of the last measure in the average latency, the audio callbacks take the samples by
+// chunks so the latency measured at each frame jumps around
+#[cfg(not(target_family = "wasm"))]
+const AUDIO_SYNC_SMOOTHING: f32 = 0.05;
+
+const FRAME_CYCLES: u64 = 70224;
 
 pub trait Input {
     fn update_events(&mut self, cycles: u128) -> Option<u128>;
@@ -34,16 +54,49 @@ impl<T: Input + ?Sized> Input for Box<T> {
 
 pub enum WindowSignal {
     Exit,
+    Screenshot,
+    Filter(Filter),
+    ToggleLayer(Layer),
+    // Only show the sprite of this OAM index (all of them if None)
+    SoloSprite(Option<usize>),
+    ToggleHiddenLayerColor,
+    ToggleChannelMute(usize),
+    // Plays this channel alone, or all of them again if it already was
+    ToggleChannelSolo(usize),
+    // Goes to the next of the volume steps of the channel
+    CycleChannelVolume(usize),
+    NextTrack,
+    PreviousTrack,
+    ToggleVgmLog,
+    SelectSlot(u8),
+    SaveSlot,
+    LoadSlot,
 }
 
 pub trait Window {
     fn update(&mut self, fb: Box<[u32; 160 * 144]>) -> Option<WindowSignal>;
+
+    // Screens of two linked Game Boys, only the first one is shown by default
+    fn update_linked(
+        &mut self,
+        fb: Box<[u32; 160 * 144]>,
+        _fb2: Box<[u32; 160 * 144]>,
+    ) -> Option<WindowSignal> {
+        self.update(fb)
+    }
 }
 
 impl<T: Window + ?Sized> Window for Box<T> {
     fn update(&mut self, fb: Box<[u32; 160 * 144]>) -> Option<WindowSignal> {
         (**self).update(fb)
     }
+    fn update_linked(
+        &mut self,
+        fb: Box<[u32; 160 * 144]>,
+        fb2: Box<[u32; 160 * 144]>,
+    ) -> Option<WindowSignal> {
+        (**self).update_linked(fb, fb2)
+    }
 }
 
 pub trait Serial {
@@ -77,22 +130,44 @@ impl<T: Serial + ?Sized> Serial for Box<T> {
     }
 }
 
-pub trait Wave {
-    fn next(&mut self, left: bool) -> Option<f32>;
+// Infrared LED and receiver of the CGB (RP register)
+pub trait Infrared {
+    fn write_led(&mut self, on: bool);
+    // True when light from the other side is received
+    fn read_light(&self) -> bool;
+    // `reading` when the receiver is enabled in RP, the light only has to be known then
+    fn update_infrared(&mut self, cycles: u128, reading: bool);
+}
+
+// Nothing in front of the receiver
+pub struct NoInfrared;
+
+impl Infrared for NoInfrared {
+    fn write_led(&mut self, _on: bool) {}
+    fn read_light(&self) -> bool {
+        false
+    }
+    fn update_infrared(&mut self, _cycles: u128, _reading: bool) {}
 }
 
 pub trait Audio {
-    fn attach_wave(&mut self, wave: MutableWave);
-    fn next(&mut self);
+    // Output rate of the backend, the APU resamples to it
+    fn sample_rate(&self) -> u32;
+    // Interleaved stereo samples (left first) at `sample_rate`
+    fn push_samples(&mut self, samples: &[f32]);
+    // Seconds of audio waiting to be played, None if the backend doesn't play anything
+    fn latency(&self) -> Option<f32>;
 }
 
 impl<T: Audio + ?Sized> Audio for Box<T> {
-    fn attach_wave(&mut self, wave: MutableWave) {
-        (**self).attach_wave(wave)
+    fn sample_rate(&self) -> u32 {
+        (**self).sample_rate()
     }
-
-    fn next(&mut self) {
-        (**self).next()
+    fn push_samples(&mut self, samples: &[f32]) {
+        (**self).push_samples(samples)
+    }
+    fn latency(&self) -> Option<f32> {
+        (**self).latency()
     }
 }
 
@@ -120,7 +195,6 @@ pub struct Gameboy<I: Input, S: Serial, A: Audio, LS: LoadSave> {
     total_cycle_counter: u128,
     pub nanos_sleep: f64,
     halt_time: u64,
-    audio_counter: u64,
     was_previously_halted: bool,
 
     last_ram_bank_enabled: bool,
@@ -128,6 +202,12 @@ pub struct Gameboy<I: Input, S: Serial, A: Audio, LS: LoadSave> {
     last_halt_cycle: SystemTime,
     last_halt_cycle_counter: u128,
     next_precise_gamepad_update: Option<u128>,
+
+    audio_sync: bool,
+    average_audio_latency: f32,
+
+    rewind: Option<Rewind>,
+    rewinding: bool,
 }
 
 impl<I: Input, S: Serial, A: Audio, LS: LoadSave> Gameboy<I, S, A, LS> {
@@ -140,7 +220,6 @@ impl<I: Input, S: Serial, A: Audio, LS: LoadSave> Gameboy<I, S, A, LS> {
             total_cycle_counter: 0,
             nanos_sleep: 0.0,
             halt_time: 0,
-            audio_counter: 0,
             was_previously_halted: false,
 
             last_ram_bank_enabled: false,
@@ -148,6 +227,12 @@ impl<I: Input, S: Serial, A: Audio, LS: LoadSave> Gameboy<I, S, A, LS> {
             last_halt_cycle: SystemTime::now(),
             last_halt_cycle_counter: 0,
             next_precise_gamepad_update: None,
+
+            audio_sync: false,
+            average_audio_latency: 0.,
+
+            rewind: None,
+            rewinding: false,
         };
 
         gb.load_save
@@ -181,11 +266,125 @@ impl<I: Input, S: Serial, A: Audio, LS: LoadSave> Gameboy<I, S, A, LS> {
         Ok(())
     }
 
+    /// State with the time it was made (seconds since the Unix epoch) and a thumbnail of the
+    /// screen (see `save_state::save_with_info`)
+    pub fn save_state_data(&self, timestamp: u64) -> Vec<u8> {
+        save_state::save_with_info(&self.state, timestamp)
+    }
+
+    /// Restores a state, in the format of the emulator or BESS. Nothing is changed if it can't
+    /// be loaded.
+    pub fn load_state_data(&mut self, data: &[u8]) -> Result<(), String> {
+        save_state::load(&mut self.state, data)
+    }
+
+    pub fn display(&self) -> &Display {
+        &self.state.mem.display
+    }
+
+    pub fn display_mut(&mut self) -> &mut Display {
+        &mut self.state.mem.display
+    }
+
+    pub fn audio(&self) -> &Channels<A> {
+        &self.state.mem.audio
+    }
+
+    pub fn audio_mut(&mut self) -> &mut Channels<A> {
+        &mut self.state.mem.audio
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.state.mem.display.frame_count
+    }
+
+    pub fn rom_title(&self) -> String {
+        self.state.mem.rom_title()
+    }
+
+    /// Encodes the last complete frame as a PNG file, upscaled with `filter`
+    pub fn screenshot(&self, filter: Filter) -> Vec<u8> {
+        let scale = filter.scale();
+        let fb = filter.apply(self.state.mem.display.framebuffer().as_ref(), 160, 144);
+
+        png::encode_rgb(&fb, 160 * scale, 144 * scale)
+    }
+
+    /// Default file name for a screenshot: "<rom title>-<frame>.png"
+    pub fn screenshot_file_name(&self) -> String {
+        self.output_file_name("screenshot", "png")
+    }
+
+    /// Default file name for a VGM log: "<rom title>-<frame>.vgm"
+    pub fn vgm_file_name(&self) -> String {
+        self.output_file_name("sound", "vgm")
+    }
+
+    fn output_file_name(&self, default_name: &str, extension: &str) -> String {
+        let title: String = self
+            .rom_title()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+
+        format!(
+            "{}-{}.{}",
+            if title.is_empty() {
+                default_name
+            } else {
+                &title
+            },
+            self.frame_count(),
+            extension
+        )
+    }
+
+    /// Starts logging the writes to the sound registers, from the current state of the APU
+    pub fn start_vgm_log(&mut self) {
+        self.state.mem.vgm = Some(VgmLog::new(&self.state.mem.audio.register_writes()));
+    }
+
+    /// Stops the log of the sound register writes and returns it as a VGM file
+    pub fn stop_vgm_log(&mut self) -> Option<Vec<u8>> {
+        self.state.mem.vgm.take().map(VgmLog::finish)
+    }
+
+    /// Paces the emulation with the audio buffer instead of the system clock: the resampling
+    /// ratio is slightly adjusted to keep the latency constant, and the emulator waits while
+    /// there is more audio than needed. Not used if the backend doesn't play anything.
+    pub fn set_audio_sync(&mut self, audio_sync: bool) {
+        self.audio_sync = audio_sync;
+        if !audio_sync {
+            self.state.mem.audio.set_rate_ratio(1.);
+        }
+    }
+
+    /// Keeps snapshots of the state in `rewind` while running, to go back with `set_rewinding`
+    pub fn enable_rewind(&mut self, rewind: Rewind) {
+        self.rewind = Some(rewind);
+    }
+
+    /// While rewinding, `run_until_next_sleep` goes back in the snapshots of the rewind buffer
+    /// instead of running the emulator, so nothing is played on the audio output
+    pub fn set_rewinding(&mut self, rewinding: bool) {
+        self.rewinding = rewinding && self.rewind.is_some();
+    }
+
     pub fn skip_bootrom(&mut self) {
         self.state.mem.boot_rom_on = false;
         self.state.cpu.pc = 0x100;
     }
 
+    pub fn set_infrared(&mut self, infrared: Box<dyn Infrared>) {
+        self.state.mem.infrared = infrared;
+    }
+
+    /// Replaces the ROM with the data of a GBS file and plays one of its tracks (starting from 1)
+    pub fn play_gbs_track(&mut self, gbs: &Gbs, track: u8) {
+        gbs.load_rom(self.state.mem.rom.as_mut());
+        gbs.start_track(&mut self.state, track);
+    }
+
     pub fn update_joypad(&mut self) {
         self.next_precise_gamepad_update = self.input.update_events(self.total_cycle_counter);
 
@@ -256,7 +455,6 @@ impl<I: Input, S: Serial, A: Audio, LS: LoadSave> Gameboy<I, S, A, LS> {
         self.last_halt_cycle_counter += c as u128;
         self.state.cpu.dbg_cycle_counter += c;
         self.total_cycle_counter += c as u128;
-        self.audio_counter += c;
 
         if self
             .next_precise_gamepad_update
@@ -265,27 +463,47 @@ impl<I: Input, S: Serial, A: Audio, LS: LoadSave> Gameboy<I, S, A, LS> {
             self.update_joypad();
         }
 
-        if self.audio_counter >= 32 {
-            self.audio_counter -= 32;
-            self.state.mem.audio.next();
-        }
-
         self.state.div_timer(c);
+        self.state.mem.audio.tick(c, self.state.mem.div);
+        if let Some(vgm) = &mut self.state.mem.vgm {
+            vgm.tick(c);
+        }
         self.state.tima_timer(c);
         self.state.update_display_interrupts(c);
         self.state.check_interrupts();
         self.state.mem.update_serial(self.total_cycle_counter);
+        let reading = self.state.mem.cgb_mode && self.state.mem.io[0x56] & 0xc0 == 0xc0;
+        self.state
+            .mem
+            .infrared
+            .update_infrared(self.total_cycle_counter, reading);
 
         return c;
     }
 
     pub fn run_until_next_sleep(&mut self) -> bool {
+        if let (true, Some(rewind)) = (self.rewinding, &mut self.rewind) {
+            // One frame shown, at the pace of a frame run
+            rewind.step_back(&mut self.state);
+            self.nanos_sleep += FRAME_CYCLES as f64 * consts::CPU_CYCLE_LENGTH_NANOS / self.speed;
+            return true;
+        }
+
         self.update_joypad();
         self.external_ram_save();
+        let frame_count = self.state.mem.display.frame_count;
         while !self.state.is_stopped {
             let c = self.run_instr();
             self.nanos_sleep += c as f64 * (consts::CPU_CYCLE_LENGTH_NANOS / self.speed) as f64;
-            if self.nanos_sleep > 0.0 {
+            // Also stopping at the end of each frame so the frontend can capture them exactly.
+            // When paced by the audio, the emulator only waits between frames.
+            if self.state.mem.display.frame_count != frame_count {
+                if let Some(rewind) = &mut self.rewind {
+                    rewind.frame(&self.state);
+                }
+                return true;
+            }
+            if self.nanos_sleep > 0.0 && !self.audio_sync {
                 return true;
             }
         }
@@ -293,8 +511,65 @@ impl<I: Input, S: Serial, A: Audio, LS: LoadSave> Gameboy<I, S, A, LS> {
         return false;
     }
 
+    /// Same as `run_until_next_sleep`, with `other` run up to the same cycle count before each
+    /// instruction so the two can be linked by their serial ports (see `LinkedSerial`). The
+    /// speed and sleeps are the ones of self, and the redraw requests of `other` stay available
+    /// with `display_mut().get_redraw_request()`.
+    pub fn run_linked_until_next_sleep(&mut self, other: &mut Self) -> bool {
+        self.update_joypad();
+        other.update_joypad();
+        self.external_ram_save();
+        other.external_ram_save();
+        let frame_count = self.state.mem.display.frame_count;
+        while !self.state.is_stopped && !other.state.is_stopped {
+            while other.total_cycle_counter < self.total_cycle_counter {
+                other.run_instr();
+            }
+            let c = self.run_instr();
+            self.nanos_sleep += c as f64 * consts::CPU_CYCLE_LENGTH_NANOS / self.speed;
+            if (self.nanos_sleep > 0.0 && !self.audio_sync)
+                || self.state.mem.display.frame_count != frame_count
+            {
+                return true;
+            }
+        }
+        self.state.mem.serial.close_serial();
+        other.state.mem.serial.close_serial();
+        false
+    }
+
+    #[cfg(not(target_family = "wasm"))]
+    fn sync_with_audio(&mut self, latency: f32) {
+        self.average_audio_latency += (latency - self.average_audio_latency) * AUDIO_SYNC_SMOOTHING;
+
+        // More samples per emulated second (so a slower emulation) when there is less audio
+        // than the target and fewer when there is more
+        let adjustment = ((AUDIO_SYNC_TARGET_LATENCY - self.average_audio_latency)
+            / AUDIO_SYNC_TARGET_LATENCY)
+            .clamp(-1., 1.)
+            * AUDIO_SYNC_MAX_RATE_ADJUSTMENT;
+        self.state
+            .mem
+            .audio
+            .set_rate_ratio((1. + adjustment as f64) / self.speed);
+
+        if latency > AUDIO_SYNC_TARGET_LATENCY {
+            thread::sleep(Duration::from_secs_f32(latency - AUDIO_SYNC_TARGET_LATENCY));
+        }
+    }
+
     #[cfg(not(target_family = "wasm"))]
     pub fn sleep_and_draw(&mut self) -> Option<Box<[u32; 160 * 144]>> {
+        // Nothing is played while rewinding, so the system clock is used
+        if self.audio_sync && !self.rewinding {
+            if let Some(latency) = self.state.mem.audio.latency() {
+                self.sync_with_audio(latency);
+                self.nanos_sleep = 0.;
+                self.now = SystemTime::now();
+                return self.state.mem.display.get_redraw_request();
+            }
+        }
+
         thread::sleep(Duration::from_nanos(self.nanos_sleep as u64));
 
         let new_now = SystemTime::now();
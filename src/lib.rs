@@ -1,15 +1,26 @@
 pub mod audio;
+pub mod audio_viewer;
+pub mod bess;
+pub mod blip;
+pub mod canvas;
 pub mod consts;
 
 pub mod desktop;
 
 pub mod display;
+pub mod filters;
+pub mod gbs;
 pub mod interrupts_timers;
 pub mod io;
 pub mod logs;
 pub mod mmio;
 pub mod opcodes;
+pub mod png;
+pub mod rewind;
+pub mod save_state;
 pub mod state;
+pub mod vgm;
+pub mod vram_viewer;
 
 #[cfg(target_family = "wasm")]
 pub mod wasm;
@@ -1,45 +1,100 @@
 pub mod audio;
+pub mod audio_viewer;
+pub mod bess;
+pub mod blip;
+pub mod canvas;
 pub mod consts;
 
 pub mod desktop;
 
 pub mod display;
+pub mod filters;
+pub mod gbs;
 pub mod interrupts_timers;
 pub mod io;
 pub mod logs;
 pub mod mmio;
 pub mod opcodes;
+pub mod png;
+pub mod rewind;
+pub mod save_state;
 pub mod state;
+pub mod vgm;
+pub mod vram_viewer;
 #[cfg(not(feature = "dynamic_rom"))]
 use cpal::traits::StreamTrait;
 
 use std::collections::HashSet;
+use std::fs;
 use std::net::TcpListener;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use crate::desktop::audio::{HeadlessAudio, RodioAudio};
-use crate::desktop::input::{Gamepad, GamepadRecorder, GamepadReplay, InputCombiner, Keyboard};
+use crate::desktop::infrared::{LinkedInfrared, SocketInfrared};
+use crate::desktop::input::{
+    Gamepad, GamepadRecorder, GamepadReplay, InputCombiner, KeyMapping, Keyboard, PLAYER1_KEYS,
+    PLAYER2_KEYS, REWIND_KEY,
+};
+use crate::desktop::link::{LinkSerial, LinkedSerial};
 use crate::desktop::load_save::FSLoadSave;
 #[cfg(not(feature = "dynamic_rom"))]
 use crate::desktop::load_save::StaticRom;
+use crate::desktop::record::{Recorder, RecordingAudio};
+use crate::desktop::serial::{CaptureFormat, CaptureSerial};
+use crate::desktop::slots;
+use crate::desktop::wav::WavExport;
 
-use crate::io::{Audio, Gameboy, Input, Serial, Window};
-use crate::logs::{log, LogLevel};
-use clap::Parser;
+use crate::filters::Filter;
+use crate::io::{Audio, Gameboy, Input, LoadSave, Serial, Window, WindowSignal};
+use crate::logs::{elog, log, LogLevel};
+use crate::rewind::Rewind;
+use clap::builder::{PossibleValuesParser, TypedValueParser};
+use clap::{Parser, Subcommand};
+
+// Volumes of a channel cycled through with Alt+1-4
+const CHANNEL_VOLUME_STEPS: [f32; 4] = [1., 0.75, 0.5, 0.25];
+
+// How long the save-state slot indicator stays on the screen
+const SLOT_INDICATOR_DURATION: Duration = Duration::from_secs(2);
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// The gameboy rom file
     #[cfg(feature = "dynamic_rom")]
-    rom: String,
+    #[arg(required = true)]
+    rom: Option<String>,
+
+    /// Run a second Game Boy with this ROM (and its own save) in the same window, linked to the
+    /// first one by the serial ports. The options recording or exporting the run, saving states,
+    /// and using the serial or infrared ports aren't supported with it
+    #[cfg(feature = "dynamic_rom")]
+    #[arg(long, conflicts_with_all = [
+        "fifo_input", "fifo_output", "record_input", "replay_input", "state_file", "load_state",
+        "stop_dump_state", "screenshot_at_frame", "vram_viewer", "audio_viewer", "record", "wav",
+        "vgm", "gbs_track", "rewind", "listen", "connect", "printer", "serial_stdout",
+        "serial_log", "serial_hex", "serial_reply_ff", "lockstep", "ir_listen", "ir_connect",
+        "no_response", "restart_on_stop",
+    ])]
+    link_rom: Option<String>,
 
     /// Serial communication input from a FIFO file
-    #[arg(long)]
+    #[arg(long, conflicts_with_all = ["listen", "connect"])]
     fifo_input: Option<String>,
 
     /// Serial communication output from a FIFO file
-    #[arg(long)]
+    #[arg(long, conflicts_with_all = ["listen", "connect"])]
     fifo_output: Option<String>,
 
     /// Record the inputs into a file when they happen so it can be replayed with --replay-input
@@ -59,6 +114,11 @@ struct Cli {
     #[arg(short, long, default_value_t = false)]
     load_state: bool,
 
+    /// Save the states in the BESS format, which other emulators (SameBoy, ...) can load. The
+    /// format of the loaded states is detected
+    #[arg(long, default_value_t = false)]
+    bess: bool,
+
     #[arg(short, long, default_value_t = 1.0)]
     speed: f32,
 
@@ -74,19 +134,149 @@ struct Cli {
     #[arg(long, default_value_t = false)]
     headless: bool,
 
+    /// Upscaling filter applied to the window (possible values: none,scale2x,scale3x,hq2x,xbr-lite,scanlines,dot-matrix,dmg-grid).
+    /// Can be cycled with F2
+    #[arg(long, default_value = "none")]
+    filter: filters::Filter,
+
+    /// Colors used for DMG games
+    #[arg(long, default_value = "green", value_parser = palette_parser())]
+    palette: [u32; 4],
+
+    /// Save a screenshot of the given frame (counted from the start) to a PNG file. Works in
+    /// headless mode, where the emulator stops after it
+    #[arg(long, value_name = "FRAME")]
+    screenshot_at_frame: Option<u64>,
+
+    /// File of the screenshot of --screenshot-at-frame (defaults to "<rom title>-<frame>.png")
+    #[arg(long, value_name = "PATH", requires = "screenshot_at_frame")]
+    screenshot_file: Option<String>,
+
+    /// Open a window showing the tiles, tilemaps, OAM entries and palettes
+    #[arg(long, default_value_t = false)]
+    vram_viewer: bool,
+
+    /// Open a window showing an oscilloscope of each sound channel with its frequency, note,
+    /// volume, duty and panning
+    #[arg(long, default_value_t = false)]
+    audio_viewer: bool,
+
+    /// Record every frame into a video file (.y4m, .rgb (raw rgb24) or .gif) and the audio into a
+    /// .wav file with the same name. Works in headless mode
+    #[arg(long)]
+    record: Option<String>,
+
+    /// Stop the emulator after recording this number of frames with --record
+    #[arg(long, value_name = "FRAMES", requires = "record")]
+    record_frames: Option<u64>,
+
+    /// Write the emulated audio into a WAV file, without going through the sound card. Works in
+    /// headless mode, and gives the same file every time with --replay-input
+    #[arg(long, value_name = "PATH")]
+    wav: Option<String>,
+
+    /// Also write each channel into its own WAV file next to the one of --wav (<name>-ch1.wav to
+    /// <name>-ch4.wav)
+    #[arg(long, default_value_t = false, requires = "wav")]
+    wav_stems: bool,
+
+    /// Stop the emulator after writing this number of frames of audio with --wav
+    #[arg(long, value_name = "FRAMES", requires = "wav")]
+    wav_frames: Option<u64>,
+
+    /// Log the writes to the sound registers from the start into a VGM file (F9 starts and stops
+    /// a log at any time, written to this file or to "<rom title>-<frame>.vgm")
+    #[arg(long, value_name = "PATH")]
+    vgm: Option<String>,
+
+    /// Track to start with when playing a GBS file (starting from 1, defaults to the first track
+    /// given by the file)
+    #[arg(long, value_name = "TRACK")]
+    gbs_track: Option<u8>,
+
+    /// Model of the high-pass filter removing the DC offset of the audio output (possible values: none,dmg,cgb)
+    #[arg(long, default_value = "dmg")]
+    high_pass: audio::HighPass,
+
+    /// Pace the emulation with the audio output instead of the system clock, which avoids audio
+    /// crackles (the emulation speed follows the sound card by tiny amounts). The recordings and
+    /// WAV exports need a fixed number of samples per frame, so it can't be used with them
+    #[arg(long, default_value_t = false, conflicts_with_all = ["record", "wav"])]
+    audio_sync: bool,
+
+    /// Keep snapshots of the last seconds in memory to play them backwards while R is held
+    #[arg(long, default_value_t = false)]
+    rewind: bool,
+
+    /// Frames between two rewind snapshots
+    #[arg(long, value_name = "FRAMES", default_value_t = 2, requires = "rewind")]
+    rewind_interval: u32,
+
+    /// Speed of the rewinding, relative to the normal speed of the game
+    #[arg(long, default_value_t = 2.0, requires = "rewind")]
+    rewind_speed: f32,
+
+    /// Memory used by the rewind snapshots, in MiB
+    #[arg(long, value_name = "MIB", default_value_t = 64, requires = "rewind")]
+    rewind_memory: usize,
+
     /// Window title
     #[cfg(feature = "dynamic_rom")]
     #[arg(long, default_value = "Gameboy Emulator")]
     title: String,
 
     /// Serial tcp listen port
-    #[arg(short = 'L', long)]
+    #[arg(short = 'L', long, conflicts_with = "connect")]
     listen: Option<u16>,
 
     /// Serial tcp connect address <address:port>
     #[arg(short, long)]
     connect: Option<String>,
 
+    /// Connect a Game Boy Printer to the serial port, saving the printed pages in this directory
+    #[arg(long, conflicts_with_all = ["listen", "connect", "fifo_input", "fifo_output"])]
+    printer: Option<String>,
+
+    /// Complete the serial transfers without anything connected and print the bytes sent
+    #[arg(long, default_value_t = false, conflicts_with_all = [
+        "listen", "connect", "fifo_input", "fifo_output", "printer",
+    ])]
+    serial_stdout: bool,
+
+    /// Complete the serial transfers without anything connected and write the bytes sent to a file
+    #[arg(long, conflicts_with_all = [
+        "listen", "connect", "fifo_input", "fifo_output", "printer",
+    ])]
+    serial_log: Option<String>,
+
+    /// Write the captured serial bytes in hexadecimal instead of text
+    #[arg(long, default_value_t = false)]
+    serial_hex: bool,
+
+    /// Answer 0xFF instead of 0x00 to the captured serial transfers, like when nothing is connected
+    #[arg(long, default_value_t = false)]
+    serial_reply_ff: bool,
+
+    /// Keep the emulators linked with -L and -c in lockstep so the transfers happen on the same
+    /// cycles whatever the network timings
+    #[arg(long, default_value_t = false)]
+    lockstep: bool,
+
+    /// Seconds to wait for the other emulator in lockstep or on the infrared port before
+    /// disconnecting
+    #[arg(long, default_value_t = 10)]
+    link_timeout: u64,
+
+    /// Infrared port (CGB) tcp listen port. While reading is enabled in RP, the emulators are
+    /// kept in lockstep with a round trip every 60µs of emulation, which needs a low latency
+    /// connection
+    #[arg(long)]
+    ir_listen: Option<u16>,
+
+    /// Infrared port (CGB) tcp connect address <address:port> (see --ir-listen)
+    #[arg(long)]
+    ir_connect: Option<String>,
+
     /// Don't send (or expect) a byte as a response to a serial transfer
     #[arg(long, default_value_t = false)]
     no_response: bool,
@@ -100,13 +290,265 @@ struct Cli {
     verbosity: String,
 }
 
+// Colors of one of the palettes of `display::DMG_PALETTES`, with its name checked by clap
+fn palette_parser() -> impl TypedValueParser<Value = [u32; 4]> {
+    PossibleValuesParser::new(display::DMG_PALETTES.map(|(name, _)| name))
+        .map(|name: String| display::dmg_palette(&name).unwrap())
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Save-state slots (saved with F3 and loaded with F4 in the slot selected with 0-9)
+    States {
+        #[command(subcommand)]
+        command: StatesCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum StatesCommand {
+    /// List the slots of a ROM with the time they were saved
+    List {
+        /// The gameboy rom file
+        rom: String,
+    },
+}
+
+fn list_states(rom: &str) {
+    for slot in 0..slots::SLOT_COUNT {
+        let description = match slots::slot_info(rom, slot) {
+            None => "empty".to_string(),
+            Some(Ok(info)) => match info.timestamp {
+                Some(timestamp) => format!("{} UTC", slots::format_timestamp(timestamp)),
+                None => "saved".to_string(),
+            },
+            Some(Err(err)) => format!("unreadable ({})", err),
+        };
+        println!("{}: {}", slot, description);
+    }
+}
+
+// Returns the text of the slot indicator
+fn save_slot<I: Input, S: Serial, A: Audio, LS: LoadSave>(
+    gameboy: &Gameboy<I, S, A, LS>,
+    base: &str,
+    slot: u8,
+) -> String {
+    let path = slots::slot_path(base, slot);
+    match fs::write(&path, gameboy.save_state_data(slots::now())) {
+        Ok(()) => {
+            log(LogLevel::Infos, format!("State saved to \"{}\"", path));
+            format!("SAVED SLOT {}", slot)
+        }
+        Err(err) => {
+            elog(
+                LogLevel::Error,
+                format!("Failed to save the state to \"{}\" ({})", path, err),
+            );
+            format!("SLOT {} NOT SAVED", slot)
+        }
+    }
+}
+
+// Returns the text of the slot indicator
+fn load_slot<I: Input, S: Serial, A: Audio, LS: LoadSave>(
+    gameboy: &mut Gameboy<I, S, A, LS>,
+    base: &str,
+    slot: u8,
+) -> String {
+    let path = slots::slot_path(base, slot);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            return format!("SLOT {} EMPTY", slot);
+        }
+        Err(err) => {
+            elog(
+                LogLevel::Error,
+                format!("Failed to read the state from \"{}\" ({})", path, err),
+            );
+            return format!("SLOT {} NOT LOADED", slot);
+        }
+    };
+    match gameboy.load_state_data(&data) {
+        Ok(()) => {
+            log(LogLevel::Infos, format!("State loaded from \"{}\"", path));
+            format!("LOADED SLOT {}", slot)
+        }
+        Err(err) => {
+            elog(
+                LogLevel::Error,
+                format!("Failed to load the state from \"{}\" ({})", path, err),
+            );
+            format!("SLOT {} NOT LOADED", slot)
+        }
+    }
+}
+
+fn save_screenshot<I: Input, S: Serial, A: Audio, LS: LoadSave>(
+    gameboy: &Gameboy<I, S, A, LS>,
+    filter: Filter,
+    path: String,
+) {
+    match fs::write(&path, gameboy.screenshot(filter)) {
+        Ok(()) => log(LogLevel::Infos, format!("Screenshot saved to \"{}\"", path)),
+        Err(err) => elog(
+            LogLevel::Error,
+            format!("Failed to save screenshot to \"{}\" ({})", path, err),
+        ),
+    }
+}
+
+fn save_vgm(path: &str, vgm: Vec<u8>) {
+    match fs::write(path, vgm) {
+        Ok(()) => log(LogLevel::Infos, format!("VGM log saved to \"{}\"", path)),
+        Err(err) => elog(
+            LogLevel::Error,
+            format!("Failed to save VGM log to \"{}\" ({})", path, err),
+        ),
+    }
+}
+
+fn log_track(gbs: &gbs::Gbs, track: u8) {
+    log(
+        LogLevel::Infos,
+        format!(
+            "Playing track {}/{} of \"{}\"",
+            track, gbs.header.track_count, gbs.header.title
+        ),
+    );
+}
+
+// Two Game Boys side by side with their serial ports linked, the second one running `link_rom`
+// with the second keyboard mapping and gamepad. Only the first one is heard.
+#[cfg(feature = "dynamic_rom")]
+// One of the Game Boys of the linked mode
+struct Player<'a> {
+    // Gamepad used, and side of the window
+    index: usize,
+    rom: &'a str,
+    save: String,
+    serial: Box<dyn Serial>,
+    mapping: KeyMapping,
+    audio: Box<dyn Audio>,
+}
+
+fn run_linked(cli: &Cli, rom: &str, link_rom: &str, palette: [u32; 4]) {
+    log(
+        LogLevel::Infos,
+        format!("Starting {:?} linked with {:?}...", rom, link_rom),
+    );
+
+    let (mut window, keys): (Box<dyn Window>, desktop::window::Keys) = if cli.headless {
+        (
+            Box::new(desktop::window::Headless),
+            Arc::new(Mutex::new(HashSet::new())),
+        )
+    } else {
+        let window = desktop::window::DesktopWindow::new(
+            format!("{} - linked", cli.title),
+            cli.filter,
+            false,
+            false,
+            2,
+        )
+        .unwrap();
+        let keys = window.keys.clone();
+        (Box::new(window), keys)
+    };
+
+    let link_save = if link_rom == rom {
+        format!("{}.2.sav", link_rom)
+    } else {
+        format!("{}.sav", link_rom)
+    };
+    let (serial, link_serial) = LinkedSerial::pair();
+    let (infrared, link_infrared) = LinkedInfrared::pair();
+    let players = [
+        Player {
+            index: 0,
+            rom,
+            save: format!("{}.sav", rom),
+            serial: Box::new(serial),
+            mapping: PLAYER1_KEYS,
+            audio: if cli.headless {
+                Box::new(HeadlessAudio {})
+            } else {
+                Box::new(RodioAudio::new(cli.audio_sync))
+            },
+        },
+        Player {
+            index: 1,
+            rom: link_rom,
+            save: link_save,
+            serial: Box::new(link_serial),
+            mapping: PLAYER2_KEYS,
+            audio: Box::new(HeadlessAudio {}),
+        },
+    ];
+
+    let [mut gameboy, mut gameboy2] = players.map(|player| {
+        let input: Box<dyn Input> = Box::new(InputCombiner::new(vec![
+            Box::new(Gamepad::with_index(player.index)),
+            Box::new(Keyboard::with_mapping(keys.clone(), player.mapping)),
+        ]));
+        let mut gameboy = Gameboy::<_, _, _, _>::new(
+            input,
+            player.serial,
+            player.audio,
+            FSLoadSave::new(player.rom, player.save),
+            cli.speed as f64,
+        );
+        if cli.skip_bootrom {
+            gameboy.skip_bootrom();
+        }
+        gameboy.display_mut().dmg_colors = palette;
+        gameboy.display_mut().cls();
+        gameboy
+    });
+    gameboy.set_infrared(Box::new(infrared));
+    gameboy2.set_infrared(Box::new(link_infrared));
+    gameboy.audio_mut().high_pass = cli.high_pass;
+    gameboy.set_audio_sync(cli.audio_sync);
+
+    let mut filter = cli.filter;
+    let mut fb2 = Box::new([0; 160 * 144]);
+    while gameboy.run_linked_until_next_sleep(&mut gameboy2) {
+        if let Some(new_fb2) = gameboy2.display_mut().get_redraw_request() {
+            fb2 = new_fb2;
+        }
+        if let Some(fb) = gameboy.sleep_and_draw() {
+            match window.update_linked(fb, fb2.clone()) {
+                Some(WindowSignal::Exit) => break,
+                Some(WindowSignal::Screenshot) => {
+                    save_screenshot(&gameboy, filter, gameboy.screenshot_file_name());
+                    let path = format!("player2-{}", gameboy2.screenshot_file_name());
+                    save_screenshot(&gameboy2, filter, path);
+                }
+                Some(WindowSignal::Filter(new_filter)) => filter = new_filter,
+                _ => {}
+            }
+        }
+    }
+}
+
 pub fn main() {
     let cli = Cli::parse();
 
-    logs::set_log_level(cli.verbosity);
+    if let Some(Command::States {
+        command: StatesCommand::List { rom },
+    }) = &cli.command
+    {
+        list_states(rom);
+        return;
+    }
+
+    logs::set_log_level(cli.verbosity.clone());
+
+    let palette = cli.palette;
 
     #[cfg(feature = "dynamic_rom")]
-    let rom = cli.rom.clone();
+    let rom = cli.rom.clone().unwrap();
 
     #[cfg(feature = "dynamic_rom")]
     let title = cli.title.clone();
@@ -120,12 +562,53 @@ pub fn main() {
     #[cfg(not(feature = "dynamic_rom"))]
     let sav = env!("GAME_SAV_FILENAME");
 
+    // The slot files are named after the ROM
+    #[cfg(feature = "dynamic_rom")]
+    let slot_base = rom.clone();
+
+    #[cfg(not(feature = "dynamic_rom"))]
+    let slot_base = sav.to_string();
+
+    let mut slot = 0;
+    let mut indicator: Option<(String, Instant)> = None;
+
+    // GBS files are played with a driver instead of being run as a ROM
+    #[cfg(feature = "dynamic_rom")]
+    let gbs = match fs::read(&rom) {
+        Ok(data) if data.starts_with(b"GBS") => {
+            Some(gbs::Gbs::parse(&data).unwrap_or_else(|err| panic!("{}", err)))
+        }
+        _ => None,
+    };
+
+    #[cfg(not(feature = "dynamic_rom"))]
+    let gbs: Option<gbs::Gbs> = None;
+
+    let mut track = match (&gbs, cli.gbs_track) {
+        (Some(gbs), Some(track)) if track == 0 || track > gbs.header.track_count => panic!(
+            "Invalid track {} (the GBS file has {} tracks)",
+            track, gbs.header.track_count
+        ),
+        (Some(gbs), None) => gbs.header.first_track.clamp(1, gbs.header.track_count),
+        (_, track) => track.unwrap_or(1),
+    };
+
+    #[cfg(feature = "dynamic_rom")]
+    if let Some(link_rom) = &cli.link_rom {
+        run_linked(&cli, &rom, link_rom, palette);
+        return;
+    }
+
     let listener = if let Some(port) = cli.listen {
         Some(TcpListener::bind(("0.0.0.0", port)).unwrap())
     } else {
         None
     };
 
+    let ir_listener = cli
+        .ir_listen
+        .map(|port| TcpListener::bind(("0.0.0.0", port)).unwrap());
+
     loop {
         #[cfg(feature = "dynamic_rom")]
         log(LogLevel::Infos, format!("Starting {:?}...", &rom));
@@ -133,29 +616,67 @@ pub fn main() {
         #[cfg(not(feature = "dynamic_rom"))]
         log(LogLevel::Infos, format!("Starting {:?}...", title));
 
-        let (mut window, keys): (Box<dyn Window>, desktop::window::Keys) = if cli.headless {
+        let (mut window, keys, mut viewer, mut audio_viewer): (
+            Box<dyn Window>,
+            desktop::window::Keys,
+            _,
+            _,
+        ) = if cli.headless {
             (
                 Box::new(desktop::window::Headless),
                 Arc::new(Mutex::new(HashSet::new())),
+                None,
+                None,
             )
         } else {
-            let window = desktop::window::DesktopWindow::new(title.clone()).unwrap();
+            let window = desktop::window::DesktopWindow::new(
+                title.clone(),
+                cli.filter,
+                cli.vram_viewer,
+                cli.audio_viewer,
+                1,
+            )
+            .unwrap();
             let keys = window.keys.clone();
-            (Box::new(window), keys)
+            let viewer = window.viewer.clone();
+            let audio_viewer = window.audio_viewer.clone();
+            (Box::new(window), keys, viewer, audio_viewer)
         };
 
         let audio: Box<dyn Audio> = if cli.headless {
             Box::new(HeadlessAudio {})
         } else {
-            Box::new(RodioAudio::new())
+            Box::new(RodioAudio::new(cli.audio_sync))
+        };
+
+        let (audio, mut recorder): (Box<dyn Audio>, _) = if let Some(path) = &cli.record {
+            let (audio, samples) = RecordingAudio::new(audio);
+            let recorder = Recorder::new(path, cli.filter, samples, audio.sample_rate())
+                .expect("Couldn't create recording files");
+            (Box::new(audio), Some(recorder))
+        } else {
+            (audio, None)
         };
 
+        let mut wav_export = cli.wav.as_ref().map(|path| {
+            WavExport::create(path, cli.wav_stems, audio.sample_rate())
+                .expect("Couldn't create the WAV files")
+        });
+
         let serial: Box<dyn Serial> = match (
             cli.fifo_input.clone(),
             cli.fifo_output.clone(),
             &listener,
             cli.connect.clone(),
         ) {
+            (_, _, Some(listener), _) if cli.lockstep => Box::new(LinkSerial::listen(
+                listener,
+                Duration::from_secs(cli.link_timeout),
+            )),
+            (_, _, _, Some(addr)) if cli.lockstep => Box::new(LinkSerial::connect(
+                &addr,
+                Duration::from_secs(cli.link_timeout),
+            )),
             (_, _, Some(listener), _) => Box::new(desktop::serial::TcpSerial::new_listener(
                 listener.try_clone().unwrap(),
                 cli.no_response,
@@ -166,7 +687,28 @@ pub fn main() {
             (Some(fifo_input), Some(fifo_output), _, _) => Box::new(
                 desktop::serial::FIFOSerial::new(fifo_input, fifo_output, cli.no_response),
             ),
-            _ => Box::new(desktop::serial::UnconnectedSerial {}),
+            _ if cli.serial_stdout || cli.serial_log.is_some() => {
+                let mut outputs: Vec<Box<dyn std::io::Write>> = vec![];
+                if cli.serial_stdout {
+                    outputs.push(Box::new(std::io::stdout()));
+                }
+                if let Some(path) = &cli.serial_log {
+                    outputs.push(Box::new(
+                        fs::File::create(path).expect("Couldn't create the serial log file"),
+                    ));
+                }
+                let format = if cli.serial_hex {
+                    CaptureFormat::Hex
+                } else {
+                    CaptureFormat::Text
+                };
+                let reply = if cli.serial_reply_ff { 0xff } else { 0x00 };
+                Box::new(CaptureSerial::new(outputs, format, reply))
+            }
+            _ => match &cli.printer {
+                Some(directory) => Box::new(desktop::printer::PrinterSerial::new(directory)),
+                None => Box::new(desktop::serial::UnconnectedSerial {}),
+            },
         };
 
         let mut gamepad: Box<dyn Input> = if let Some(record_file) = cli.replay_input.clone() {
@@ -174,7 +716,7 @@ pub fn main() {
         } else {
             Box::new(InputCombiner::new(vec![
                 Box::new(Gamepad::new()),
-                Box::new(Keyboard::new(keys)),
+                Box::new(Keyboard::new(keys.clone())),
             ]))
         };
 
@@ -190,11 +732,31 @@ pub fn main() {
 
         #[cfg(feature = "dynamic_rom")]
         if let Some(state_file) = &cli.state_file {
-            fs_load_save = fs_load_save.state_file(state_file);
+            fs_load_save = fs_load_save.state_file(state_file).bess(cli.bess);
         }
 
         let mut gameboy =
             Gameboy::<_, _, _, _>::new(gamepad, serial, audio, fs_load_save, cli.speed as f64);
+        gameboy.audio_mut().high_pass = cli.high_pass;
+        gameboy.set_audio_sync(cli.audio_sync);
+        gameboy.audio_mut().enable_scope(audio_viewer.is_some());
+        if cli.rewind {
+            gameboy.enable_rewind(Rewind::new(
+                cli.rewind_interval,
+                cli.rewind_speed,
+                cli.rewind_memory * 1024 * 1024,
+            ));
+        }
+        if wav_export.is_some() {
+            gameboy.audio_mut().start_capture(cli.wav_stems);
+        }
+
+        let link_timeout = Duration::from_secs(cli.link_timeout);
+        if let Some(listener) = &ir_listener {
+            gameboy.set_infrared(Box::new(SocketInfrared::listen(listener, link_timeout)));
+        } else if let Some(addr) = &cli.ir_connect {
+            gameboy.set_infrared(Box::new(SocketInfrared::connect(addr, link_timeout)));
+        }
 
         if cli.load_state {
             gameboy.load_state().unwrap();
@@ -204,12 +766,231 @@ pub fn main() {
             gameboy.skip_bootrom();
         }
 
+        gameboy.display_mut().dmg_colors = palette;
+        gameboy.display_mut().cls();
+
+        if let Some(gbs) = &gbs {
+            gameboy.play_gbs_track(gbs, track);
+            log_track(gbs, track);
+        }
+        let mut track_start_frame = gameboy.frame_count();
+
+        if cli.vgm.is_some() {
+            gameboy.start_vgm_log();
+        }
+
+        let mut filter = cli.filter;
+        let mut pending_screenshot = cli.screenshot_at_frame;
+        let mut last_frame = gameboy.frame_count();
+        let mut recorded_frames = 0;
+        let mut exported_frames = 0;
+
         while gameboy.run_until_next_sleep() {
-            if let Some(fb) = gameboy.sleep_and_draw() {
-                if let Some(io::WindowSignal::Exit) = window.update(fb) {
-                    break;
+            if let Ok(keys) = keys.lock() {
+                gameboy.set_rewinding(keys.contains(&REWIND_KEY));
+            }
+
+            if gameboy.frame_count() != last_frame {
+                last_frame = gameboy.frame_count();
+                if let Some(recorder) = &mut recorder {
+                    if let Err(err) = recorder.frame(gameboy.display().framebuffer()) {
+                        elog(LogLevel::Error, format!("Failed to record frame ({})", err));
+                    }
+                    recorded_frames += 1;
+                    if Some(recorded_frames) == cli.record_frames {
+                        break;
+                    }
+                }
+                if let Some(wav_export) = &mut wav_export {
+                    if let Some(capture) = gameboy.audio_mut().take_capture() {
+                        if let Err(err) = wav_export.write(&capture) {
+                            elog(LogLevel::Error, format!("Failed to write audio ({})", err));
+                        }
+                    }
+                    exported_frames += 1;
+                    if Some(exported_frames) == cli.wav_frames {
+                        break;
+                    }
                 }
             }
+
+            if let Some(frame) = pending_screenshot {
+                if gameboy.frame_count() >= frame {
+                    let path = cli
+                        .screenshot_file
+                        .clone()
+                        .unwrap_or_else(|| gameboy.screenshot_file_name());
+                    save_screenshot(&gameboy, filter, path);
+                    pending_screenshot = None;
+                    if cli.headless {
+                        break;
+                    }
+                }
+            }
+
+            if let Some(mut fb) = gameboy.sleep_and_draw() {
+                if let Some(gbs) = &gbs {
+                    fb = gbs.info_screen(track, gameboy.frame_count() - track_start_frame);
+                }
+
+                if let Some(sender) = &viewer {
+                    // The viewer window has been closed
+                    if sender.send(vram_viewer::render(gameboy.display())).is_err() {
+                        viewer = None;
+                    }
+                }
+                if let Some(sender) = &audio_viewer {
+                    if sender.send(audio_viewer::render(gameboy.audio())).is_err() {
+                        audio_viewer = None;
+                        gameboy.audio_mut().enable_scope(false);
+                    }
+                }
+
+                if let Some((text, shown_at)) = &indicator {
+                    if shown_at.elapsed() < SLOT_INDICATOR_DURATION {
+                        slots::draw_indicator(&mut fb, text);
+                    } else {
+                        indicator = None;
+                    }
+                }
+
+                match window.update(fb) {
+                    Some(WindowSignal::Exit) => break,
+                    Some(WindowSignal::Screenshot) => {
+                        save_screenshot(&gameboy, filter, gameboy.screenshot_file_name())
+                    }
+                    Some(WindowSignal::SelectSlot(new_slot)) => {
+                        slot = new_slot;
+                        let summary = slots::slot_summary(&slot_base, slot);
+                        indicator = Some((format!("SLOT {} {}", slot, summary), Instant::now()));
+                    }
+                    Some(WindowSignal::SaveSlot) => {
+                        let text = save_slot(&gameboy, &slot_base, slot);
+                        indicator = Some((text, Instant::now()));
+                    }
+                    Some(WindowSignal::LoadSlot) => {
+                        let text = load_slot(&mut gameboy, &slot_base, slot);
+                        indicator = Some((text, Instant::now()));
+                    }
+                    Some(WindowSignal::Filter(new_filter)) => filter = new_filter,
+                    Some(WindowSignal::ToggleLayer(layer)) => {
+                        let visible = gameboy.display_mut().toggle_layer(layer);
+                        log(
+                            LogLevel::Infos,
+                            format!(
+                                "{:?} layer {}",
+                                layer,
+                                if visible { "shown" } else { "hidden" }
+                            ),
+                        );
+                    }
+                    Some(WindowSignal::SoloSprite(index)) => {
+                        gameboy.display_mut().solo_sprite(index);
+                        match index {
+                            Some(index) => {
+                                log(LogLevel::Infos, format!("Only showing sprite #{}", index))
+                            }
+                            None => log(LogLevel::Infos, "Showing all sprites"),
+                        }
+                    }
+                    Some(WindowSignal::ToggleChannelMute(channel)) => {
+                        let muted = gameboy.audio_mut().toggle_channel_mute(channel);
+                        log(
+                            LogLevel::Infos,
+                            format!(
+                                "CH{} {}",
+                                channel + 1,
+                                if muted { "muted" } else { "unmuted" }
+                            ),
+                        );
+                    }
+                    Some(WindowSignal::ToggleChannelSolo(channel)) => {
+                        let audio = gameboy.audio_mut();
+                        if audio.controls.solo == Some(channel) {
+                            audio.solo_channel(None);
+                            log(LogLevel::Infos, "Playing all the channels");
+                        } else {
+                            audio.solo_channel(Some(channel));
+                            log(LogLevel::Infos, format!("Only playing CH{}", channel + 1));
+                        }
+                    }
+                    Some(WindowSignal::CycleChannelVolume(channel)) => {
+                        let audio = gameboy.audio_mut();
+                        let volume = CHANNEL_VOLUME_STEPS
+                            .iter()
+                            .find(|v| **v < audio.controls.volumes[channel])
+                            .copied()
+                            .unwrap_or(CHANNEL_VOLUME_STEPS[0]);
+                        audio.set_channel_volume(channel, volume);
+                        log(
+                            LogLevel::Infos,
+                            format!("CH{} volume: {}%", channel + 1, volume * 100.),
+                        );
+                    }
+                    Some(WindowSignal::ToggleVgmLog) => {
+                        if let Some(vgm) = gameboy.stop_vgm_log() {
+                            let path = cli.vgm.clone().unwrap_or(gameboy.vgm_file_name());
+                            save_vgm(&path, vgm);
+                        } else {
+                            gameboy.start_vgm_log();
+                            log(LogLevel::Infos, "Logging the sound register writes");
+                        }
+                    }
+                    Some(WindowSignal::ToggleHiddenLayerColor) => {
+                        let layers = &mut gameboy.display_mut().layers;
+                        layers.hidden_color = match layers.hidden_color {
+                            None => Some(display::HIDDEN_LAYER_DEBUG_COLOR),
+                            Some(_) => None,
+                        };
+                    }
+                    Some(signal @ (WindowSignal::NextTrack | WindowSignal::PreviousTrack)) => {
+                        if let Some(gbs) = &gbs {
+                            let count = gbs.header.track_count;
+                            track = if matches!(signal, WindowSignal::NextTrack) {
+                                track % count + 1
+                            } else {
+                                if track == 1 {
+                                    count
+                                } else {
+                                    track - 1
+                                }
+                            };
+                            gameboy.play_gbs_track(gbs, track);
+                            track_start_frame = gameboy.frame_count();
+                            log_track(gbs, track);
+                        }
+                    }
+                    None => {}
+                }
+            }
+        }
+
+        if let Some(recorder) = recorder {
+            if let Err(err) = recorder.finish() {
+                elog(
+                    LogLevel::Error,
+                    format!("Failed to finish recording ({})", err),
+                );
+            }
+        }
+
+        if let Some(mut wav_export) = wav_export {
+            let result = match gameboy.audio_mut().take_capture() {
+                Some(capture) => wav_export.write(&capture),
+                None => Ok(()),
+            }
+            .and_then(|_| wav_export.finish());
+            if let Err(err) = result {
+                elog(
+                    LogLevel::Error,
+                    format!("Failed to finish the WAV export ({})", err),
+                );
+            }
+        }
+
+        if let Some(vgm) = gameboy.stop_vgm_log() {
+            let path = cli.vgm.clone().unwrap_or(gameboy.vgm_file_name());
+            save_vgm(&path, vgm);
         }
 
         if cli.stop_dump_state {
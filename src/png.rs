@@ -0,0 +1,219 @@
+// Minimal PNG encoder (8 bits RGB, no interlacing).
+// The image data is compressed with a greedy LZ77 using the fixed deflate Huffman codes,
+// which is plenty for pixel art where most lines are repeated colors.
+
+const LENGTH_BASES: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASES: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_SIZE: usize = 1 << 15;
+const MAX_CHAIN: usize = 64;
+
+/// Encodes a 0x00RRGGBB buffer of `width * height` pixels to a PNG file
+pub fn encode_rgb(pixels: &[u32], width: usize, height: usize) -> Vec<u8> {
+    let mut raw = Vec::with_capacity((width * 3 + 1) * height);
+    for line in pixels.chunks_exact(width).take(height) {
+        raw.push(0); // Filter type: None
+        for px in line {
+            raw.extend_from_slice(&px.to_be_bytes()[1..]);
+        }
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]); // 8 bits depth, RGB, deflate, no filter, no interlace
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &zlib_compress(&raw));
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for chunk in data.chunks(5552) {
+        for x in chunk {
+            a += *x as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+struct BitWriter {
+    out: Vec<u8>,
+    bits: u32,
+    n_bits: u8,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, n: u8) {
+        self.bits |= value << self.n_bits;
+        self.n_bits += n;
+        while self.n_bits >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.n_bits -= 8;
+        }
+    }
+
+    // Huffman codes are stored most significant bit first
+    fn write_code(&mut self, code: u32, n: u8) {
+        self.write(code.reverse_bits() >> (32 - n), n);
+    }
+
+    fn flush(mut self) -> Vec<u8> {
+        if self.n_bits > 0 {
+            self.out.push(self.bits as u8);
+        }
+        self.out
+    }
+
+    fn literal(&mut self, lit: u16) {
+        match lit {
+            0..=143 => self.write_code(0b00110000 + lit as u32, 8),
+            144..=255 => self.write_code(0b110010000 + (lit - 144) as u32, 9),
+            256..=279 => self.write_code((lit - 256) as u32, 7),
+            _ => self.write_code(0b11000000 + (lit - 280) as u32, 8),
+        }
+    }
+
+    fn length(&mut self, length: usize) {
+        let i = LENGTH_BASES
+            .iter()
+            .rposition(|b| *b as usize <= length)
+            .unwrap();
+        self.literal(257 + i as u16);
+        self.write(
+            (length - LENGTH_BASES[i] as usize) as u32,
+            LENGTH_EXTRA_BITS[i],
+        );
+    }
+
+    fn distance(&mut self, distance: usize) {
+        let i = DISTANCE_BASES
+            .iter()
+            .rposition(|b| *b as usize <= distance)
+            .unwrap();
+        self.write_code(i as u32, 5);
+        self.write(
+            (distance - DISTANCE_BASES[i] as usize) as u32,
+            DISTANCE_EXTRA_BITS[i],
+        );
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    ((data[0] as usize) << 10 ^ (data[1] as usize) << 5 ^ data[2] as usize) & (HASH_SIZE - 1)
+}
+
+fn insert(head: &mut [usize], prev: &mut [usize], data: &[u8], k: usize) {
+    let h = hash(&data[k..]);
+    prev[k % WINDOW_SIZE] = head[h];
+    head[h] = k;
+}
+
+pub fn zlib_compress(data: &[u8]) -> Vec<u8> {
+    let mut w = BitWriter {
+        out: vec![0x78, 0x01],
+        bits: 0,
+        n_bits: 0,
+    };
+
+    // A single final block with fixed Huffman codes
+    w.write(1, 1);
+    w.write(1, 2);
+
+    // head: last position of each hash, prev: previous position with the same hash
+    let mut head = vec![usize::MAX; HASH_SIZE];
+    let mut prev = vec![usize::MAX; WINDOW_SIZE];
+    let mut i = 0;
+    while i < data.len() {
+        let mut best = (0, 0);
+
+        if i + MIN_MATCH <= data.len() {
+            let max = (data.len() - i).min(MAX_MATCH);
+            let mut candidate = head[hash(&data[i..])];
+            let mut chain = 0;
+
+            while candidate != usize::MAX && i - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let len = (0..max)
+                    .take_while(|k| data[candidate + k] == data[i + k])
+                    .count();
+                if len > best.0 {
+                    best = (len, i - candidate);
+                    if len == max {
+                        break;
+                    }
+                }
+                let next = prev[candidate % WINDOW_SIZE];
+                if next >= candidate {
+                    break;
+                }
+                candidate = next;
+                chain += 1;
+            }
+            insert(&mut head, &mut prev, data, i);
+        }
+
+        if best.0 >= MIN_MATCH {
+            w.length(best.0);
+            w.distance(best.1);
+            for k in i + 1..(i + best.0).min(data.len().saturating_sub(MIN_MATCH - 1)) {
+                insert(&mut head, &mut prev, data, k);
+            }
+            i += best.0;
+        } else {
+            w.literal(data[i] as u16);
+            i += 1;
+        }
+    }
+    w.literal(256);
+
+    let mut out = w.flush();
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
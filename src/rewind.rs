@@ -0,0 +1,206 @@
+// Rewind buffer. A save state (see `save_state::save`) is taken every few frames and kept in
+// memory: the newest one complete and the older ones as deltas giving each snapshot from the one
+// that follows it. Going back one snapshot only decodes one delta, and the oldest snapshots are
+// dropped without touching the others when the buffer is full.
+//
+// A delta is the size of the snapshot (u32 LE) followed by pairs of runs until the snapshot is
+// complete:
+//   bytes copied from the newer snapshot (LEB128) | literal bytes (LEB128) | literals
+
+use std::collections::VecDeque;
+
+use crate::io::{Audio, Serial};
+use crate::logs::{elog, LogLevel};
+use crate::save_state;
+use crate::state::GBState;
+
+// Equal bytes needed to end a literal run, shorter ones cost more as a copy run than as literals
+const MIN_COPY: usize = 4;
+
+pub struct Rewind {
+    // Frames between two snapshots
+    interval: u32,
+    // Frames of the game rewound per frame shown
+    speed: f32,
+    // Maximum number of bytes used by the snapshots
+    capacity: usize,
+    newest: Option<Vec<u8>>,
+    // From the oldest snapshot to the one before the newest
+    deltas: VecDeque<Vec<u8>>,
+    deltas_size: usize,
+    // Frames run since the newest snapshot
+    frames: u32,
+    // Frames rewound that didn't reach the previous snapshot yet
+    progress: f32,
+    // Set with the first snapshot
+    layout: Option<save_state::Layout>,
+}
+
+impl Rewind {
+    pub fn new(interval: u32, speed: f32, capacity: usize) -> Self {
+        Self {
+            interval: interval.max(1),
+            speed,
+            capacity,
+            newest: None,
+            deltas: VecDeque::new(),
+            deltas_size: 0,
+            frames: 0,
+            progress: 0.,
+            layout: None,
+        }
+    }
+
+    /// Number of snapshots in the buffer
+    pub fn len(&self) -> usize {
+        self.deltas.len() + self.newest.is_some() as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.newest.is_none()
+    }
+
+    /// Bytes used by the snapshots
+    pub fn size(&self) -> usize {
+        self.deltas_size + self.newest.as_ref().map_or(0, Vec::len)
+    }
+
+    /// To call at the end of each frame run, takes a snapshot every `interval` frames
+    pub fn frame<S: Serial, A: Audio>(&mut self, state: &GBState<S, A>) {
+        self.progress = 0.;
+        self.frames += 1;
+        if self.newest.is_none() || self.frames >= self.interval {
+            let layout = self
+                .layout
+                .get_or_insert_with(|| save_state::Layout::new(state));
+            let snapshot = save_state::save_with_layout(state, layout);
+            self.push(snapshot);
+            self.frames = 0;
+        }
+    }
+
+    fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(previous) = self.newest.take() {
+            let delta = encode(&snapshot, &previous);
+            self.deltas_size += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.newest = Some(snapshot);
+
+        while self.size() > self.capacity {
+            match self.deltas.pop_front() {
+                Some(delta) => self.deltas_size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// To call instead of running a frame while rewinding: goes back `speed` frames and loads
+    /// the snapshot reached, if any. The first step goes back to the newest snapshot. Returns
+    /// false once the oldest snapshot is reached.
+    pub fn step_back<S: Serial, A: Audio>(&mut self, state: &mut GBState<S, A>) -> bool {
+        let Some(mut newest) = self.newest.take() else {
+            return false;
+        };
+
+        self.progress += self.speed;
+        let mut restored = false;
+        let mut available = true;
+        loop {
+            let distance = if self.frames > 0 {
+                self.frames
+            } else {
+                self.interval
+            } as f32;
+            if self.progress < distance {
+                break;
+            }
+            if self.frames == 0 {
+                match self.deltas.pop_back() {
+                    Some(delta) => {
+                        self.deltas_size -= delta.len();
+                        newest = decode(&delta, &newest);
+                    }
+                    None => {
+                        self.progress = 0.;
+                        available = false;
+                        break;
+                    }
+                }
+            }
+            self.progress -= distance;
+            self.frames = 0;
+            restored = true;
+        }
+
+        if let (true, Some(layout)) = (restored, &self.layout) {
+            if let Err(err) = save_state::load_with_layout(state, &newest, layout) {
+                elog(
+                    LogLevel::Error,
+                    format!("Failed to load the rewind snapshot ({})", err),
+                );
+            }
+        }
+        self.newest = Some(newest);
+        available
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    while let Some(&byte) = data.get(*pos) {
+        *pos += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            break;
+        }
+    }
+    value
+}
+
+// Delta giving `target` from `base`
+fn encode(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let same = |i: usize| base.get(i) == Some(&target[i]);
+
+    let mut out = (target.len() as u32).to_le_bytes().to_vec();
+    let mut i = 0;
+    while i < target.len() {
+        let copy_start = i;
+        while i < target.len() && same(i) {
+            i += 1;
+        }
+        write_varint(&mut out, i - copy_start);
+
+        let literal_start = i;
+        while i < target.len() && !(i..(i + MIN_COPY).min(target.len())).all(same) {
+            i += 1;
+        }
+        write_varint(&mut out, i - literal_start);
+        out.extend_from_slice(&target[literal_start..i]);
+    }
+    out
+}
+
+fn decode(delta: &[u8], base: &[u8]) -> Vec<u8> {
+    let size = u32::from_le_bytes(delta[..4].try_into().unwrap()) as usize;
+    let mut out = Vec::with_capacity(size);
+    let mut pos = 4;
+    while out.len() < size && pos < delta.len() {
+        let copy = read_varint(delta, &mut pos);
+        out.extend_from_slice(&base[out.len()..out.len() + copy]);
+        let literals = read_varint(delta, &mut pos);
+        out.extend_from_slice(&delta[pos..pos + literals]);
+        pos += literals;
+    }
+    out
+}
@@ -0,0 +1,435 @@
+// Save states. The file starts with a header:
+//   magic "GBSTATE\0" | version (u16 LE) | CRC32 of the ROM (u32 LE)
+// followed by tagged sections:
+//   tag (4 bytes) | length (u32 LE) | data
+// Each section holds the complete state of one component, including the internal state that
+// can't be seen through the registers (timer counters, PPU dot, APU timers, ...). A state is
+// loaded by setting the fields directly, so it doesn't go through the side effects of the
+// register writes (OAM DMA, channel triggers, DIV reset...).
+//
+// The other side of the serial port isn't part of the state, so a transfer started by the game
+// with its internal clock isn't resumed (see `restore_serial`).
+//
+// The sections of one version always have the same size, so a state is checked completely
+// before anything is loaded and a bad file leaves the emulator untouched. Unknown sections are
+// skipped, like the optional ones giving the time the state was made and a thumbnail of the
+// screen (see `save_with_info`).
+
+use crate::bess;
+use crate::io::{Audio, Serial};
+use crate::png::{self, crc32};
+use crate::state::{GBState, Memory};
+
+pub const MAGIC: [u8; 8] = *b"GBSTATE\0";
+pub const VERSION: u16 = 1;
+
+const HEADER_SIZE: usize = 14;
+
+const CPU: [u8; 4] = *b"CPU ";
+const MEM: [u8; 4] = *b"MEM ";
+const TIMER: [u8; 4] = *b"TIMR";
+const PPU: [u8; 4] = *b"PPU ";
+const APU: [u8; 4] = *b"APU ";
+const SERIAL: [u8; 4] = *b"SER ";
+
+const SECTIONS: [[u8; 4]; 6] = [CPU, MEM, TIMER, PPU, APU, SERIAL];
+
+// Seconds since the Unix epoch (u64 LE)
+const TIME: [u8; 4] = *b"TIME";
+// PNG file
+const THUMBNAIL: [u8; 4] = *b"THMB";
+
+pub const THUMBNAIL_WIDTH: usize = 80;
+pub const THUMBNAIL_HEIGHT: usize = 72;
+
+// Largest ROM of the emulated cartridges
+const MAX_ROM_SIZE: usize = 0x200000;
+
+/// Little endian writer of the state of a component
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    fn new() -> Self {
+        Self { data: vec![] }
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend(value.to_le_bytes());
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+/// Reader of a section written by `StateWriter`. The size of the section is checked before it
+/// is read, so the reads can't run past its end.
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl StateReader<'_> {
+    fn take(&mut self, n: usize) -> &[u8] {
+        let (bytes, rest) = self.data.split_at(n);
+        self.data = rest;
+        bytes
+    }
+
+    pub fn u8(&mut self) -> u8 {
+        self.take(1)[0]
+    }
+
+    pub fn bool(&mut self) -> bool {
+        self.u8() != 0
+    }
+
+    pub fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.take(2).try_into().unwrap())
+    }
+
+    pub fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.take(4).try_into().unwrap())
+    }
+
+    pub fn i32(&mut self) -> i32 {
+        i32::from_le_bytes(self.take(4).try_into().unwrap())
+    }
+
+    pub fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.take(8).try_into().unwrap())
+    }
+
+    pub fn bytes(&mut self, bytes: &mut [u8]) {
+        bytes.copy_from_slice(self.take(bytes.len()));
+    }
+}
+
+/// CRC32 of the ROM, with the size given by its header
+pub fn rom_checksum(rom: &[u8]) -> u32 {
+    let size = (0x8000usize << rom[0x148].min(8)).min(MAX_ROM_SIZE);
+    crc32(&rom[..size])
+}
+
+fn write_section<S: Serial, A: Audio>(tag: [u8; 4], state: &GBState<S, A>, w: &mut StateWriter) {
+    let mem = &state.mem;
+    match tag {
+        CPU => {
+            w.bytes(&state.cpu.r);
+            w.u16(state.cpu.pc);
+            w.u16(state.cpu.sp);
+            w.bool(mem.ime);
+            w.bool(mem.halt);
+            w.bool(state.is_stopped);
+        }
+        MEM => {
+            w.bool(mem.boot_rom_on);
+            w.bool(mem.cgb_mode);
+            w.u8(mem.rom_bank);
+            w.u8(mem.ram_bank);
+            w.bool(mem.ram_bank_enabled);
+            w.bytes(mem.wram_00.as_ref());
+            w.bytes(mem.wram_01.as_ref());
+            w.bytes(mem.external_ram.as_ref());
+            w.bytes(mem.hram.as_ref());
+            // Last values written to the registers, as read by the ones without their own state
+            w.bytes(mem.io.as_ref());
+            w.u8(mem.interrupts_register);
+            w.u8(mem.joypad_reg);
+            w.bool(mem.joypad_is_action);
+            w.u8(mem.bgcram_pointer);
+            w.bool(mem.bgcram_pointer_autoincrement);
+            w.u8(mem.obcram_pointer);
+            w.bool(mem.obcram_pointer_autoincrement);
+        }
+        TIMER => {
+            w.u8(mem.div);
+            w.u64(state.div_cycles);
+            w.u8(mem.tima);
+            w.u8(mem.tma);
+            w.bool(mem.timer_enabled);
+            w.u8(mem.timer_speed);
+            w.u64(state.tima_cycles);
+        }
+        PPU => mem.display.write_state(w),
+        APU => mem.audio.write_state(w),
+        SERIAL => {
+            w.u8(mem.serial.read_data());
+            w.u8(mem.serial.read_control());
+        }
+        _ => unreachable!(),
+    }
+}
+
+fn read_section<S: Serial, A: Audio>(tag: [u8; 4], state: &mut GBState<S, A>, r: &mut StateReader) {
+    let mem = &mut state.mem;
+    match tag {
+        CPU => {
+            r.bytes(&mut state.cpu.r);
+            state.cpu.pc = r.u16();
+            state.cpu.sp = r.u16();
+            mem.ime = r.bool();
+            mem.halt = r.bool();
+            state.is_stopped = r.bool();
+        }
+        MEM => {
+            mem.boot_rom_on = r.bool();
+            mem.cgb_mode = r.bool();
+            mem.rom_bank = r.u8() & 0x7f;
+            mem.ram_bank = r.u8() & 0b11;
+            mem.ram_bank_enabled = r.bool();
+            r.bytes(mem.wram_00.as_mut());
+            r.bytes(mem.wram_01.as_mut());
+            r.bytes(mem.external_ram.as_mut());
+            r.bytes(mem.hram.as_mut());
+            r.bytes(mem.io.as_mut());
+            mem.interrupts_register = r.u8();
+            mem.joypad_reg = r.u8();
+            mem.joypad_is_action = r.bool();
+            mem.bgcram_pointer = r.u8() & 0b111111;
+            mem.bgcram_pointer_autoincrement = r.bool();
+            mem.obcram_pointer = r.u8() & 0b111111;
+            mem.obcram_pointer_autoincrement = r.bool();
+        }
+        TIMER => {
+            mem.div = r.u8();
+            state.div_cycles = r.u64();
+            mem.tima = r.u8();
+            mem.tma = r.u8();
+            mem.timer_enabled = r.bool();
+            mem.timer_speed = r.u8() & 0b11;
+            state.tima_cycles = r.u64();
+        }
+        PPU => mem.display.read_state(r),
+        APU => mem.audio.read_state(r),
+        SERIAL => {
+            let data = r.u8();
+            let control = r.u8();
+            restore_serial(mem, data, control);
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Sets the registers of the serial port. A transfer the game started with the internal clock
+/// would send its byte again to the other side, which has moved on, so it ends at once instead
+/// like with nothing connected: 0xff is received and the serial interrupt is requested. Waiting
+/// for a transfer clocked by the other side doesn't send anything and is kept.
+pub(crate) fn restore_serial<S: Serial, A: Audio>(mem: &mut Memory<S, A>, data: u8, control: u8) {
+    if control & 0x81 == 0x81 {
+        mem.serial.write_data(0xff);
+        mem.serial.write_control(control & 0x7f);
+        mem.io[0x0f] |= 0b1000;
+    } else {
+        mem.serial.write_data(data);
+        mem.serial.write_control(control);
+    }
+}
+
+/// What the states of one emulator have in common: the checksum of its ROM and the size of each
+/// section, which don't change while it runs. Kept by the ones saving and loading states often
+/// (see `rewind`) so they are computed once.
+pub struct Layout {
+    checksum: u32,
+    sizes: [usize; SECTIONS.len()],
+}
+
+impl Layout {
+    pub fn new<S: Serial, A: Audio>(state: &GBState<S, A>) -> Self {
+        Self {
+            checksum: rom_checksum(state.mem.rom.as_ref()),
+            sizes: SECTIONS.map(|tag| {
+                let mut w = StateWriter::new();
+                write_section(tag, state, &mut w);
+                w.data.len()
+            }),
+        }
+    }
+}
+
+/// Complete state of the emulator
+pub fn save<S: Serial, A: Audio>(state: &GBState<S, A>) -> Vec<u8> {
+    write(state, rom_checksum(state.mem.rom.as_ref()))
+}
+
+/// `save` without computing the checksum of the ROM again
+pub fn save_with_layout<S: Serial, A: Audio>(state: &GBState<S, A>, layout: &Layout) -> Vec<u8> {
+    write(state, layout.checksum)
+}
+
+fn write<S: Serial, A: Audio>(state: &GBState<S, A>, checksum: u32) -> Vec<u8> {
+    let mut data = Vec::with_capacity(0x40000);
+    data.extend(MAGIC);
+    data.extend(VERSION.to_le_bytes());
+    data.extend(checksum.to_le_bytes());
+
+    for tag in SECTIONS {
+        let mut w = StateWriter::new();
+        write_section(tag, state, &mut w);
+        push_section(&mut data, tag, &w.data);
+    }
+
+    data
+}
+
+fn push_section(data: &mut Vec<u8>, tag: [u8; 4], section: &[u8]) {
+    data.extend(tag);
+    data.extend((section.len() as u32).to_le_bytes());
+    data.extend_from_slice(section);
+}
+
+// Screen at half size, each pixel being the average of 4
+fn thumbnail(framebuffer: &[u32; 160 * 144]) -> Vec<u8> {
+    let mut pixels = Vec::with_capacity(THUMBNAIL_WIDTH * THUMBNAIL_HEIGHT);
+    for y in 0..THUMBNAIL_HEIGHT {
+        for x in 0..THUMBNAIL_WIDTH {
+            let mut sum = [0; 3];
+            for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                let pixel = framebuffer[(y * 2 + dy) * 160 + x * 2 + dx];
+                for (i, channel) in sum.iter_mut().enumerate() {
+                    *channel += (pixel >> (16 - i * 8)) & 0xff;
+                }
+            }
+            pixels.push(((sum[0] / 4) << 16) | ((sum[1] / 4) << 8) | (sum[2] / 4));
+        }
+    }
+    png::encode_rgb(&pixels, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT)
+}
+
+/// `save` with the time it was made (seconds since the Unix epoch) and a thumbnail of the
+/// screen, for the states kept by the user
+pub fn save_with_info<S: Serial, A: Audio>(state: &GBState<S, A>, timestamp: u64) -> Vec<u8> {
+    let mut data = save(state);
+    push_section(&mut data, TIME, &timestamp.to_le_bytes());
+    push_section(
+        &mut data,
+        THUMBNAIL,
+        &thumbnail(state.mem.display.framebuffer()),
+    );
+    data
+}
+
+/// Optional information of a state saved by `save_with_info`
+#[derive(Debug, Clone, Default)]
+pub struct StateInfo {
+    pub timestamp: Option<u64>,
+    // PNG file of THUMBNAIL_WIDTH x THUMBNAIL_HEIGHT
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+fn check_header(data: &[u8]) -> Result<(), String> {
+    if data.len() < HEADER_SIZE || data[..8] != MAGIC {
+        return Err("Not a save state".to_string());
+    }
+    let version = u16::from_le_bytes(data[8..10].try_into().unwrap());
+    if version != VERSION {
+        return Err(format!("Unsupported save state version {}", version));
+    }
+    Ok(())
+}
+
+/// Reads the information of a state without loading it
+pub fn read_info(data: &[u8]) -> Result<StateInfo, String> {
+    check_header(data)?;
+    let mut info = StateInfo::default();
+    for (tag, section) in parse_sections(data)? {
+        match tag {
+            TIME if section.len() == 8 => {
+                info.timestamp = Some(u64::from_le_bytes(section.try_into().unwrap()))
+            }
+            THUMBNAIL => info.thumbnail = Some(section.to_vec()),
+            _ => {}
+        }
+    }
+    Ok(info)
+}
+
+// Tag and data of a section
+type Section<'a> = ([u8; 4], &'a [u8]);
+
+// Sections of the state, in the order of the file
+fn parse_sections(data: &[u8]) -> Result<Vec<Section<'_>>, String> {
+    let mut sections = vec![];
+    let mut data = &data[HEADER_SIZE..];
+    while !data.is_empty() {
+        if data.len() < 8 {
+            return Err("Truncated save state".to_string());
+        }
+        let tag: [u8; 4] = data[..4].try_into().unwrap();
+        let length = u32::from_le_bytes(data[4..8].try_into().unwrap()) as usize;
+        if data.len() - 8 < length {
+            return Err("Truncated save state".to_string());
+        }
+        sections.push((tag, &data[8..8 + length]));
+        data = &data[8 + length..];
+    }
+    Ok(sections)
+}
+
+/// Restores a state written by `save`, or a BESS state (see `bess`). Nothing is changed if it
+/// can't be loaded.
+pub fn load<S: Serial, A: Audio>(state: &mut GBState<S, A>, data: &[u8]) -> Result<(), String> {
+    if !data.starts_with(&MAGIC) && bess::is_bess(data) {
+        return bess::import(state, data);
+    }
+    let layout = Layout::new(state);
+    load_with_layout(state, data, &layout)
+}
+
+/// `load` of a state written by `save`, checked against the layout of the emulator instead of
+/// computing it again
+pub fn load_with_layout<S: Serial, A: Audio>(
+    state: &mut GBState<S, A>,
+    data: &[u8],
+    layout: &Layout,
+) -> Result<(), String> {
+    check_header(data)?;
+    let checksum = u32::from_le_bytes(data[10..14].try_into().unwrap());
+    if checksum != layout.checksum {
+        return Err("The save state was made with another ROM".to_string());
+    }
+
+    let sections = parse_sections(data)?;
+    let mut found = vec![];
+    for (tag, size) in SECTIONS.into_iter().zip(layout.sizes) {
+        let Some((_, section)) = sections.iter().find(|(t, _)| *t == tag) else {
+            return Err(format!(
+                "Missing section \"{}\" in the save state",
+                String::from_utf8_lossy(&tag)
+            ));
+        };
+        if section.len() != size {
+            return Err(format!(
+                "Bad size of the section \"{}\" in the save state",
+                String::from_utf8_lossy(&tag)
+            ));
+        }
+        found.push((tag, *section));
+    }
+
+    for (tag, section) in found {
+        read_section(tag, state, &mut StateReader { data: section });
+    }
+    Ok(())
+}
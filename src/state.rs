@@ -1,8 +1,9 @@
 use crate::audio::Channels;
 use crate::consts::{PROGRAM_START_ADDRESS, STACK_START_ADDRESS};
 use crate::display::Display;
-use crate::io::{Audio, Serial};
+use crate::io::{Audio, Infrared, NoInfrared, Serial};
 use crate::logs::{log, LogLevel};
+use crate::vgm::VgmLog;
 
 pub mod reg {
     pub const B: u8 = 0;
@@ -147,8 +148,13 @@ pub struct Memory<S: Serial, A: Audio> {
 
     pub audio: Channels<A>,
 
+    // Log of the writes to the sound registers, if one is being recorded
+    pub vgm: Option<VgmLog>,
+
     pub serial: S,
 
+    pub infrared: Box<dyn Infrared>,
+
     pub ime: bool,
 
     pub div: u8,
@@ -197,6 +203,7 @@ impl<S: Serial, A: Audio> Memory<S, A> {
             io: Box::new([0; 0x80]),
             hram: Box::new([0; 0x7f]),
             audio: Channels::new(audio),
+            vgm: None,
             ime: false,
             interrupts_register: 0,
             joypad_is_action: false,
@@ -208,9 +215,22 @@ impl<S: Serial, A: Audio> Memory<S, A> {
             timer_enabled: false,
             timer_speed: 0,
             serial,
+            infrared: Box::new(NoInfrared),
         }
     }
 
+    /// Title from the cartridge header (0x134-0x143)
+    pub fn rom_title(&self) -> String {
+        self.rom[0x134..0x144]
+            .iter()
+            .take_while(|c| **c != 0)
+            .filter(|c| c.is_ascii_graphic() || **c == b' ')
+            .map(|c| *c as char)
+            .collect::<String>()
+            .trim()
+            .to_string()
+    }
+
     pub fn update_serial(&mut self, cycles: u128) {
         if self.serial.update_serial(cycles) {
             self.io[0x0f] |= 0b1000;
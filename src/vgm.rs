@@ -0,0 +1,95 @@
+// Log of the writes to the sound registers in the VGM 1.71 format, which has a command for the
+// DMG sound chip. Players and trackers replay the writes on their own model of the APU, so the
+// log contains the exact soundtrack.
+
+use crate::consts::CPU_CLOCK_SPEED;
+
+const VERSION: u32 = 0x171;
+const HEADER_SIZE: usize = 0x100;
+
+// Waits are counted in samples at 44.1kHz whatever the chip
+const SAMPLE_RATE: u64 = 44100;
+
+const CMD_GB_WRITE: u8 = 0xb3;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC_FRAME: u8 = 0x62;
+const CMD_WAIT_PAL_FRAME: u8 = 0x63;
+// 0x70-0x7f wait 1 to 16 samples
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END: u8 = 0x66;
+
+pub struct VgmLog {
+    commands: Vec<u8>,
+    // Cycles since the start of the log
+    cycles: u64,
+    // Samples already covered by the wait commands
+    samples: u64,
+}
+
+impl VgmLog {
+    /// Log starting with `initial_writes` (address, value), which bring the chip to the current
+    /// state of the APU (see `Channels::register_writes`)
+    pub fn new(initial_writes: &[(u8, u8)]) -> Self {
+        let mut log = Self {
+            commands: vec![],
+            cycles: 0,
+            samples: 0,
+        };
+        for (addr, value) in initial_writes {
+            log.write(*addr, *value);
+        }
+        log
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    /// Write to a sound register (0xff10-0xff3f) at the current time
+    pub fn write(&mut self, addr: u8, value: u8) {
+        self.wait();
+        self.commands.extend([CMD_GB_WRITE, addr - 0x10, value]);
+    }
+
+    // Adds the waits up to the current time
+    fn wait(&mut self) {
+        let now = self.cycles * SAMPLE_RATE / CPU_CLOCK_SPEED;
+        let mut samples = now - self.samples;
+        self.samples = now;
+
+        while samples > 0 {
+            let wait = samples.min(u16::MAX as u64);
+            match wait {
+                735 => self.commands.push(CMD_WAIT_NTSC_FRAME),
+                882 => self.commands.push(CMD_WAIT_PAL_FRAME),
+                1..=16 => self.commands.push(CMD_WAIT_SHORT + wait as u8 - 1),
+                _ => {
+                    self.commands.push(CMD_WAIT);
+                    self.commands.extend((wait as u16).to_le_bytes());
+                }
+            }
+            samples -= wait;
+        }
+    }
+
+    /// Ends the log at the current time and returns the content of the VGM file
+    pub fn finish(mut self) -> Vec<u8> {
+        self.wait();
+        self.commands.push(CMD_END);
+
+        let mut file = vec![0; HEADER_SIZE];
+        let mut set = |offset: usize, value: u32| {
+            file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        set(0x00, u32::from_le_bytes(*b"Vgm "));
+        set(0x04, (HEADER_SIZE + self.commands.len() - 4) as u32);
+        set(0x08, VERSION);
+        set(0x18, self.samples as u32);
+        // Relative to the field itself
+        set(0x34, (HEADER_SIZE - 0x34) as u32);
+        set(0x80, CPU_CLOCK_SPEED as u32);
+
+        file.extend(self.commands);
+        file
+    }
+}
@@ -0,0 +1,275 @@
+// Debug view of the video memory: tile data of both banks, the two tilemaps with the
+// viewport and window rectangles, the 40 OAM entries and the DMG/CGB palettes.
+
+use crate::canvas::{Canvas, GLYPH_HEIGHT};
+use crate::display::{lcdc_flags, Display};
+
+const MARGIN: usize = 8;
+const OAM_CELL_WIDTH: usize = 72;
+const OAM_CELL_HEIGHT: usize = 36;
+
+pub const WIDTH: usize = MARGIN * 2 + OAM_CELL_WIDTH * 8;
+pub const HEIGHT: usize = MARGIN * 4 + (GLYPH_HEIGHT + 2) * 3 + 192 + 256 + OAM_CELL_HEIGHT * 5;
+
+const BACKGROUND: u32 = 0x00202020;
+const CELL_BACKGROUND: u32 = 0x00383838;
+const TEXT: u32 = 0x00e0e0e0;
+const VIEWPORT: u32 = 0x00ff3030;
+const WINDOW: u32 = 0x0030a0ff;
+
+fn tile_pixel(display: &Display, tile_pointer: usize, x: usize, y: usize) -> u8 {
+    let tiledata = display.tiledata();
+    let b = 7 - x;
+    ((tiledata[tile_pointer + y * 2] >> b) & 1)
+        | (((tiledata[tile_pointer + y * 2 + 1] >> b) & 1) << 1)
+}
+
+// BG palette 0 in CGB mode, BGP otherwise
+fn bg_palette(display: &Display, cgb_palette: u8) -> u8 {
+    if display.cgb_mode {
+        cgb_palette
+    } else {
+        display.bg_palette
+    }
+}
+
+/// All the tiles of the VRAM bank 0 (left) and 1 (right), 16 tiles per line
+pub fn tiles(display: &Display) -> Canvas {
+    let mut canvas = Canvas::new(256, 192, BACKGROUND);
+    let palette = bg_palette(display, 0);
+
+    for bank in 0..2 {
+        for tile in 0..384 {
+            let tile_pointer = bank * 0x1800 + tile * 16;
+            let tx = bank * 128 + (tile % 16) * 8;
+            let ty = (tile / 16) * 8;
+            for y in 0..8 {
+                for x in 0..8 {
+                    let data = tile_pixel(display, tile_pointer, x, y);
+                    canvas.set(
+                        (tx + x) as i32,
+                        (ty + y) as i32,
+                        display.color_palette(data, palette, display.cgb_mode),
+                    );
+                }
+            }
+        }
+    }
+
+    canvas
+}
+
+/// One of the 32x32 tilemaps (0: 0x9800, 1: 0x9c00). The part shown by the scrolling
+/// registers is outlined in red and the window in blue.
+pub fn tilemap(display: &Display, map: usize) -> Canvas {
+    let mut canvas = Canvas::new(256, 256, BACKGROUND);
+
+    for i in 0..0x400 {
+        let tile = display.tilemaps()[map * 0x400 + i];
+        // Same attributes as the ones used when rendering the background
+        let attr = if display.cgb_mode {
+            display.bg_map_attr()[i]
+        } else {
+            0
+        };
+
+        let tile_pointer = if display.lcdc & lcdc_flags::BG_TILEDATA_AREA != 0 {
+            (tile as usize) << 4
+        } else {
+            ((tile as i8 as i32) * 16 + 0x1000) as usize
+        } + if attr & 0b1000 != 0 { 0x1800 } else { 0 };
+        let x_flip = attr & 0b100000 != 0;
+        let y_flip = attr & 0b1000000 != 0;
+        let palette = bg_palette(display, attr & 0b111);
+
+        for y in 0..8 {
+            for x in 0..8 {
+                let data = tile_pixel(
+                    display,
+                    tile_pointer,
+                    if x_flip { 7 - x } else { x },
+                    if y_flip { 7 - y } else { y },
+                );
+                canvas.set(
+                    ((i % 32) * 8 + x) as i32,
+                    ((i / 32) * 8 + y) as i32,
+                    display.color_palette(data, palette, display.cgb_mode),
+                );
+            }
+        }
+    }
+
+    let bg_map = (display.lcdc & lcdc_flags::BG_TILEMAP_AREA != 0) as usize;
+    let win_map = (display.lcdc & lcdc_flags::WIN_TILEMAP_AREA != 0) as usize;
+
+    if display.lcdc & lcdc_flags::WIN_ENABLE != 0
+        && map == win_map
+        && display.window_y < 144
+        && display.window_x < 167
+    {
+        canvas.rect(
+            0,
+            0,
+            167 - display.window_x.max(7) as usize,
+            144 - display.window_y as usize,
+            WINDOW,
+        );
+    }
+
+    if map == bg_map {
+        // The viewport wraps around the tilemap
+        let (x, y) = (display.viewport_x as usize, display.viewport_y as usize);
+        for d in 0..160 {
+            canvas.set(((x + d) % 256) as i32, y as i32, VIEWPORT);
+            canvas.set(((x + d) % 256) as i32, ((y + 143) % 256) as i32, VIEWPORT);
+        }
+        for d in 0..144 {
+            canvas.set(x as i32, ((y + d) % 256) as i32, VIEWPORT);
+            canvas.set(((x + 159) % 256) as i32, ((y + d) % 256) as i32, VIEWPORT);
+        }
+    }
+
+    canvas
+}
+
+/// The 40 objects, drawn at twice their size with their position, tile and attributes
+pub fn oam(display: &Display) -> Canvas {
+    let mut canvas = Canvas::new(OAM_CELL_WIDTH * 8, OAM_CELL_HEIGHT * 5, BACKGROUND);
+    let tall = display.lcdc & lcdc_flags::OBJ_SIZE != 0;
+
+    for o in 0..40 {
+        let entry = &display.oam()[o * 4..o * 4 + 4];
+        let (y, x, tile, attr) = (entry[0], entry[1], entry[2], entry[3]);
+        let cx = ((o % 8) * OAM_CELL_WIDTH) as i32;
+        let cy = ((o / 8) * OAM_CELL_HEIGHT) as i32;
+
+        canvas.rect(cx, cy, OAM_CELL_WIDTH, OAM_CELL_HEIGHT, CELL_BACKGROUND);
+        canvas.fill_rect(cx + 2, cy + 2, 16, 32, CELL_BACKGROUND);
+
+        let (first_tile, height) = if tall { (tile & 0xfe, 16) } else { (tile, 8) };
+        let tile_pointer = ((first_tile as usize) << 4)
+            + if display.cgb_mode && attr & 0b1000 != 0 {
+                0x1800
+            } else {
+                0
+            };
+        let palette = if display.cgb_mode {
+            (attr & 0b111) + 8
+        } else {
+            display.obj_palettes[((attr >> 4) & 1) as usize]
+        };
+
+        for py in 0..height {
+            for px in 0..8 {
+                let data = tile_pixel(display, tile_pointer, px, py);
+                if data != 0 {
+                    canvas.fill_rect(
+                        cx + 2 + px as i32 * 2,
+                        cy + 2 + py as i32 * 2,
+                        2,
+                        2,
+                        display.color_palette(data, palette, display.cgb_mode),
+                    );
+                }
+            }
+        }
+
+        let palette_line = if display.cgb_mode {
+            format!("PAL:{} VB:{}", attr & 0b111, (attr >> 3) & 1)
+        } else {
+            format!("OBP{}", (attr >> 4) & 1)
+        };
+        let flags_line = format!(
+            "{} {} {}",
+            if attr & 0b100000 != 0 { "XF" } else { "--" },
+            if attr & 0b1000000 != 0 { "YF" } else { "--" },
+            if attr & 0b10000000 != 0 { "BG" } else { "--" },
+        );
+        let lines = [
+            format!("#{:02} X:{:02X}", o, x),
+            format!("Y:{:02X} T:{:02X}", y, tile),
+            palette_line,
+            flags_line,
+        ];
+        for (i, line) in lines.iter().enumerate() {
+            canvas.text(cx + 22, cy + 4 + (i * GLYPH_HEIGHT) as i32, line, TEXT);
+        }
+    }
+
+    canvas
+}
+
+/// DMG palette registers and the 8 BG and 8 OBJ palettes of the CGB CRAM
+pub fn palettes(display: &Display) -> Canvas {
+    let mut canvas = Canvas::new(256, 192, BACKGROUND);
+
+    let swatches = |canvas: &mut Canvas, x: i32, y: i32, label: &str, colors: [u32; 4]| {
+        canvas.text(x, y + 3, label, TEXT);
+        for (i, color) in colors.iter().enumerate() {
+            canvas.fill_rect(x + 24 + i as i32 * 14, y, 12, 12, *color);
+        }
+    };
+
+    canvas.text(0, 0, "DMG", TEXT);
+    for (i, (label, register)) in [
+        ("BGP", display.bg_palette),
+        ("OBP0", display.obj_palettes[0]),
+        ("OBP1", display.obj_palettes[1]),
+    ]
+    .iter()
+    .enumerate()
+    {
+        let colors = [0, 1, 2, 3].map(|c| display.color_palette(c, *register, false));
+        swatches(&mut canvas, 0, 8 + i as i32 * 14, label, colors);
+    }
+
+    canvas.text(0, 56, "CGB BG", TEXT);
+    canvas.text(128, 56, "CGB OBJ", TEXT);
+    for p in 0..8 {
+        let y = 64 + p as i32 * 14;
+        let bg = [0, 1, 2, 3].map(|c| display.color_palette(c, p, true));
+        let obj = [0, 1, 2, 3].map(|c| display.color_palette(c, p + 8, true));
+        swatches(&mut canvas, 0, y, &p.to_string(), bg);
+        swatches(&mut canvas, 128, y, &p.to_string(), obj);
+    }
+
+    canvas
+}
+
+/// All the viewers in a single `WIDTH` x `HEIGHT` image
+pub fn render(display: &Display) -> Canvas {
+    let mut canvas = Canvas::new(WIDTH, HEIGHT, BACKGROUND);
+    let label_height = GLYPH_HEIGHT + 2;
+    let m = MARGIN as i32;
+
+    let mut y = m;
+    canvas.text(m, y, "TILES (BANK 0 / BANK 1)", TEXT);
+    canvas.text(m * 2 + 256, y, "PALETTES", TEXT);
+    y += label_height as i32;
+    canvas.blit(m, y, &tiles(display));
+    canvas.blit(m * 2 + 256, y, &palettes(display));
+    y += 192 + m;
+
+    let bg_map = (display.lcdc & lcdc_flags::BG_TILEMAP_AREA != 0) as usize;
+    let win_map = (display.lcdc & lcdc_flags::WIN_TILEMAP_AREA != 0) as usize;
+    for map in 0..2 {
+        let mut label = format!("MAP {:04X}", 0x9800 + map * 0x400);
+        if map == bg_map {
+            label += " BG";
+        }
+        if map == win_map && display.lcdc & lcdc_flags::WIN_ENABLE != 0 {
+            label += " WIN";
+        }
+        canvas.text(m + map as i32 * (256 + m), y, &label, TEXT);
+    }
+    y += label_height as i32;
+    canvas.blit(m, y, &tilemap(display, 0));
+    canvas.blit(m * 2 + 256, y, &tilemap(display, 1));
+    y += 256 + m;
+
+    canvas.text(m, y, "OAM", TEXT);
+    y += label_height as i32;
+    canvas.blit(m, y, &oam(display));
+
+    canvas
+}
@@ -15,13 +15,14 @@ use std::sync::{Arc, Mutex};
 
 use crate::desktop;
 use crate::desktop::audio::CpalAudio;
-use crate::desktop::input::{Gamepad, InputCombiner, Keyboard};
+use crate::desktop::input::{Gamepad, InputCombiner, Keyboard, REWIND_KEY};
 use crate::wasm::load_save::StaticRom;
 use crate::wasm::input::{WebButtonsInput, WebButtonsInputConfig};
 
 use crate::io::{Audio, Gameboy, Input, Serial};
 use crate::logs;
 use crate::logs::{elog, log, LogLevel};
+use crate::rewind::Rewind;
 use crate::wasm::utils::SystemTime;
 use wasm_bindgen::prelude::*;
 
@@ -38,6 +39,11 @@ pub fn set_panic_hook() {
 const WIDTH: u32 = 160;
 const HEIGHT: u32 = 144;
 
+// Rewind snapshot every 2 frames, played back at twice the normal speed, in 32MiB
+const REWIND_INTERVAL: u32 = 2;
+const REWIND_SPEED: f32 = 2.;
+const REWIND_MEMORY: usize = 32 * 1024 * 1024;
+
 #[wasm_bindgen]
 pub struct Emulator {
     gameboy: Gameboy<Box<dyn Input>, Box<dyn Serial>, Box<dyn Audio>, StaticRom>,
@@ -80,7 +86,8 @@ impl Emulator {
 
         let fs_load_save = StaticRom::new();
 
-        let gameboy = Gameboy::<_, _, _, _>::new(gamepad, serial, audio, fs_load_save, 1.);
+        let mut gameboy = Gameboy::<_, _, _, _>::new(gamepad, serial, audio, fs_load_save, 1.);
+        gameboy.enable_rewind(Rewind::new(REWIND_INTERVAL, REWIND_SPEED, REWIND_MEMORY));
 
         Self {
             gameboy,
@@ -122,7 +129,7 @@ impl Emulator {
         };
         let _ = event_loop.run(move |event, elwt| {
             if let Some(fb) = gameboy.sleep_and_draw() {
-                desktop::window::draw(pixels.frame_mut(), &fb);
+                desktop::window::draw(pixels.frame_mut(), fb.as_ref());
                 frames += 1;
                 if frames == 60 {
                     log(
@@ -173,6 +180,9 @@ impl Emulator {
                 }
             }
 
+            if let Ok(keys) = keys.lock() {
+                gameboy.set_rewinding(keys.contains(&REWIND_KEY));
+            }
             gameboy.run_until_next_sleep();
             elwt.set_control_flow(winit::event_loop::ControlFlow::wait_duration(
                 Duration::from_micros(1000000 / 60),
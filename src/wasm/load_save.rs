@@ -1,10 +1,10 @@
 use base64::prelude::*;
-use std::io::{Cursor, Write, Read};
 use web_sys::window;
 
 use crate::state::GBState;
 use crate::io::{LoadSave, Audio, Serial};
 use crate::logs::{log, LogLevel};
+use crate::save_state;
 
 #[derive(Debug)]
 pub struct StaticRom;
@@ -58,26 +58,7 @@ impl LoadSave for StaticRom {
     }
 
     fn save_state<S: Serial, A: Audio>(&self, state: &GBState<S, A>) -> Result<(), std::io::Error> {
-        let mut cursor = Cursor::new(vec![]);
-        for addr in 0x8000..0xa000 {
-            cursor.write_all(&[state.mem.r(addr)])?;
-        }
-
-        cursor.write_all(state.mem.wram_00.as_ref())?;
-        cursor.write_all(state.mem.wram_01.as_ref())?;
-
-        for addr in 0xff00..0xff80 {
-            cursor.write_all(&[state.mem.r(addr)])?;
-        }
-
-        cursor.write_all(state.mem.hram.as_ref())?;
-        cursor.write_all(&[state.mem.interrupts_register])?;
-
-        cursor.write_all(&state.cpu.r)?;
-        cursor.write_all(&state.cpu.pc.to_le_bytes())?;
-        cursor.write_all(&state.cpu.sp.to_le_bytes())?;
-        cursor.write_all(&[state.mem.boot_rom_on.into(), state.mem.ime.into()])?;
-        let state_b64 = BASE64_STANDARD.encode(cursor.into_inner().as_slice());
+        let state_b64 = BASE64_STANDARD.encode(save_state::save(state));
 
         let local_storage = window()
             .expect("Cannot get localStorage if window doesn't exists")
@@ -104,41 +85,10 @@ impl LoadSave for StaticRom {
         log(LogLevel::Infos, "Load State From LocalStorage");
         if let Ok(Some(state_b64)) = local_storage.get_item("gameboy_state") {
             if let Ok(state_vec) = BASE64_STANDARD.decode(state_b64) {
-                let mut cursor = Cursor::new(state_vec);
-                let mut vram = Box::new([0; 0x2000]);
-                cursor.read_exact(vram.as_mut())?;
-                for i in 0x0000..0x2000 {
-                    state.mem.w(0x8000 + i, vram[i as usize]);
-                }
-
-                cursor.read_exact(state.mem.wram_00.as_mut())?;
-                cursor.read_exact(state.mem.wram_01.as_mut())?;
-
-                let mut io = [0; 0x80];
-                cursor.read_exact(io.as_mut())?;
-                for i in 0x00..0x80 {
-                    state.mem.w(0xff00 + i, io[i as usize]);
+                match save_state::load(state, &state_vec) {
+                    Ok(()) => log(LogLevel::Infos, "State loaded !"),
+                    Err(err) => log(LogLevel::Error, format!("Loading state failed ({})", err)),
                 }
-
-                cursor.read_exact(state.mem.hram.as_mut())?;
-
-                let mut reg8 = [0; 1];
-                cursor.read_exact(reg8.as_mut())?;
-                state.mem.interrupts_register = reg8[0];
-
-                cursor.read_exact(&mut state.cpu.r)?;
-
-                let mut reg16 = [0; 2];
-                cursor.read_exact(&mut reg16)?;
-                state.cpu.pc = u16::from_le_bytes(reg16);
-                cursor.read_exact(&mut reg16)?;
-                state.cpu.sp = u16::from_le_bytes(reg16);
-
-                cursor.read_exact(reg8.as_mut())?;
-                state.mem.boot_rom_on = reg8[0] != 0;
-                cursor.read_exact(reg8.as_mut())?;
-                state.mem.ime = reg8[0] != 0;
-                log(LogLevel::Infos, "State loaded !");
             } else {
                 log(LogLevel::Error, "Decoding State from LocalStorage failed");
             }